@@ -0,0 +1,245 @@
+/// Prover ownership, access control lists and visibility
+///
+/// A prover is owned by whoever registered it, an api key (`key:<name>`) or an Ethereum
+/// address. The owner may do anything with it, and other principals need an ACL entry granting
+/// `prove`, `update` or `delete`. Public provers are listed to everyone and anyone allowed to
+/// prove may use them, private ones are hidden from principals that have no entry and only
+/// prove for those granted `prove`. Provers registered before ownership existed have no owner
+/// and stay open, and with auth turned off there are no principals so nothing is checked.
+use crate::errors::ProvingServerError;
+use crate::models::ProverConfig;
+use crate::signature::parse_address;
+use rocket::serde::{Deserialize, Serialize};
+use rusqlite::types::{FromSql, FromSqlError, ToSql, ToSqlOutput, ValueRef};
+use std::fmt;
+use std::str::FromStr;
+
+const KEY_PRINCIPAL_PREFIX: &str = "key:";
+
+/// Who is making a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Principal {
+    /// An api key, by name
+    Key(String),
+    /// A lowercase Ethereum address that signed the request
+    Address(String),
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Key(name) => write!(f, "{}{}", KEY_PRINCIPAL_PREFIX, name),
+            Principal::Address(address) => f.write_str(address),
+        }
+    }
+}
+
+impl FromStr for Principal {
+    type Err = ProvingServerError;
+    fn from_str(principal: &str) -> Result<Principal, ProvingServerError> {
+        match principal.strip_prefix(KEY_PRINCIPAL_PREFIX) {
+            Some("") => Err(ProvingServerError::InvalidProverConfig {
+                message: format!("{:?} doesn't name an api key", principal),
+            }),
+            Some(name) => Ok(Principal::Key(name.to_string())),
+            None => parse_address(principal).map(Principal::Address),
+        }
+    }
+}
+
+/// Parses a principal, returning it in the form provers store.
+pub fn normalize_principal(principal: &str) -> Result<String, ProvingServerError> {
+    principal.parse::<Principal>().map(|p| p.to_string())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Permission {
+    Prove,
+    Update,
+    Delete,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Prove => "prove",
+            Permission::Update => "update",
+            Permission::Delete => "delete",
+        }
+    }
+}
+
+impl FromSql for Permission {
+    fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
+        match value.as_str()? {
+            "prove" => Ok(Permission::Prove),
+            "update" => Ok(Permission::Update),
+            "delete" => Ok(Permission::Delete),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for Permission {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Public
+    }
+}
+
+impl FromSql for Visibility {
+    fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
+        match value.as_str()? {
+            "public" => Ok(Visibility::Public),
+            "private" => Ok(Visibility::Private),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for Visibility {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::from(match self {
+            Visibility::Public => "public",
+            Visibility::Private => "private",
+        }))
+    }
+}
+
+/// Permissions granted to a principal other than the owner.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct AclEntry {
+    /// `key:<name>` or an Ethereum address
+    pub principal: String,
+    pub permissions: Vec<Permission>,
+}
+
+/// Whether `principal` is the prover's owner or was granted `permission`, ignoring visibility
+/// and provers without an owner.
+pub fn grants(prover: &ProverConfig, principal: &Principal, permission: Permission) -> bool {
+    let principal = principal.to_string();
+    prover.owner.as_ref() == Some(&principal)
+        || prover
+            .acl
+            .iter()
+            .any(|entry| entry.principal == principal && entry.permissions.contains(&permission))
+}
+
+/// Whether `principal` may change who has access to the prover.
+pub fn owns(prover: &ProverConfig, principal: Option<&Principal>) -> bool {
+    match (principal, &prover.owner) {
+        (Some(principal), Some(owner)) => principal.to_string() == *owner,
+        _ => true,
+    }
+}
+
+pub fn visible_to(prover: &ProverConfig, principal: Option<&Principal>) -> bool {
+    match (prover.visibility, principal) {
+        (Visibility::Private, Some(principal)) if prover.owner.is_some() => {
+            let principal = principal.to_string();
+            prover.owner.as_ref() == Some(&principal)
+                || prover.acl.iter().any(|entry| entry.principal == principal)
+        }
+        _ => true,
+    }
+}
+
+/// Refuses `principal` unless it may use `permission` on the prover. Anyone may prove with a
+/// public prover.
+pub fn check(
+    prover: &ProverConfig,
+    principal: Option<&Principal>,
+    permission: Permission,
+) -> Result<(), ProvingServerError> {
+    let principal = match principal {
+        Some(principal) if prover.owner.is_some() => principal,
+        _ => return Ok(()),
+    };
+    if (permission == Permission::Prove && prover.visibility == Visibility::Public)
+        || grants(prover, principal, permission)
+    {
+        return Ok(());
+    }
+    Err(ProvingServerError::Forbidden {
+        message: format!(
+            "{} may not {} {}",
+            principal,
+            permission.as_str(),
+            prover.label()
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::fixtures;
+
+    const ADDRESS: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    fn key(name: &str) -> Principal {
+        Principal::Key(String::from(name))
+    }
+
+    #[test]
+    fn unit_principals_parse() {
+        assert_eq!("key:deploy".parse::<Principal>().unwrap(), key("deploy"));
+        assert_eq!(
+            normalize_principal("0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266").unwrap(),
+            ADDRESS
+        );
+        assert!("key:".parse::<Principal>().is_err());
+        assert!("deploy".parse::<Principal>().is_err());
+    }
+
+    #[test]
+    fn unit_owner_and_acl_decide() {
+        let mut prover = fixtures::df_prover_config();
+        // unowned provers are open to everyone
+        assert!(check(&prover, Some(&key("other")), Permission::Delete).is_ok());
+
+        prover.owner = Some(String::from("key:owner"));
+        prover.acl = vec![AclEntry {
+            principal: String::from(ADDRESS),
+            permissions: vec![Permission::Prove, Permission::Update],
+        }];
+        let signer = Principal::Address(String::from(ADDRESS));
+        for permission in [Permission::Prove, Permission::Update, Permission::Delete] {
+            assert!(check(&prover, Some(&key("owner")), permission).is_ok());
+            assert!(check(&prover, None, permission).is_ok());
+        }
+        assert!(check(&prover, Some(&signer), Permission::Update).is_ok());
+        assert!(matches!(
+            check(&prover, Some(&signer), Permission::Delete),
+            Err(ProvingServerError::Forbidden { .. })
+        ));
+        assert!(check(&prover, Some(&key("other")), Permission::Update).is_err());
+        assert!(owns(&prover, Some(&key("owner"))));
+        assert!(!owns(&prover, Some(&signer)));
+
+        // public provers prove for anyone and show up for anyone
+        assert!(check(&prover, Some(&key("other")), Permission::Prove).is_ok());
+        assert!(visible_to(&prover, Some(&key("other"))));
+
+        prover.visibility = Visibility::Private;
+        assert!(check(&prover, Some(&key("other")), Permission::Prove).is_err());
+        assert!(!visible_to(&prover, Some(&key("other"))));
+        assert!(check(&prover, Some(&signer), Permission::Prove).is_ok());
+        assert!(visible_to(&prover, Some(&signer)));
+        assert!(visible_to(&prover, None));
+    }
+}
@@ -0,0 +1,94 @@
+/// Movable version aliases such as `latest`, `stable` or `season-5`
+///
+/// An alias names one version of a prover and is repointed through the alias routes, so
+/// clients don't have to change when a circuit is upgraded. The prove and vkey routes take an
+/// alias wherever they take a version. An exact version wins over an alias of the same name,
+/// and responses report the version that was used in `X-Prover-Version`.
+use crate::errors::ProvingServerError;
+use crate::models::{ProverAlias, ProverConfig};
+use rocket::http::Header;
+use rocket::response::{self, Responder};
+use rocket::Request;
+use rusqlite::{Connection, OptionalExtension};
+
+pub const VERSION_HEADER: &str = "X-Prover-Version";
+
+/// The prover `version` names in `namespace`, either exactly or through an alias.
+pub fn resolve(
+    namespace: &str,
+    name: &str,
+    version: &str,
+    conn: &Connection,
+) -> Result<ProverConfig, ProvingServerError> {
+    let not_found = || ProvingServerError::ProverNotFound {
+        name: name.to_string(),
+        version: version.to_string(),
+    };
+    let exact = |version: &str| {
+        ProverConfig::get_by_name_and_version(
+            namespace,
+            name.to_string(),
+            version.to_string(),
+            conn,
+        )
+        .optional()
+    };
+    if let Some(prover) = exact(version)? {
+        return Ok(prover);
+    }
+    match ProverAlias::get(namespace, name, version, conn)? {
+        Some(alias) => exact(&alias.version)?.ok_or_else(not_found),
+        None => Err(not_found()),
+    }
+}
+
+/// A response along with the concrete version of the prover that produced it.
+pub struct WithVersion<R>(pub R, pub String);
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for WithVersion<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.0.respond_to(req)?;
+        response.set_header(Header::new(VERSION_HEADER, self.1));
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Crud, DEFAULT_NAMESPACE};
+    use crate::test::fixtures;
+
+    #[tokio::test]
+    async fn unit_resolve_prefers_exact_versions() {
+        let conn = fixtures::setup_db().await;
+        let mut prover = fixtures::df_prover_config();
+        prover.create(&conn).unwrap();
+        let mut newer = fixtures::df_prover_config();
+        newer.version = String::from("0.0.2");
+        newer.create(&conn).unwrap();
+        for (alias, version) in [("latest", "0.0.2"), ("0.0.1", "0.0.2")] {
+            ProverAlias {
+                namespace: String::from(DEFAULT_NAMESPACE),
+                name: prover.name.clone(),
+                alias: String::from(alias),
+                version: String::from(version),
+                updated_at: 0,
+            }
+            .set(&conn)
+            .unwrap();
+        }
+
+        let in_default = |version| resolve(DEFAULT_NAMESPACE, "test", version, &conn);
+        assert_eq!(in_default("latest").unwrap().version, "0.0.2");
+        assert_eq!(in_default("0.0.1").unwrap().version, "0.0.1");
+        assert!(matches!(
+            in_default("stable"),
+            Err(ProvingServerError::ProverNotFound { .. })
+        ));
+        assert!(matches!(
+            resolve("team-a", "test", "latest", &conn),
+            Err(ProvingServerError::ProverNotFound { .. })
+        ));
+    }
+}
@@ -0,0 +1,269 @@
+/// API key authentication
+///
+/// Keys are random 32 byte secrets handed out once by `proving-server keys mint`. Only their
+/// sha256 is stored, which is enough since the secrets have full entropy. Routes say which
+/// scope they need through the `Authorized<S>` request guard, and keys are sent as
+/// `Authorization: Bearer <key>` or `X-Api-Key: <key>`.
+use crate::acl::Principal;
+use crate::errors::ProvingServerError;
+use crate::models::ApiKey;
+use crate::storage::validate_identifier;
+use crate::types::{Config, Db};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+const KEY_PREFIX: &str = "ps_";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AuthConfig {
+    /// Turning this off leaves every route open, only sensible behind another gateway
+    pub enabled: bool,
+    /// How far in the future a signed proof request may expire, which also bounds how long
+    /// its nonce is remembered
+    pub signature_max_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            enabled: true,
+            signature_max_ttl_secs: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum Scope {
+    /// Register, update and delete provers, and manage artifact storage
+    #[serde(rename = "prover:admin")]
+    ProverAdmin,
+    /// Generate proofs
+    #[serde(rename = "prove")]
+    Prove,
+    /// Look at provers and their load jobs
+    #[serde(rename = "read")]
+    Read,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ProverAdmin => "prover:admin",
+            Scope::Prove => "prove",
+            Scope::Read => "read",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ProvingServerError;
+    fn from_str(scope: &str) -> Result<Scope, ProvingServerError> {
+        match scope {
+            "prover:admin" => Ok(Scope::ProverAdmin),
+            "prove" => Ok(Scope::Prove),
+            "read" => Ok(Scope::Read),
+            _ => Err(ProvingServerError::InvalidConfig {
+                message: format!(
+                    "unknown scope {:?}, expected prover:admin, prove or read",
+                    scope
+                ),
+            }),
+        }
+    }
+}
+
+/// A fresh secret in the form `ps_<64 hex chars>`.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", KEY_PREFIX, hex::encode(bytes))
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Marks the scope a route requires, see `Authorized`.
+pub trait RequiredScope: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+pub struct ProverAdmin;
+pub struct Prove;
+pub struct Read;
+
+impl RequiredScope for ProverAdmin {
+    const SCOPE: Scope = Scope::ProverAdmin;
+}
+impl RequiredScope for Prove {
+    const SCOPE: Scope = Scope::Prove;
+}
+impl RequiredScope for Read {
+    const SCOPE: Scope = Scope::Read;
+}
+
+/// Request guard that only lets through requests carrying a live key with scope `S`.
+/// `key` is `None` when authentication is turned off.
+pub struct Authorized<S: RequiredScope> {
+    pub key: Option<ApiKey>,
+    scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope> Authorized<S> {
+    /// The key as the owner or grantee of a prover, `None` when authentication is off.
+    pub fn principal(&self) -> Option<Principal> {
+        self.key
+            .as_ref()
+            .map(|key| Principal::Key(key.name.clone()))
+    }
+
+    /// Checks that the key may act in `namespace`, which tenant keys only may in their own.
+    pub fn check_namespace(&self, namespace: &str) -> Result<(), ProvingServerError> {
+        validate_identifier("namespace", namespace)?;
+        match self.key.as_ref().and_then(|key| key.namespace.as_ref()) {
+            Some(own) if own != namespace => Err(ProvingServerError::Forbidden {
+                message: format!("this key belongs to the {} namespace", own),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the key isn't owned by a tenant, for routes that span every namespace.
+    pub fn check_server_wide(&self) -> Result<(), ProvingServerError> {
+        match self.key.as_ref().and_then(|key| key.namespace.as_ref()) {
+            Some(own) => Err(ProvingServerError::Forbidden {
+                message: format!("this key belongs to the {} namespace", own),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn presented_secret<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    let headers = request.headers();
+    headers
+        .get_one("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get_one("X-Api-Key"))
+        .map(str::trim)
+}
+
+#[rocket::async_trait]
+impl<'r, S: RequiredScope> FromRequest<'r> for Authorized<S> {
+    type Error = ProvingServerError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let enabled = match request.rocket().state::<Config>() {
+            Some(config) => config.lock().await.auth.enabled,
+            None => true,
+        };
+        if !enabled {
+            return Outcome::Success(Authorized {
+                key: None,
+                scope: PhantomData,
+            });
+        }
+        let unauthorized = |message: &str| {
+            Outcome::Failure((
+                Status::Unauthorized,
+                ProvingServerError::Unauthorized {
+                    message: message.to_string(),
+                },
+            ))
+        };
+        let secret = match presented_secret(request) {
+            Some(secret) => secret,
+            None => return unauthorized("an api key is required"),
+        };
+        let db = match request.rocket().state::<Db>() {
+            Some(db) => db,
+            None => return unauthorized("no key store is configured"),
+        };
+        let key = match ApiKey::find_by_secret(secret, &*db.lock().await) {
+            Ok(Some(key)) => key,
+            Ok(None) => return unauthorized("unknown or revoked api key"),
+            Err(e) => return Outcome::Failure((Status::InternalServerError, e.into())),
+        };
+        if !key.scopes.contains(&S::SCOPE) {
+            return Outcome::Failure((
+                Status::Forbidden,
+                ProvingServerError::Forbidden {
+                    message: format!("this key lacks the {} scope", S::SCOPE),
+                },
+            ));
+        }
+        Outcome::Success(Authorized {
+            key: Some(key),
+            scope: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Crud;
+    use crate::test::fixtures;
+
+    #[test]
+    fn unit_scopes_round_trip() {
+        for scope in [Scope::ProverAdmin, Scope::Prove, Scope::Read] {
+            assert_eq!(scope.as_str().parse::<Scope>().unwrap(), scope);
+        }
+        assert!("admin".parse::<Scope>().is_err());
+    }
+
+    #[tokio::test]
+    async fn unit_mint_find_and_revoke() {
+        let conn = fixtures::setup_db().await;
+        let (key, secret) = ApiKey::mint("deploy", None, &[Scope::Prove], &conn).unwrap();
+        assert!(secret.starts_with(KEY_PREFIX));
+        assert_ne!(key.hash, secret);
+
+        let found = ApiKey::find_by_secret(&secret, &conn).unwrap().unwrap();
+        assert_eq!(found.id, key.id);
+        assert_eq!(found.scopes, vec![Scope::Prove]);
+        assert!(ApiKey::find_by_secret("ps_guess", &conn).unwrap().is_none());
+
+        assert_eq!(ApiKey::revoke("deploy", &conn).unwrap(), 1);
+        assert!(ApiKey::find_by_secret(&secret, &conn).unwrap().is_none());
+        assert!(ApiKey::list(&conn).unwrap()[0].revoked);
+    }
+
+    #[tokio::test]
+    async fn unit_update_key() {
+        let conn = fixtures::setup_db().await;
+        let (mut key, secret) = ApiKey::mint("ci", None, &[Scope::Read], &conn).unwrap();
+        key.name = String::from("ci-team-a");
+        key.scopes = vec![Scope::Read, Scope::Prove];
+        key.namespace = Some(String::from("team-a"));
+        assert_eq!(key.update(&conn).unwrap(), 1);
+        let found = ApiKey::find_by_secret(&secret, &conn).unwrap().unwrap();
+        assert_eq!(found.name, "ci-team-a");
+        assert_eq!(found.scopes, vec![Scope::Read, Scope::Prove]);
+        assert_eq!(found.namespace.as_deref(), Some("team-a"));
+
+        key.revoked = true;
+        key.update(&conn).unwrap();
+        assert!(ApiKey::find_by_secret(&secret, &conn).unwrap().is_none());
+        assert!(ApiKey::get(key.id.unwrap(), &conn).unwrap().revoked);
+        key.revoked = false;
+        key.update(&conn).unwrap();
+        assert!(ApiKey::find_by_secret(&secret, &conn).unwrap().is_some());
+    }
+}
@@ -0,0 +1,105 @@
+/// Maintenance commands that run against the configured database and artifact store
+use crate::auth::Scope;
+use crate::config;
+use crate::db;
+use crate::errors::ProvingServerError;
+use crate::models::ApiKey;
+use crate::storage::{collect_all_garbage, report_all, validate_identifier, GC_GRACE};
+use crate::types::DatabaseMode;
+use crate::utils;
+use rocket::serde::json::to_string_pretty;
+use rusqlite::Connection;
+
+const USAGE: &str = "usage: proving-server [gc | blobs | keys | --print-config]
+
+  gc                            remove artifact blobs no prover references
+  blobs                         list every blob and the provers using it
+  keys mint <name> [--namespace <ns>] <scope>...
+                                create an api key, scopes are prover:admin, prove and read,
+                                keys minted for a namespace can only act within it
+  keys revoke <name>            revoke an api key
+  keys list                     list api keys
+  --print-config                show the effective configuration and exit";
+
+/// Runs `args[0]` as a command. Returns `None` when no command was given and the server
+/// should start as usual.
+pub async fn run(args: &[String]) -> Option<Result<(), ProvingServerError>> {
+    let command = args.get(0)?;
+    Some(run_command(command, &args[1..]).await)
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+async fn run_command(command: &str, args: &[String]) -> Result<(), ProvingServerError> {
+    utils::load_environment_variables();
+    let config = utils::init_config()?;
+    match command {
+        "--print-config" => {
+            println!("# from {} and the environment", config::config_file());
+            print!("{}", config::to_toml(&config)?);
+        }
+        "gc" => {
+            let db = db::init_async_database(config.clone())?;
+            let report = collect_all_garbage(&config, &db, GC_GRACE).await?;
+            println!("{}", to_string_pretty(&report).unwrap());
+        }
+        "blobs" => {
+            let db = db::init_async_database(config.clone())?;
+            let report = report_all(&config, &db).await?;
+            println!("{}", to_string_pretty(&report).unwrap());
+        }
+        "keys" => {
+            if let DatabaseMode::Memory = config.db_config {
+                return Err(ProvingServerError::InvalidConfig {
+                    message: String::from(
+                        "api keys need a database file, set DB_FILE_PATH or database.path_to_file",
+                    ),
+                });
+            }
+            let conn = db::init_async_database(config)?;
+            let conn = conn.lock().await;
+            keys(args, &conn)?;
+        }
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn keys(args: &[String], conn: &Connection) -> Result<(), ProvingServerError> {
+    match (args.get(0).map(String::as_str), args.get(1)) {
+        (Some("mint"), Some(name)) if args.len() > 2 => {
+            let (namespace, scopes) = match args[2].as_str() {
+                "--namespace" if args.len() > 4 => (Some(args[3].as_str()), &args[4..]),
+                "--namespace" => usage(),
+                _ => (None, &args[2..]),
+            };
+            if let Some(namespace) = namespace {
+                validate_identifier("namespace", namespace)?;
+            }
+            let scopes = scopes
+                .iter()
+                .map(|scope| scope.parse::<Scope>())
+                .collect::<Result<Vec<Scope>, ProvingServerError>>()?;
+            let (key, secret) = ApiKey::mint(name, namespace, &scopes, conn)?;
+            eprintln!(
+                "minted {} with {:?} for {}, the key is only shown once:",
+                key.name,
+                key.scopes,
+                namespace.unwrap_or("every namespace")
+            );
+            println!("{}", secret);
+        }
+        (Some("revoke"), Some(name)) => match ApiKey::revoke(name, conn)? {
+            0 => eprintln!("no live key named {}", name),
+            _ => eprintln!("revoked {}", name),
+        },
+        (Some("list"), None) => {
+            println!("{}", to_string_pretty(&ApiKey::list(conn)?).unwrap());
+        }
+        _ => usage(),
+    }
+    Ok(())
+}
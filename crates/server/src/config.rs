@@ -0,0 +1,297 @@
+/// Server configuration, layered from defaults, a TOML file and environment variables
+///
+/// Later layers win: built in defaults, then the file named by `PROVING_SERVER_CONFIG`
+/// (`proving-server.toml` by default, it may be missing), then the historical variables like
+/// `ZK_FILE_PATH` or `FETCH_ALLOWED_HOSTS`, then `PROVING_SERVER_<KEY>` overrides where nested
+/// keys are separated by `__`, e.g. `PROVING_SERVER_FETCH__MAX_BYTES`.
+use crate::errors::ProvingServerError;
+use crate::types::{DatabaseMode, EnvConfig, StorageMode};
+use reqwest::Url;
+use rocket::figment::providers::{Env, Format, Toml};
+use rocket::figment::Figment;
+use std::env;
+
+const CONFIG_FILE_VAR: &str = "PROVING_SERVER_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "proving-server.toml";
+
+/// Variables from before the config file existed, and the keys they set.
+const LEGACY_VARS: [(&str, &str); 16] = [
+    ("ZK_FILE_PATH", "zk_file_path"),
+    ("PORT", "port"),
+    ("IPFS_GATEWAY", "ipfs_gateway"),
+    ("NPM_REGISTRY", "npm_registry"),
+    ("PROVER_MANIFEST", "manifest_path"),
+    ("PROVER_MANIFEST_PRUNE", "manifest_prune"),
+    ("FETCH_BLOCK_PRIVATE", "fetch.block_private"),
+    ("FETCH_MAX_BYTES", "fetch.max_bytes"),
+    ("FETCH_MAX_REDIRECTS", "fetch.max_redirects"),
+    ("STORAGE_BACKEND", "storage.backend"),
+    ("S3_ENDPOINT", "storage.endpoint"),
+    ("S3_BUCKET", "storage.bucket"),
+    ("S3_REGION", "storage.region"),
+    ("S3_PREFIX", "storage.prefix"),
+    ("S3_ACCESS_KEY_ID", "storage.access_key_id"),
+    ("S3_SECRET_ACCESS_KEY", "storage.secret_access_key"),
+];
+
+/// Comma separated legacy variables that set lists.
+const LEGACY_LISTS: [(&str, &str); 5] = [
+    ("FETCH_ALLOWED_SCHEMES", "fetch.allowed_schemes"),
+    ("FETCH_ALLOWED_HOSTS", "fetch.allowed_hosts"),
+    ("FETCH_DENIED_HOSTS", "fetch.denied_hosts"),
+    ("FETCH_ALLOWED_CIDRS", "fetch.allowed_cidrs"),
+    ("FETCH_DENIED_CIDRS", "fetch.denied_cidrs"),
+];
+
+fn invalid(message: String) -> ProvingServerError {
+    ProvingServerError::InvalidConfig { message }
+}
+
+/// The config file named by `PROVING_SERVER_CONFIG`.
+pub fn config_file() -> String {
+    env::var(CONFIG_FILE_VAR).unwrap_or_else(|_| String::from(DEFAULT_CONFIG_FILE))
+}
+
+/// Every layer, in order of precedence.
+pub fn figment() -> Figment {
+    let mut figment = Figment::new().merge(Toml::file(config_file()));
+    for (var, key) in LEGACY_VARS {
+        figment = figment.merge(Env::raw().only(&[var]).map(move |_| key.into()));
+    }
+    // `DB_FILE_PATH` switches the database mode as well as setting the path
+    if let Ok(path) = env::var("DB_FILE_PATH") {
+        figment = figment
+            .merge(("database.mode", "file"))
+            .merge(("database.path_to_file", path));
+    }
+    for (var, key) in LEGACY_LISTS {
+        if let Ok(list) = env::var(var) {
+            let items: Vec<String> = list
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect();
+            figment = figment.merge((key, items));
+        }
+    }
+    figment.merge(
+        Env::prefixed("PROVING_SERVER_")
+            .ignore(&["config"])
+            .split("__"),
+    )
+}
+
+pub fn load() -> Result<EnvConfig, ProvingServerError> {
+    extract(&figment())
+}
+
+/// Deserializes and validates the configuration held by `figment`.
+pub fn extract(figment: &Figment) -> Result<EnvConfig, ProvingServerError> {
+    let config: EnvConfig = figment.extract().map_err(|e| invalid(e.to_string()))?;
+    validate(&config)?;
+    Ok(config)
+}
+
+fn check_url(key: &str, value: &str) -> Result<(), ProvingServerError> {
+    match Url::parse(value) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(()),
+        _ => Err(invalid(format!(
+            "{} must be an http(s) url, got {:?}",
+            key, value
+        ))),
+    }
+}
+
+pub fn validate(config: &EnvConfig) -> Result<(), ProvingServerError> {
+    if config.zk_file_path.is_empty() {
+        return Err(invalid(String::from(
+            "zk_file_path is required, set it in the config file or with ZK_FILE_PATH",
+        )));
+    }
+    if !(1..=65535).contains(&config.port) {
+        return Err(invalid(format!(
+            "port must be between 1 and 65535, got {}",
+            config.port
+        )));
+    }
+    if config.queue_size == 0 {
+        return Err(invalid(String::from("queue_size must be at least 1")));
+    }
+    if let DatabaseMode::File { path_to_file } = &config.db_config {
+        if path_to_file.is_empty() {
+            return Err(invalid(String::from(
+                "database.path_to_file is required when database.mode is file",
+            )));
+        }
+    }
+    let fetch = &config.fetch_policy;
+    if fetch.allowed_schemes.is_empty() {
+        return Err(invalid(String::from(
+            "fetch.allowed_schemes must allow at least one scheme",
+        )));
+    }
+    if fetch.max_bytes == 0 || fetch.timeout_secs == 0 || fetch.connect_timeout_secs == 0 {
+        return Err(invalid(String::from(
+            "fetch.max_bytes, fetch.timeout_secs and fetch.connect_timeout_secs must be above 0",
+        )));
+    }
+    if config.auth.signature_max_ttl_secs == 0 {
+        return Err(invalid(String::from(
+            "auth.signature_max_ttl_secs must be above 0",
+        )));
+    }
+    config.limits.validate()?;
+    config.logging.validate()?;
+    config.shutdown.validate()?;
+    config.queue.validate()?;
+    config.scheduler.validate()?;
+    config.cors.validate()?;
+    check_url("ipfs_gateway", &config.ipfs_gateway)?;
+    check_url("npm_registry", &config.npm_registry)?;
+    if let StorageMode::S3(s3) = &config.storage {
+        check_url("storage.endpoint", &s3.endpoint)?;
+        for (key, value) in [
+            ("storage.bucket", &s3.bucket),
+            ("storage.access_key_id", &s3.access_key_id),
+            ("storage.secret_access_key", &s3.secret_access_key),
+        ] {
+            if value.is_empty() {
+                return Err(invalid(format!("{} is required for the s3 backend", key)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The effective configuration as TOML, with secrets redacted.
+pub fn to_toml(config: &EnvConfig) -> Result<String, ProvingServerError> {
+    let mut config = config.clone();
+    if let StorageMode::S3(s3) = &mut config.storage {
+        s3.secret_access_key = String::from("<redacted>");
+    }
+    // going through `Value` puts plain keys ahead of tables, as TOML requires
+    toml::Value::try_from(&config)
+        .and_then(|value| toml::to_string_pretty(&value))
+        .map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(toml: &str) -> Result<EnvConfig, ProvingServerError> {
+        extract(&Figment::new().merge(Toml::string(toml)))
+    }
+
+    #[test]
+    fn unit_defaults_and_file_values() {
+        let config = from_toml(
+            r#"
+            zk_file_path = "/var/lib/proving-server"
+
+            [fetch]
+            allowed_hosts = ["unpkg.com"]
+            allowed_cidrs = ["10.1.0.0/16"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.queue_size, 64);
+        assert!(matches!(config.db_config, DatabaseMode::Memory));
+        assert!(matches!(config.storage, StorageMode::Local));
+        assert_eq!(config.fetch_policy.allowed_hosts, vec!["unpkg.com"]);
+        assert_eq!(config.fetch_policy.max_redirects, 5);
+        assert_eq!(
+            config.fetch_policy.allowed_cidrs,
+            vec!["10.1.0.0/16".parse::<ipnet::IpNet>().unwrap()]
+        );
+        assert_eq!(config.ipfs_gateway, "https://ipfs.io");
+
+        let config = from_toml(
+            r#"
+            zk_file_path = "/data"
+            port = 9000
+
+            [database]
+            mode = "file"
+            path_to_file = "/data/provers.db"
+
+            [storage]
+            backend = "s3"
+            endpoint = "http://localhost:9000"
+            bucket = "artifacts"
+            access_key_id = "minio"
+            secret_access_key = "minio-secret"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 9000);
+        assert!(matches!(
+            &config.db_config,
+            DatabaseMode::File { path_to_file } if path_to_file == "/data/provers.db"
+        ));
+        match &config.storage {
+            StorageMode::S3(s3) => {
+                assert_eq!(s3.region, "us-east-1");
+                assert_eq!(s3.prefix, "");
+            }
+            StorageMode::Local => panic!("expected the s3 backend"),
+        }
+        let printed = to_toml(&config).unwrap();
+        assert!(printed.contains("bucket = \"artifacts\""));
+        assert!(!printed.contains("minio-secret"));
+    }
+
+    #[test]
+    fn unit_invalid_configs_are_explained() {
+        for (toml, expected) in [
+            ("port = 8000", "zk_file_path"),
+            ("zk_file_path = \"/data\"\nport = 0", "port must be between"),
+            ("zk_file_path = \"/data\"\nport = \"eighty\"", "port"),
+            ("zk_file_path = \"/data\"\nqueue_size = 0", "queue_size"),
+            (
+                "zk_file_path = \"/data\"\nipfs_gateway = \"ipfs.io\"",
+                "ipfs_gateway must be an http(s) url",
+            ),
+            (
+                "zk_file_path = \"/data\"\n[database]\nmode = \"file\"",
+                "database.path_to_file",
+            ),
+            (
+                "zk_file_path = \"/data\"\n[storage]\nbackend = \"s3\"\nendpoint = \"http://localhost:9000\"",
+                "storage.bucket",
+            ),
+            (
+                "zk_file_path = \"/data\"\n[fetch]\nallowed_cidrs = [\"not a cidr\"]",
+                "allowed_cidrs",
+            ),
+            (
+                "zk_file_path = \"/data\"\n[logging]\nlevel = \"loud\"",
+                "logging.level",
+            ),
+            (
+                "zk_file_path = \"/data\"\n[queue]\nlease_secs = 0",
+                "queue.lease_secs",
+            ),
+            (
+                "zk_file_path = \"/data\"\n[queue]\nworkers = 0",
+                "queue.workers",
+            ),
+            (
+                "zk_file_path = \"/data\"\n[scheduler]\nbatch_weight = 0",
+                "scheduler.batch_weight",
+            ),
+        ] {
+            match from_toml(toml) {
+                Err(ProvingServerError::InvalidConfig { message }) => assert!(
+                    message.contains(expected),
+                    "{:?} should mention {:?}, got {:?}",
+                    toml,
+                    expected,
+                    message
+                ),
+                other => panic!("{:?} should be rejected, got {:?}", toml, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,389 @@
+/// Cross origin access for browser clients, driven by the `[cors]` config section
+///
+/// Only origins that are listed get CORS headers back. Preflight `OPTIONS` requests are
+/// answered by a catch-all route, and refused with a 403 when the origin, method or headers
+/// they ask for aren't allowed.
+use crate::errors::ProvingServerError;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Request, Response};
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", default)]
+pub struct CorsConfig {
+    /// Exact origins such as `https://app.example.com`, or `*` for any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers browsers may send, `*` allows any
+    pub allowed_headers: Vec<String>,
+    /// Response headers scripts may read besides the safelisted ones
+    pub exposed_headers: Vec<String>,
+    /// Only allowed with explicit origins, browsers refuse credentials with `*`
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec![],
+            allowed_methods: ["GET", "POST", "PATCH", "DELETE"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            allowed_headers: [
+                "Content-Type",
+                "Authorization",
+                "X-Api-Key",
+                "X-Request-Id",
+                crate::signature::SIGNATURE_HEADER,
+                crate::signature::NONCE_HEADER,
+                crate::signature::EXPIRES_HEADER,
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect(),
+            exposed_headers: [
+                crate::logging::REQUEST_ID_HEADER,
+                crate::scheduler::PROOF_ID_HEADER,
+                "X-RateLimit-Limit",
+                "X-RateLimit-Remaining",
+                "X-Quota-Limit",
+                "X-Quota-Remaining",
+                "X-Quota-Reset",
+                "Retry-After",
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect(),
+            allow_credentials: false,
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        let invalid = |message: String| ProvingServerError::InvalidConfig { message };
+        for origin in &self.allowed_origins {
+            if origin == "*" {
+                if self.allow_credentials {
+                    return Err(invalid(String::from(
+                        "cors.allow_credentials can't be combined with the `*` origin",
+                    )));
+                }
+                continue;
+            }
+            match reqwest::Url::parse(origin) {
+                Ok(url)
+                    if url.has_host()
+                        && origin.trim_end_matches('/') == url.origin().ascii_serialization() => {}
+                _ => {
+                    return Err(invalid(format!(
+                        "cors.allowed_origins entry {:?} should look like https://example.com",
+                        origin
+                    )))
+                }
+            }
+        }
+        for method in &self.allowed_methods {
+            if method.parse::<Method>().is_err() {
+                return Err(invalid(format!(
+                    "cors.allowed_methods entry {:?} is not an http method",
+                    method
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.trim_end_matches('/') == origin)
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        if self.allowed_headers.iter().any(|h| h == "*") {
+            return true;
+        }
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .all(|h| {
+                self.allowed_headers
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(h))
+            })
+    }
+}
+
+pub struct CORS {
+    policy: CorsConfig,
+}
+
+impl CORS {
+    pub fn new(policy: CorsConfig) -> Self {
+        CORS { policy }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for CORS {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add CORS headers to responses",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let headers = request.headers();
+        let origin = match headers.get_one("Origin") {
+            Some(origin) => origin,
+            None => return,
+        };
+        let preflight = request.method() == Method::Options
+            && headers.contains("Access-Control-Request-Method");
+        response.adjoin_header(Header::new("Vary", "Origin"));
+        if !self.policy.allows_origin(origin) {
+            if preflight {
+                response.set_status(Status::Forbidden);
+            }
+            return;
+        }
+        if self.policy.allowed_origins.iter().any(|o| o == "*") {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        } else {
+            response.set_header(Header::new(
+                "Access-Control-Allow-Origin",
+                origin.to_string(),
+            ));
+        }
+        if self.policy.allow_credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+        if !preflight {
+            if !self.policy.exposed_headers.is_empty() {
+                response.set_header(Header::new(
+                    "Access-Control-Expose-Headers",
+                    self.policy.exposed_headers.join(", "),
+                ));
+            }
+            return;
+        }
+        let method = headers
+            .get_one("Access-Control-Request-Method")
+            .unwrap_or("");
+        let requested_headers = headers
+            .get_one("Access-Control-Request-Headers")
+            .unwrap_or("");
+        if !self.policy.allows_method(method) || !self.policy.allows_headers(requested_headers) {
+            response.set_status(Status::Forbidden);
+            return;
+        }
+        response.set_header(Header::new(
+            "Access-Control-Allow-Methods",
+            self.policy.allowed_methods.join(", "),
+        ));
+        let allowed_headers = if self.policy.allowed_headers.iter().any(|h| h == "*") {
+            requested_headers.to_string()
+        } else {
+            self.policy.allowed_headers.join(", ")
+        };
+        if !allowed_headers.is_empty() {
+            response.set_header(Header::new("Access-Control-Allow-Headers", allowed_headers));
+        }
+        response.set_header(Header::new(
+            "Access-Control-Max-Age",
+            self.policy.max_age_secs.to_string(),
+        ));
+    }
+}
+
+/// Answers every preflight, the fairing decides what it allows.
+#[options("/<_..>")]
+pub fn preflight() -> Status {
+    Status::NoContent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    fn client(policy: CorsConfig) -> Client {
+        let rocket = rocket::build()
+            .attach(CORS::new(policy))
+            .mount("/", routes![preflight, crate::routes::index]);
+        Client::tracked(rocket).unwrap()
+    }
+
+    fn app_policy() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec![String::from("https://app.example.com")],
+            allow_credentials: true,
+            ..CorsConfig::default()
+        }
+    }
+
+    #[test]
+    fn unit_allowed_origin_is_echoed() {
+        let client = client(app_policy());
+        let response = client
+            .get("/")
+            .header(Header::new("Origin", "https://app.example.com"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+        assert_eq!(headers.get_one("Vary"), Some("Origin"));
+        let exposed = headers.get_one("Access-Control-Expose-Headers").unwrap();
+        for header in [
+            "X-Request-Id",
+            "X-RateLimit-Remaining",
+            "X-Quota-Reset",
+            "Retry-After",
+        ] {
+            assert!(exposed.contains(header), "{} should be exposed", header);
+        }
+    }
+
+    #[test]
+    fn unit_denied_origin_gets_no_cors_headers() {
+        let client = client(app_policy());
+        let response = client
+            .get("/")
+            .header(Header::new("Origin", "https://evil.example.com"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response
+            .headers()
+            .get_one("Access-Control-Allow-Origin")
+            .is_none());
+
+        let response = client
+            .options("/v1/prover")
+            .header(Header::new("Origin", "https://evil.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn unit_preflight() {
+        let client = client(app_policy());
+        let response = client
+            .options("/v1/prover")
+            .header(Header::new("Origin", "https://app.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .header(Header::new(
+                "Access-Control-Request-Headers",
+                "content-type",
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        let headers = response.headers();
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Methods"),
+            Some("GET, POST, PATCH, DELETE")
+        );
+        assert_eq!(
+            headers.get_one("Access-Control-Allow-Headers"),
+            Some(
+                "Content-Type, Authorization, X-Api-Key, X-Request-Id, X-Proof-Signature, \
+                 X-Proof-Nonce, X-Proof-Expires"
+            )
+        );
+        assert_eq!(headers.get_one("Access-Control-Max-Age"), Some("3600"));
+
+        let response = client
+            .options("/v1/prover")
+            .header(Header::new("Origin", "https://app.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "PUT"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .options("/v1/prover")
+            .header(Header::new("Origin", "https://app.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .header(Header::new("Access-Control-Request-Headers", "X-Secret"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .options("/v1/prove/move/0.0.1")
+            .header(Header::new("Origin", "https://app.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .header(Header::new(
+                "Access-Control-Request-Headers",
+                "x-api-key, x-proof-signature, x-proof-nonce, x-proof-expires",
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+    }
+
+    #[test]
+    fn unit_wildcard_origin() {
+        let client = client(CorsConfig {
+            allowed_origins: vec![String::from("*")],
+            ..CorsConfig::default()
+        });
+        let response = client
+            .get("/")
+            .header(Header::new("Origin", "https://anywhere.example.com"))
+            .dispatch();
+        let headers = response.headers();
+        assert_eq!(headers.get_one("Access-Control-Allow-Origin"), Some("*"));
+        assert!(headers
+            .get_one("Access-Control-Allow-Credentials")
+            .is_none());
+    }
+
+    #[test]
+    fn unit_validate() {
+        assert!(app_policy().validate().is_ok());
+        for policy in [
+            CorsConfig {
+                allowed_origins: vec![String::from("*")],
+                allow_credentials: true,
+                ..CorsConfig::default()
+            },
+            CorsConfig {
+                allowed_origins: vec![String::from("app.example.com")],
+                ..CorsConfig::default()
+            },
+            CorsConfig {
+                allowed_origins: vec![String::from("https://app.example.com/path")],
+                ..CorsConfig::default()
+            },
+            CorsConfig {
+                allowed_methods: vec![String::from("FETCH IT")],
+                ..CorsConfig::default()
+            },
+        ] {
+            assert!(
+                policy.validate().is_err(),
+                "{:?} should be rejected",
+                policy
+            );
+        }
+    }
+}
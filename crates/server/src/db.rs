@@ -15,19 +15,28 @@ pub fn init_async_database(config: EnvConfig) -> Result<Db> {
 pub fn init_tables(conn: Connection) -> Result<Connection> {
     conn.execute(
         "
-    CREATE TABLE prover (
+    CREATE TABLE IF NOT EXISTS prover (
         id INTEGER PRIMARY KEY,
+        namespace TEXT NOT NULL DEFAULT 'default',
         name TEXT NOT NULL,
         version TEXT NOT NULL,
         path_to_wasm  TEXT NOT NULL,
         path_to_zkey TEXT NOT NULL,
-        path_to_r1cs TEXT NOT NULL
+        path_to_r1cs TEXT NOT NULL,
+        wasm_hash TEXT,
+        zkey_hash TEXT,
+        r1cs_hash TEXT,
+        owner TEXT,
+        visibility TEXT NOT NULL DEFAULT 'public',
+        pinned_wasm_hash TEXT,
+        pinned_zkey_hash TEXT,
+        pinned_r1cs_hash TEXT
     )",
         [],
     )?;
     conn.execute(
         "
-    CREATE TABLE builder_params (
+    CREATE TABLE IF NOT EXISTS builder_params (
         id INTEGER PRIMARY KEY,
         name TEXT NOT NULL,
         prover INTEGER,
@@ -39,7 +48,109 @@ pub fn init_tables(conn: Connection) -> Result<Connection> {
     )?;
     conn.execute(
         "
-    CREATE TABLE job (
+    CREATE TABLE IF NOT EXISTS npm_source (
+        id INTEGER PRIMARY KEY,
+        prover INTEGER NOT NULL,
+        spec TEXT NOT NULL,
+        package TEXT NOT NULL,
+        version TEXT NOT NULL,
+        circuit TEXT NOT NULL,
+        tarball TEXT NOT NULL,
+        integrity TEXT NOT NULL,
+        FOREIGN KEY(prover) REFERENCES prover(id)
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS prover_signer (
+        id INTEGER PRIMARY KEY,
+        address TEXT NOT NULL,
+        prover INTEGER NOT NULL,
+        FOREIGN KEY(prover) REFERENCES prover(id)
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS prover_acl (
+        id INTEGER PRIMARY KEY,
+        prover INTEGER NOT NULL,
+        principal TEXT NOT NULL,
+        permission TEXT NOT NULL,
+        FOREIGN KEY(prover) REFERENCES prover(id)
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS prover_limit (
+        id INTEGER PRIMARY KEY,
+        prover INTEGER NOT NULL UNIQUE,
+        requests_per_minute INTEGER,
+        burst INTEGER,
+        proving_seconds_per_day INTEGER,
+        FOREIGN KEY(prover) REFERENCES prover(id)
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS prover_alias (
+        namespace TEXT NOT NULL,
+        name TEXT NOT NULL,
+        alias TEXT NOT NULL,
+        version TEXT NOT NULL,
+        updated_at INTEGER NOT NULL,
+        PRIMARY KEY(namespace, name, alias)
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS proving_usage (
+        client TEXT NOT NULL,
+        prover INTEGER NOT NULL,
+        day INTEGER NOT NULL,
+        seconds REAL NOT NULL,
+        PRIMARY KEY(client, prover, day)
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS signature_nonce (
+        signer TEXT NOT NULL,
+        nonce TEXT NOT NULL,
+        expires_at INTEGER NOT NULL,
+        PRIMARY KEY(signer, nonce)
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS api_key (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        hash TEXT NOT NULL UNIQUE,
+        scopes TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        revoked_at INTEGER,
+        namespace TEXT
+    )
+    ",
+        [],
+    )?;
+    conn.execute(
+        "
+    CREATE TABLE IF NOT EXISTS job (
         id INTEGER PRIMARY KEY,
         status INTEGER not null,
         message TEXT not null,
@@ -49,8 +160,58 @@ pub fn init_tables(conn: Connection) -> Result<Connection> {
     ",
         [],
     )?;
+    // databases from before artifacts were stored by hash
+    add_column(&conn, "prover", "wasm_hash", "TEXT")?;
+    add_column(&conn, "prover", "zkey_hash", "TEXT")?;
+    add_column(&conn, "prover", "r1cs_hash", "TEXT")?;
+    // databases from before namespaces existed
+    add_column(
+        &conn,
+        "prover",
+        "namespace",
+        "TEXT NOT NULL DEFAULT 'default'",
+    )?;
+    add_column(&conn, "api_key", "namespace", "TEXT")?;
+    add_column(&conn, "prover", "owner", "TEXT")?;
+    add_column(
+        &conn,
+        "prover",
+        "visibility",
+        "TEXT NOT NULL DEFAULT 'public'",
+    )?;
+    // databases from before checksums were kept apart from verified hashes
+    add_column(&conn, "prover", "pinned_wasm_hash", "TEXT")?;
+    add_column(&conn, "prover", "pinned_zkey_hash", "TEXT")?;
+    add_column(&conn, "prover", "pinned_r1cs_hash", "TEXT")?;
+    // databases from before the queue was kept in the job table
+    add_column(&conn, "job", "queued_at", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(&conn, "job", "lease_owner", "TEXT")?;
+    add_column(&conn, "job", "leased_until", "INTEGER")?;
+    add_column(&conn, "job", "attempts", "INTEGER NOT NULL DEFAULT 0")?;
+    // databases from before jobs kept their history
+    add_column(&conn, "job", "kind", "TEXT NOT NULL DEFAULT 'load'")?;
+    add_column(&conn, "job", "created_at", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column(&conn, "job", "started_at", "INTEGER")?;
+    add_column(&conn, "job", "finished_at", "INTEGER")?;
     Ok(conn)
 }
+
+/// Adds `column` to a table created by an older version that didn't have it yet.
+fn add_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let exists = conn
+        .prepare(&format!(
+            "SELECT 1 FROM pragma_table_info('{}') where name = ?1",
+            table
+        ))?
+        .exists([column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
 #[cfg(test)]
 use crate::types::Config;
 #[cfg(test)]
@@ -97,15 +258,81 @@ async fn unit_insert_into_prover() -> Result<()> {
     let prover_iter = stmt.query_map([], |row| {
         Ok(ProverConfig {
             id: None,
+            namespace: String::from(crate::models::DEFAULT_NAMESPACE),
             name: row.get(1)?,
             version: row.get(2)?,
             path_to_wasm: row.get(3)?,
             path_to_zkey: row.get(4)?,
             path_to_r1cs: row.get(5)?,
             builder_params: vec![],
+            wasm_hash: None,
+            zkey_hash: None,
+            r1cs_hash: None,
+            pinned_wasm_hash: None,
+            pinned_zkey_hash: None,
+            pinned_r1cs_hash: None,
+            npm: None,
+            signers: vec![],
+            limits: Default::default(),
+            owner: None,
+            visibility: Default::default(),
+            acl: vec![],
         })
     })?;
     let provers: Vec<ProverConfig> = prover_iter.map(|r| r.unwrap()).collect();
     assert_eq!(provers.len(), 1);
     Ok(())
 }
+
+#[test]
+fn unit_old_databases_gain_namespaces() -> Result<()> {
+    let conn = Connection::open_in_memory()?;
+    conn.execute(
+        "CREATE TABLE prover (id INTEGER PRIMARY KEY, name TEXT NOT NULL, version TEXT NOT NULL,
+        path_to_wasm TEXT NOT NULL, path_to_zkey TEXT NOT NULL, path_to_r1cs TEXT NOT NULL,
+        wasm_hash TEXT, zkey_hash TEXT, r1cs_hash TEXT)",
+        [],
+    )?;
+    conn.execute(
+        "insert into prover (name, version, path_to_wasm, path_to_zkey, path_to_r1cs) values ('move', '1', 'a', 'b', 'c')",
+        [],
+    )?;
+    let conn = init_tables(init_tables(conn)?)?;
+    let namespace: String = conn.query_row("SELECT namespace FROM prover", [], |row| row.get(0))?;
+    assert_eq!(namespace, "default");
+    // and then owners, existing provers stay open
+    use crate::models::Crud;
+    let prover = crate::models::ProverConfig::get(1, &conn)?;
+    assert_eq!(prover.owner, None);
+    assert_eq!(prover.visibility, crate::acl::Visibility::Public);
+    Ok(())
+}
+
+#[test]
+fn unit_databases_from_before_hashes_are_migrated() -> Result<()> {
+    use crate::models::{Crud, Job, ProverConfig};
+    let conn = Connection::open_in_memory()?;
+    // the schema before artifacts were stored by hash
+    conn.execute(
+        "CREATE TABLE prover (id INTEGER PRIMARY KEY, name TEXT NOT NULL, version TEXT NOT NULL,
+        path_to_wasm TEXT NOT NULL, path_to_zkey TEXT NOT NULL, path_to_r1cs TEXT NOT NULL)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE job (id INTEGER PRIMARY KEY, status INTEGER not null,
+        message TEXT not null, prover INTEGER, FOREIGN KEY(prover) REFERENCES prover(id))",
+        [],
+    )?;
+    conn.execute(
+        "insert into prover (name, version, path_to_wasm, path_to_zkey, path_to_r1cs) values ('move', '1', 'a', 'b', 'c')",
+        [],
+    )?;
+    let conn = init_tables(conn)?;
+    let prover = ProverConfig::get(1, &conn)?;
+    assert_eq!(prover.wasm_hash, None);
+    assert!(prover.npm.is_none());
+    assert!(prover.signers.is_empty());
+    assert_eq!(prover.limits, Default::default());
+    assert!(Job::get_for_prover(1, &conn)?.is_none());
+    Ok(())
+}
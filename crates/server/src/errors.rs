@@ -10,8 +10,75 @@ pub enum ProvingServerError {
         #[from]
         source: reqwest::Error,
     },
+    #[error("Database Error {source:?}")]
+    Database {
+        #[from]
+        source: rusqlite::Error,
+    },
+    #[error("IO Error {source:?}")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
     #[error("Bad Proof Input Error: {message}")]
     BadProofInputsError { message: String },
+    #[error("Proof Failed: {message}")]
+    ProofFailed { message: String },
+    #[error("Fetch Denied: {reason}")]
+    FetchDenied { reason: String },
+    #[error("Artifact Too Large: exceeded {limit} bytes")]
+    ArtifactTooLarge { limit: u64 },
+    #[error("Invalid Prover Config: {message}")]
+    InvalidProverConfig { message: String },
+    #[error("Invalid Artifact Path: {path}")]
+    InvalidArtifactPath { path: String },
+    #[error("Checksum Mismatch: expected {expected} but got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error("Storage Error: {message}")]
+    Storage { message: String },
+    #[error("Unauthorized: {message}")]
+    Unauthorized { message: String },
+    #[error("Forbidden: {message}")]
+    Forbidden { message: String },
+    #[error("Prover {name}@{version} does not exist")]
+    ProverNotFound { name: String, version: String },
+    #[error("Prover {name}@{version} is still loading")]
+    ProverNotReady { name: String, version: String },
+    #[error("Invalid Config: {message}")]
+    InvalidConfig { message: String },
+    #[error("Invalid Manifest {path}: {message}")]
+    InvalidManifest { path: String, message: String },
+    #[error("Shutting down, try another instance")]
+    ShuttingDown,
+    #[error("Load queue is full, {capacity} jobs are already waiting")]
+    QueueFull { capacity: usize },
+    #[error("Job {id} does not exist")]
+    JobNotFound { id: i64 },
+    #[error("Invalid job filter: {message}")]
+    InvalidJobFilter { message: String },
+    #[error("Job {id} already finished as {status:?}")]
+    JobFinished {
+        id: i64,
+        status: crate::models::JobStatus,
+    },
+    #[error("Unknown priority {priority}, use interactive, normal or batch")]
+    InvalidPriority { priority: String },
+    #[error("Proof {id} does not exist")]
+    ProofNotFound { id: String },
+    #[error("Prover needs {needed} bytes but only {available} are left in the memory budget")]
+    OverMemoryBudget { needed: u64, available: u64 },
+    #[error("Too Many Requests: {message}")]
+    RateLimited {
+        message: String,
+        budget: crate::limits::Budget,
+    },
+}
+
+fn text_response<'o>(status: Status, body: String) -> rocket::response::Result<'o> {
+    Response::build()
+        .sized_body(body.len(), Cursor::new(body))
+        .status(status)
+        .ok()
 }
 
 impl<'r, 'o: 'r> Responder<'r, 'o> for ProvingServerError {
@@ -19,13 +86,44 @@ impl<'r, 'o: 'r> Responder<'r, 'o> for ProvingServerError {
         // log `self` to your favored error tracker, e.g.
         // sentry::capture_error(&self);
         match self {
-            ProvingServerError::BadProofInputsError { message } => Response::build()
-                .sized_body(
-                    format!("{} is missing from your inputs", message).len(),
-                    Cursor::new(format!("{} is missing from your inputs", message)),
-                )
-                .status(Status::BadRequest)
-                .ok(),
+            ProvingServerError::BadProofInputsError { message } => text_response(
+                Status::BadRequest,
+                format!("{} is missing from your inputs", message),
+            ),
+            ProvingServerError::FetchDenied { .. }
+            | ProvingServerError::ArtifactTooLarge { .. }
+            | ProvingServerError::InvalidProverConfig { .. }
+            | ProvingServerError::InvalidJobFilter { .. }
+            | ProvingServerError::InvalidPriority { .. }
+            | ProvingServerError::InvalidArtifactPath { .. } => {
+                text_response(Status::BadRequest, self.to_string())
+            }
+            ProvingServerError::Unauthorized { .. } => {
+                text_response(Status::Unauthorized, self.to_string())
+            }
+            ProvingServerError::Forbidden { .. } => {
+                text_response(Status::Forbidden, self.to_string())
+            }
+            ProvingServerError::ProverNotFound { .. }
+            | ProvingServerError::JobNotFound { .. }
+            | ProvingServerError::ProofNotFound { .. } => {
+                text_response(Status::NotFound, self.to_string())
+            }
+            ProvingServerError::JobFinished { .. } => {
+                text_response(Status::Conflict, self.to_string())
+            }
+            ProvingServerError::ProverNotReady { .. }
+            | ProvingServerError::ShuttingDown
+            | ProvingServerError::QueueFull { .. } => {
+                text_response(Status::ServiceUnavailable, self.to_string())
+            }
+            ProvingServerError::RateLimited { ref budget, .. } => {
+                let mut response = text_response(Status::TooManyRequests, self.to_string())?;
+                for header in budget.headers() {
+                    response.set_header(header);
+                }
+                Ok(response)
+            }
             // in our simplistic example, we're happy to respond with the default 500 responder in all cases
             _ => Status::InternalServerError.respond_to(req),
         }
@@ -0,0 +1,381 @@
+/// Guarded downloads for user supplied artifact urls
+use crate::errors::ProvingServerError;
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use reqwest::redirect;
+use reqwest::Url;
+use rocket::serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+// Ranges that are never reachable from the public internet. Blocking these by default keeps
+// registrations from pointing us at cloud metadata endpoints or services on the private network.
+// The IPv6 prefixes that embed an IPv4 address are blocked whole, since they can reach any of it.
+const PRIVATE_RANGES: [&str; 20] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/96",
+    "::1/128",
+    "::ffff:0:0/96",
+    "64:ff9b::/96",
+    "64:ff9b:1::/48",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+lazy_static! {
+    static ref PRIVATE_NETS: Vec<IpNet> = PRIVATE_RANGES
+        .iter()
+        .map(|range| range.parse().unwrap())
+        .collect();
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct FetchPolicy {
+    pub allowed_schemes: Vec<String>,
+    /// When non empty, only these hosts may be fetched. `*.example.com` matches subdomains.
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+    /// Explicitly allowed ranges, these take precedence over `block_private`.
+    pub allowed_cidrs: Vec<IpNet>,
+    pub denied_cidrs: Vec<IpNet>,
+    pub block_private: bool,
+    pub max_bytes: u64,
+    pub max_redirects: usize,
+    pub connect_timeout_secs: u64,
+    /// Limit on a whole download, body included
+    pub timeout_secs: u64,
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        FetchPolicy {
+            allowed_schemes: vec![String::from("https"), String::from("http")],
+            allowed_hosts: vec![],
+            denied_hosts: vec![],
+            allowed_cidrs: vec![],
+            denied_cidrs: vec![],
+            block_private: true,
+            max_bytes: 1 << 30,
+            max_redirects: 5,
+            connect_timeout_secs: 10,
+            timeout_secs: 30 * 60,
+        }
+    }
+}
+
+fn denied(reason: String) -> ProvingServerError {
+    ProvingServerError::FetchDenied { reason }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host.ends_with(&format!(".{}", suffix)),
+        None => pattern == host,
+    }
+}
+
+pub fn is_private(ip: &IpAddr) -> bool {
+    PRIVATE_NETS.iter().any(|net| net.contains(ip))
+}
+
+impl FetchPolicy {
+    /// Checks everything that can be known about a url without touching the network.
+    pub fn check_url(&self, url: &Url) -> Result<(), ProvingServerError> {
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme.eq_ignore_ascii_case(url.scheme()))
+        {
+            return Err(denied(format!("scheme {} is not allowed", url.scheme())));
+        }
+        let host = match url.host_str() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Err(denied(format!("{} has no host", url))),
+        };
+        if self.denied_hosts.iter().any(|p| host_matches(p, host)) {
+            return Err(denied(format!("host {} is denied", host)));
+        }
+        if !self.allowed_hosts.is_empty()
+            && !self.allowed_hosts.iter().any(|p| host_matches(p, host))
+        {
+            return Err(denied(format!("host {} is not in the allow list", host)));
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            self.check_ip(&ip)?;
+        }
+        Ok(())
+    }
+
+    pub fn check_ip(&self, ip: &IpAddr) -> Result<(), ProvingServerError> {
+        if self.denied_cidrs.iter().any(|net| net.contains(ip)) {
+            return Err(denied(format!("address {} is denied", ip)));
+        }
+        if self.allowed_cidrs.iter().any(|net| net.contains(ip)) {
+            return Ok(());
+        }
+        if self.block_private && is_private(ip) {
+            return Err(denied(format!("address {} is private", ip)));
+        }
+        Ok(())
+    }
+
+    /// Resolves the host of `url` and checks every address it points at. Returns the address
+    /// to connect to, which the download pins so a second lookup can't rebind the host to an
+    /// address that was never checked. Hosts that are ip addresses need no pin.
+    pub async fn check_resolved(
+        &self,
+        url: &Url,
+    ) -> Result<Option<SocketAddr>, ProvingServerError> {
+        self.check_url(url)?;
+        let host = url.host_str().unwrap_or_default();
+        let bare = host.trim_start_matches('[').trim_end_matches(']');
+        if bare.parse::<IpAddr>().is_ok() {
+            return Ok(None);
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((bare, port))
+            .await
+            .map_err(|e| denied(format!("could not resolve {}: {}", host, e)))?
+            .collect();
+        for addr in &addrs {
+            self.check_ip(&addr.ip())?;
+        }
+        match addrs.first() {
+            Some(addr) => Ok(Some(*addr)),
+            None => Err(denied(format!("{} did not resolve to any address", host))),
+        }
+    }
+
+    /// A client for a single hop, connecting to `pinned` instead of resolving the host again.
+    fn client(
+        &self,
+        url: &Url,
+        pinned: Option<SocketAddr>,
+    ) -> Result<reqwest::Client, ProvingServerError> {
+        // a proxy would resolve the host itself, past the pin and the address checks
+        let mut builder = reqwest::Client::builder()
+            .no_proxy()
+            .redirect(redirect::Policy::none())
+            .connect_timeout(Duration::from_secs(self.connect_timeout_secs))
+            .timeout(Duration::from_secs(self.timeout_secs));
+        if let (Some(host), Some(addr)) = (url.host_str(), pinned) {
+            builder = builder.resolve(host, addr);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+pub fn parse_url(url: &str) -> Result<Url, ProvingServerError> {
+    Url::parse(url).map_err(|e| denied(format!("{} is not a valid url: {}", url, e)))
+}
+
+/// Streams `url` into `dest`, re-validating the target on every redirect hop and aborting once
+/// the body grows past `policy.max_bytes`. Returns the number of bytes written.
+pub async fn fetch_to_writer<W: Write>(
+    policy: &FetchPolicy,
+    url: &str,
+    dest: &mut W,
+) -> Result<u64, ProvingServerError> {
+    let mut url = parse_url(url)?;
+    let mut hops = 0;
+    let mut resp = loop {
+        let pinned = policy.check_resolved(&url).await?;
+        let resp = policy.client(&url, pinned)?.get(url.clone()).send().await?;
+        if !resp.status().is_redirection() {
+            break resp;
+        }
+        hops += 1;
+        if hops > policy.max_redirects {
+            return Err(denied(format!("too many redirects fetching {}", url)));
+        }
+        let location = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| denied(format!("redirect from {} without a location", url)))?;
+        url = url
+            .join(location)
+            .map_err(|e| denied(format!("bad redirect location {}: {}", location, e)))?;
+    };
+    let resp_status = resp.status();
+    if !resp_status.is_success() {
+        return Err(denied(format!("{} responded with {}", url, resp_status)));
+    }
+    if resp.content_length().unwrap_or(0) > policy.max_bytes {
+        return Err(ProvingServerError::ArtifactTooLarge {
+            limit: policy.max_bytes,
+        });
+    }
+    let mut written: u64 = 0;
+    while let Some(chunk) = resp.chunk().await? {
+        written += chunk.len() as u64;
+        if written > policy.max_bytes {
+            return Err(ProvingServerError::ArtifactTooLarge {
+                limit: policy.max_bytes,
+            });
+        }
+        dest.write_all(&chunk)?;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch_to_writer, parse_url, FetchPolicy};
+    use crate::errors::ProvingServerError;
+    use crate::test::fixtures::http::{serve, TestResponse};
+
+    fn local_policy() -> FetchPolicy {
+        FetchPolicy {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..FetchPolicy::default()
+        }
+    }
+
+    #[test]
+    fn unit_blocks_private_and_link_local_by_default() {
+        let policy = FetchPolicy::default();
+        for url in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:8000/v1/prover",
+            "http://10.1.2.3/move.zkey",
+            "http://[::1]/move.zkey",
+            "http://[fe80::1]/move.zkey",
+            // 169.254.169.254 through NAT64, 6to4 and as an IPv4-compatible address
+            "http://[64:ff9b::a9fe:a9fe]/",
+            "http://[2002:a9fe:a9fe::1]/",
+            "http://[::a9fe:a9fe]/",
+        ] {
+            assert!(
+                policy.check_url(&parse_url(url).unwrap()).is_err(),
+                "{}",
+                url
+            );
+        }
+        let ok = parse_url("https://unpkg.com/@darkforest_eth/snarks@6.6.6/move.wasm").unwrap();
+        assert!(policy.check_url(&ok).is_ok());
+    }
+
+    #[test]
+    fn unit_scheme_and_host_lists() {
+        let policy = FetchPolicy {
+            allowed_schemes: vec![String::from("https")],
+            allowed_hosts: vec![String::from("*.unpkg.com"), String::from("unpkg.com")],
+            denied_hosts: vec![String::from("evil.unpkg.com")],
+            ..FetchPolicy::default()
+        };
+        let check = |url: &str| policy.check_url(&parse_url(url).unwrap()).is_ok();
+        assert!(check("https://unpkg.com/move.wasm"));
+        assert!(check("https://cdn.unpkg.com/move.wasm"));
+        assert!(!check("http://unpkg.com/move.wasm"));
+        assert!(!check("file:///etc/passwd"));
+        assert!(!check("https://evil.unpkg.com/move.wasm"));
+        assert!(!check("https://example.com/move.wasm"));
+    }
+
+    #[tokio::test]
+    async fn unit_resolved_hosts_are_pinned() {
+        let url = parse_url("http://localhost:8123/move.zkey").unwrap();
+        let res = FetchPolicy::default().check_resolved(&url).await;
+        assert!(matches!(res, Err(ProvingServerError::FetchDenied { .. })));
+
+        let policy = FetchPolicy {
+            allowed_cidrs: vec!["127.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()],
+            ..FetchPolicy::default()
+        };
+        let pinned = policy.check_resolved(&url).await.unwrap().unwrap();
+        assert!(pinned.ip().is_loopback());
+        assert_eq!(pinned.port(), 8123);
+        let literal = parse_url("http://127.0.0.1:8123/move.zkey").unwrap();
+        assert_eq!(policy.check_resolved(&literal).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn unit_system_proxies_are_ignored() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let proxied = Arc::new(AtomicUsize::new(0));
+        let counter = proxied.clone();
+        let proxy = serve(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            TestResponse::ok("from the proxy")
+        })
+        .await;
+        let base = serve(|_| TestResponse::ok("direct")).await;
+        let url = parse_url(&format!("{}/move.zkey", base)).unwrap();
+        // only set while the client is built, which is when reqwest reads it
+        std::env::set_var("HTTP_PROXY", &proxy);
+        let client = local_policy().client(&url, None);
+        std::env::remove_var("HTTP_PROXY");
+        let body = client.unwrap().get(url).send().await.unwrap().text().await;
+        assert_eq!(body.unwrap(), "direct");
+        assert_eq!(proxied.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn unit_redirect_to_metadata_endpoint_is_denied() {
+        let base =
+            serve(|_| TestResponse::redirect("http://169.254.169.254/latest/meta-data/")).await;
+        let mut dest = vec![];
+        let res = fetch_to_writer(&local_policy(), &format!("{}/move.zkey", base), &mut dest).await;
+        assert!(matches!(res, Err(ProvingServerError::FetchDenied { .. })));
+        assert!(dest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unit_redirect_to_denied_host_is_denied() {
+        let base = serve(|_| TestResponse::redirect("http://localhost/admin")).await;
+        let policy = FetchPolicy {
+            denied_hosts: vec![String::from("localhost")],
+            ..local_policy()
+        };
+        let res = fetch_to_writer(&policy, &format!("{}/move.zkey", base), &mut vec![]).await;
+        assert!(matches!(res, Err(ProvingServerError::FetchDenied { .. })));
+    }
+
+    #[tokio::test]
+    async fn unit_follows_allowed_redirects_and_caps_size() {
+        let base = serve(|req| match req.path.as_str() {
+            "/old" => TestResponse::redirect("/new"),
+            "/loop" => TestResponse::redirect("/loop"),
+            _ => TestResponse::ok(vec![7; 64]),
+        })
+        .await;
+        let mut dest = vec![];
+        let written = fetch_to_writer(&local_policy(), &format!("{}/old", base), &mut dest)
+            .await
+            .unwrap();
+        assert_eq!(written, 64);
+        assert_eq!(dest, vec![7; 64]);
+
+        let small = FetchPolicy {
+            max_bytes: 16,
+            ..local_policy()
+        };
+        let res = fetch_to_writer(&small, &format!("{}/new", base), &mut vec![]).await;
+        assert!(matches!(
+            res,
+            Err(ProvingServerError::ArtifactTooLarge { limit: 16 })
+        ));
+
+        let res = fetch_to_writer(&local_policy(), &format!("{}/loop", base), &mut vec![]).await;
+        assert!(matches!(res, Err(ProvingServerError::FetchDenied { .. })));
+    }
+}
@@ -0,0 +1,184 @@
+/// Liveness and readiness probes for Cloud Run and Kubernetes
+///
+/// `/health/live` answers whenever the server does. `/health/ready` answers 200 once the
+/// database responds, a load worker is running and every prover listed in the manifest is loaded,
+/// and 503 until then, with the state of each listed prover so deploys can gate on them. Probes
+/// don't carry credentials, so neither route needs an api key.
+use crate::models::{Job, ProverConfig};
+use crate::shutdown::Drain;
+use crate::types::proof::Provers;
+use crate::types::reqres::{ProverReadiness, ReadinessResponse};
+use crate::types::Db;
+use rusqlite::{Connection, OptionalExtension};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// How many worker tasks are running. Cloning shares the count.
+#[derive(Clone, Default)]
+pub struct WorkerHealth(Arc<AtomicUsize>);
+
+/// Counts a worker as running until it is dropped, which also happens when the worker panics.
+pub struct Running(WorkerHealth);
+
+impl WorkerHealth {
+    pub fn running(&self) -> Running {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Running(self.clone())
+    }
+
+    /// Whether any worker is left to take load jobs.
+    pub fn is_alive(&self) -> bool {
+        self.0.load(Ordering::SeqCst) > 0
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        (self.0).0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequiredProver {
+    pub namespace: String,
+    pub name: String,
+    pub version: String,
+}
+
+/// Provers that have to be loaded before the server is ready, those listed in the manifest.
+#[derive(Default)]
+pub struct RequiredProvers(Mutex<Vec<RequiredProver>>);
+
+impl RequiredProvers {
+    pub async fn set(&self, provers: Vec<RequiredProver>) {
+        *self.0.lock().await = provers;
+    }
+}
+
+fn database_responds(conn: &Connection) -> bool {
+    conn.query_row("select 1", [], |row| row.get::<_, i64>(0))
+        .is_ok()
+}
+
+fn prover_readiness(
+    required: &RequiredProver,
+    loaded: bool,
+    conn: &Connection,
+) -> Result<ProverReadiness, rusqlite::Error> {
+    let prover = ProverConfig::get_by_name_and_version(
+        &required.namespace,
+        required.name.clone(),
+        required.version.clone(),
+        conn,
+    )
+    .optional()?;
+    let job = match prover.and_then(|prover| prover.id) {
+        Some(id) => Job::get_for_prover(id, conn)?,
+        None => None,
+    };
+    Ok(ProverReadiness {
+        namespace: required.namespace.clone(),
+        name: required.name.clone(),
+        version: required.version.clone(),
+        status: job.as_ref().map(|job| job.status),
+        message: job.map(|job| job.message),
+        loaded,
+    })
+}
+
+/// Checks everything the server needs before it should get traffic. A draining server is never
+/// ready, so traffic moves elsewhere before it stops.
+pub async fn readiness(
+    db: &Db,
+    provers: &Provers,
+    required: &RequiredProvers,
+    worker: &WorkerHealth,
+    drain: &Drain,
+) -> ReadinessResponse {
+    let required = required.0.lock().await.clone();
+    let loaded: Vec<bool> = {
+        let provers = provers.lock().await;
+        required
+            .iter()
+            .map(|p| provers.contains_key(&format!("{}/{}@{}", p.namespace, p.name, p.version)))
+            .collect()
+    };
+    let conn = db.lock().await;
+    let database = database_responds(&conn);
+    let provers: Vec<ProverReadiness> = if database {
+        required
+            .iter()
+            .zip(loaded)
+            .filter_map(|(p, loaded)| prover_readiness(p, loaded, &conn).ok())
+            .collect()
+    } else {
+        vec![]
+    };
+    drop(conn);
+    let worker = worker.is_alive();
+    let draining = drain.is_draining();
+    ReadinessResponse {
+        ready: !draining
+            && database
+            && worker
+            && provers.len() == required.len()
+            && provers.iter().all(|p| p.loaded),
+        database,
+        worker,
+        draining,
+        provers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Crud, JobStatus, DEFAULT_NAMESPACE};
+    use crate::test::fixtures;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn unit_ready_once_required_provers_load() {
+        let db: Db = Arc::new(Mutex::new(fixtures::setup_db().await));
+        let provers = crate::utils::init_provers();
+        let required = RequiredProvers::default();
+        let worker = WorkerHealth::default();
+        let drain = Drain::default();
+
+        let report = readiness(&db, &provers, &required, &worker, &drain).await;
+        assert!(report.database);
+        assert!(!report.worker);
+        assert!(!report.ready);
+
+        let running = worker.running();
+        assert!(
+            readiness(&db, &provers, &required, &worker, &drain)
+                .await
+                .ready
+        );
+        assert_eq!(drain.drain(Duration::from_secs(1)).await, 0);
+        let report = readiness(&db, &provers, &required, &worker, &drain).await;
+        assert!(report.draining && !report.ready);
+        let drain = Drain::default();
+
+        let mut prover = fixtures::df_prover_config();
+        prover.create(&*db.lock().await).unwrap();
+        Job::queue_for_prover(&prover, String::from("fetching"), &*db.lock().await).unwrap();
+        required
+            .set(vec![RequiredProver {
+                namespace: String::from(DEFAULT_NAMESPACE),
+                name: prover.name.clone(),
+                version: prover.version.clone(),
+            }])
+            .await;
+        let report = readiness(&db, &provers, &required, &worker, &drain).await;
+        assert!(!report.ready);
+        assert_eq!(report.provers.len(), 1);
+        assert_eq!(report.provers[0].status, Some(JobStatus::Pending));
+        assert!(!report.provers[0].loaded);
+
+        drop(running);
+        assert!(!worker.is_alive());
+    }
+}
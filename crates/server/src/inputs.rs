@@ -0,0 +1,185 @@
+/// Private proof inputs
+///
+/// Inputs are the prover's witness, such as a player's secret coordinates, so they must never
+/// reach a log line, an error message, the database or a panic. `PrivateInputs` formats its
+/// values as `<redacted>`, can't be serialized, refuses bad values without echoing them, and
+/// zeroizes its own values once they are handed to the circuit builder or it is dropped. The
+/// copies the builder and the witness make belong to ark-circom, which frees them without
+/// clearing them.
+use rocket::serde::de::{self, Deserializer, MapAccess, Visitor};
+use rocket::serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Inputs to a proof by builder param.
+#[derive(Default)]
+pub struct PrivateInputs(HashMap<String, u64>);
+
+impl PrivateInputs {
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.0.get(name).copied()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// Names and values, for hashing the inputs into a signed message.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &u64)> {
+        self.0.iter()
+    }
+}
+
+impl From<HashMap<String, u64>> for PrivateInputs {
+    fn from(inputs: HashMap<String, u64>) -> Self {
+        PrivateInputs(inputs)
+    }
+}
+
+impl Zeroize for PrivateInputs {
+    fn zeroize(&mut self) {
+        for value in self.0.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+impl Drop for PrivateInputs {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl fmt::Debug for PrivateInputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.0.keys().collect();
+        names.sort();
+        f.debug_map()
+            .entries(names.into_iter().map(|name| (name, Redacted)))
+            .finish()
+    }
+}
+
+impl<'de> Deserialize<'de> for PrivateInputs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(InputsVisitor)
+    }
+}
+
+struct InputsVisitor;
+
+impl<'de> Visitor<'de> for InputsVisitor {
+    type Value = PrivateInputs;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of input names to unsigned integers")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PrivateInputs, A::Error> {
+        // dropped, and so zeroized, if a later entry is refused
+        let mut inputs = PrivateInputs::default();
+        while let Some(name) = map.next_key::<String>()? {
+            let InputValue(value) = map.next_value()?;
+            inputs.0.insert(name, value);
+        }
+        Ok(inputs)
+    }
+}
+
+/// One input value. serde's own errors quote the value they got, so anything but an unsigned
+/// integer is refused here with an error of our own.
+struct InputValue(u64);
+
+impl<'de> Deserialize<'de> for InputValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // `deserialize_u64` would let the format describe what it found instead
+        deserializer.deserialize_any(InputValueVisitor)
+    }
+}
+
+struct InputValueVisitor;
+
+fn not_unsigned<E: de::Error>() -> E {
+    E::custom("proof inputs must be unsigned integers")
+}
+
+impl<'de> Visitor<'de> for InputValueVisitor {
+    type Value = InputValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an unsigned integer")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<InputValue, E> {
+        Ok(InputValue(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<InputValue, E> {
+        Err(not_unsigned())
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<InputValue, E> {
+        Err(not_unsigned())
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<InputValue, E> {
+        Err(not_unsigned())
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<InputValue, E> {
+        Err(not_unsigned())
+    }
+
+    fn visit_bytes<E: de::Error>(self, _: &[u8]) -> Result<InputValue, E> {
+        Err(not_unsigned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json;
+
+    #[test]
+    fn unit_inputs_never_show_their_values() {
+        let mut inputs: PrivateInputs =
+            json::from_str(r#"{"x1": 48611, "PLANETHASH_KEY": 77123}"#).unwrap();
+        assert_eq!(inputs.get("x1"), Some(48611));
+        let printed = format!("{:?}", inputs);
+        assert_eq!(
+            printed,
+            r#"{"PLANETHASH_KEY": <redacted>, "x1": <redacted>}"#
+        );
+
+        inputs.zeroize();
+        assert_eq!(inputs.get("x1"), Some(0));
+        assert_eq!(inputs.get("PLANETHASH_KEY"), Some(0));
+
+        for body in [
+            r#"{"x1": -48611}"#,
+            r#"{"x1": 48611.5}"#,
+            r#"{"x1": "48611"}"#,
+            r#"{"x1": true}"#,
+            r#"{"x1": 48611, "y1": 184467440737095516150}"#,
+        ] {
+            let error = json::from_str::<PrivateInputs>(body).unwrap_err();
+            let message = error.to_string();
+            assert!(!message.contains("48611"), "{} leaked in {}", body, message);
+            assert!(
+                !message.contains("18446744"),
+                "{} leaked in {}",
+                body,
+                message
+            );
+        }
+    }
+}
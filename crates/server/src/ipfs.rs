@@ -0,0 +1,547 @@
+/// Verified downloads of `ipfs://<cid>` artifacts through an untrusted http gateway
+///
+/// Blocks are requested one at a time in the trustless gateway format (`?format=raw`) and
+/// each block is hashed against the CID that named it before anything is written. UnixFS
+/// files are walked in order, so only bytes that descend from the requested root ever reach
+/// the blob store.
+use crate::errors::ProvingServerError;
+use crate::fetch::{fetch_to_writer, FetchPolicy};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Write;
+
+const SHA2_256: u64 = 0x12;
+const RAW: u64 = 0x55;
+const DAG_PB: u64 = 0x70;
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+/// Upper bound on blocks per file, a 1 GiB file in default 256 KiB chunks needs ~4100
+const MAX_BLOCKS: usize = 1 << 16;
+
+fn invalid_cid(cid: &str, reason: &str) -> ProvingServerError {
+    ProvingServerError::InvalidProverConfig {
+        message: format!("{} is not a supported cid: {}", cid, reason),
+    }
+}
+
+fn malformed(reason: &str) -> ProvingServerError {
+    ProvingServerError::Storage {
+        message: format!("malformed ipfs block: {}", reason),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cid {
+    pub version: u64,
+    pub codec: u64,
+    /// sha2-256 digest of the block
+    pub digest: Vec<u8>,
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// The `length` bytes at `pos`, moving past them. `None` when they run past the end.
+fn read_bytes<'a>(bytes: &'a [u8], pos: &mut usize, length: u64) -> Option<&'a [u8]> {
+    let end = pos.checked_add(usize::try_from(length).ok()?)?;
+    let read = bytes.get(*pos..end)?;
+    *pos = end;
+    Some(read)
+}
+
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in input.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+fn base32_encode(input: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for byte in input {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base58_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out: Vec<u8> = vec![];
+    for c in input.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in out.iter_mut().rev() {
+            carry += *byte as u32 * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            out.insert(0, (carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = input.bytes().take_while(|c| *c == b'1').count();
+    let mut decoded = vec![0u8; zeros];
+    decoded.extend(out);
+    Some(decoded)
+}
+
+fn base58_encode(input: &[u8]) -> String {
+    let mut digits: Vec<u8> = vec![];
+    for byte in input {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut().rev() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.insert(0, (carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = input.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat('1')
+        .take(zeros)
+        .chain(digits.iter().map(|d| BASE58_ALPHABET[*d as usize] as char))
+        .collect()
+}
+
+impl Cid {
+    /// Parses the string form, CIDv0 (`Qm...`) or CIDv1 in base32 (`b...`) or base58 (`z...`).
+    pub fn parse(cid: &str) -> Result<Cid, ProvingServerError> {
+        if cid.len() == 46 && cid.starts_with("Qm") {
+            let bytes = base58_decode(cid).ok_or_else(|| invalid_cid(cid, "bad base58"))?;
+            return Cid::from_bytes(&bytes).ok_or_else(|| invalid_cid(cid, "bad multihash"));
+        }
+        let bytes = match cid.chars().next() {
+            Some('b') => base32_decode(&cid[1..]),
+            Some('z') => base58_decode(&cid[1..]),
+            _ => None,
+        }
+        .ok_or_else(|| invalid_cid(cid, "expected a base32 or base58 multibase"))?;
+        let parsed = Cid::from_bytes(&bytes).ok_or_else(|| invalid_cid(cid, "bad encoding"))?;
+        if parsed.codec != RAW && parsed.codec != DAG_PB {
+            return Err(invalid_cid(
+                cid,
+                "only raw and dag-pb blocks can be verified",
+            ));
+        }
+        Ok(parsed)
+    }
+
+    /// Parses the binary form used inside dag-pb links.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Cid> {
+        let mut pos = 0;
+        let (version, codec) = if bytes.len() == 34 && bytes[0] == SHA2_256 as u8 {
+            (0, DAG_PB)
+        } else {
+            let version = read_varint(bytes, &mut pos)?;
+            let codec = read_varint(bytes, &mut pos)?;
+            (version, codec)
+        };
+        if version > 1 {
+            return None;
+        }
+        let hash = read_varint(bytes, &mut pos)?;
+        let length = read_varint(bytes, &mut pos)?;
+        let digest = read_bytes(bytes, &mut pos, length)?.to_vec();
+        if hash != SHA2_256 || length != 32 || pos != bytes.len() {
+            return None;
+        }
+        Some(Cid {
+            version,
+            codec,
+            digest,
+        })
+    }
+
+    pub fn verify(&self, block: &[u8]) -> Result<(), ProvingServerError> {
+        let actual = Sha256::digest(block);
+        if actual.as_slice() != self.digest.as_slice() {
+            return Err(ProvingServerError::ChecksumMismatch {
+                expected: hex::encode(&self.digest),
+                actual: hex::encode(actual),
+            });
+        }
+        Ok(())
+    }
+
+    fn multihash(&self) -> Vec<u8> {
+        let mut bytes = vec![SHA2_256 as u8, 32];
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version == 0 {
+            return write!(f, "{}", base58_encode(&self.multihash()));
+        }
+        // both codecs fit in a single varint byte
+        let mut bytes = vec![1, self.codec as u8];
+        bytes.extend(self.multihash());
+        write!(f, "b{}", base32_encode(&bytes))
+    }
+}
+
+/// `ipfs://<cid>` or `ipfs://<cid>/` to a parsed CID, `None` for any other url.
+pub fn parse_ipfs_url(url: &str) -> Option<Result<Cid, ProvingServerError>> {
+    let cid = url.strip_prefix("ipfs://")?.trim_end_matches('/');
+    Some(Cid::parse(cid))
+}
+
+/// The fields of a dag-pb node this reader cares about.
+struct PbNode {
+    links: Vec<Cid>,
+    /// UnixFS `Data.Data`, the file bytes stored in this node
+    data: Vec<u8>,
+}
+
+/// Iterates the (field number, wire type, payload) entries of a protobuf message.
+fn pb_fields(bytes: &[u8]) -> Result<Vec<(u64, u64, &[u8], u64)>, ProvingServerError> {
+    let mut fields = vec![];
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let key = read_varint(bytes, &mut pos).ok_or_else(|| malformed("bad field key"))?;
+        let (field, wire) = (key >> 3, key & 7);
+        match wire {
+            0 => {
+                let value = read_varint(bytes, &mut pos).ok_or_else(|| malformed("bad varint"))?;
+                fields.push((field, wire, &bytes[0..0], value));
+            }
+            2 => {
+                let length = read_varint(bytes, &mut pos).ok_or_else(|| malformed("bad length"))?;
+                let payload = read_bytes(bytes, &mut pos, length)
+                    .ok_or_else(|| malformed("truncated field"))?;
+                fields.push((field, wire, payload, 0));
+            }
+            _ => return Err(malformed("unsupported wire type")),
+        }
+    }
+    Ok(fields)
+}
+
+fn parse_pb_node(block: &[u8]) -> Result<PbNode, ProvingServerError> {
+    let mut node = PbNode {
+        links: vec![],
+        data: vec![],
+    };
+    for (field, wire, payload, _) in pb_fields(block)? {
+        match (field, wire) {
+            (2, 2) => {
+                let hash = pb_fields(payload)?
+                    .into_iter()
+                    .find(|(field, wire, _, _)| *field == 1 && *wire == 2)
+                    .ok_or_else(|| malformed("link without a hash"))?
+                    .2;
+                node.links
+                    .push(Cid::from_bytes(hash).ok_or_else(|| malformed("link with a bad cid"))?);
+            }
+            (1, 2) => {
+                for (field, wire, inner, value) in pb_fields(payload)? {
+                    match (field, wire) {
+                        // 0 raw, 2 file. Directories, symlinks and the rest are not artifacts.
+                        (1, 0) if value != 0 && value != 2 => {
+                            return Err(malformed("not a unixfs file"))
+                        }
+                        (2, 2) => node.data = inner.to_vec(),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(node)
+}
+
+/// Writes the file named by `cid` into `dest`, verifying every block against its CID.
+/// Returns the number of bytes written.
+pub async fn fetch_cid<W: Write>(
+    policy: &FetchPolicy,
+    gateway: &str,
+    cid: &Cid,
+    dest: &mut W,
+) -> Result<u64, ProvingServerError> {
+    let mut written: u64 = 0;
+    let mut fetched = 0;
+    // depth first, children in order, so the file comes out front to back
+    let mut pending = vec![cid.clone()];
+    while let Some(cid) = pending.pop() {
+        fetched += 1;
+        if fetched > MAX_BLOCKS {
+            return Err(ProvingServerError::ArtifactTooLarge {
+                limit: policy.max_bytes,
+            });
+        }
+        let mut block = vec![];
+        let url = format!("{}/ipfs/{}?format=raw", gateway.trim_end_matches('/'), cid);
+        fetch_to_writer(policy, &url, &mut block).await?;
+        cid.verify(&block)?;
+        let data = match cid.codec {
+            RAW => block,
+            _ => {
+                let node = parse_pb_node(&block)?;
+                pending.extend(node.links.into_iter().rev());
+                node.data
+            }
+        };
+        written += data.len() as u64;
+        if written > policy.max_bytes {
+            return Err(ProvingServerError::ArtifactTooLarge {
+                limit: policy.max_bytes,
+            });
+        }
+        dest.write_all(&data)?;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::fixtures::http::{serve, TestResponse};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn raw_cid(block: &[u8]) -> Cid {
+        Cid {
+            version: 1,
+            codec: RAW,
+            digest: Sha256::digest(block).to_vec(),
+        }
+    }
+
+    fn pb_bytes(field: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![(field << 3) | 2];
+        let mut length = payload.len();
+        loop {
+            let byte = (length & 0x7f) as u8;
+            length >>= 7;
+            if length == 0 {
+                out.push(byte);
+                break;
+            }
+            out.push(byte | 0x80);
+        }
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// A dag-pb file node linking `children` in order, as `ipfs add` would produce.
+    fn file_node(children: &[Cid]) -> Vec<u8> {
+        let mut block = vec![];
+        for child in children {
+            let mut hash = vec![1, child.codec as u8];
+            hash.extend(child.multihash());
+            block.extend(pb_bytes(2, &pb_bytes(1, &hash)));
+        }
+        // unixfs Data { Type: File }
+        block.extend(pb_bytes(1, &[0x08, 0x02]));
+        block
+    }
+
+    fn gateway(
+        blocks: HashMap<String, Vec<u8>>,
+    ) -> impl Fn(crate::test::fixtures::http::TestRequest) -> TestResponse + Send + Sync + 'static
+    {
+        let blocks = Arc::new(blocks);
+        move |req| {
+            let cid = req
+                .path
+                .trim_start_matches("/ipfs/")
+                .trim_end_matches("?format=raw");
+            match blocks.get(cid) {
+                Some(block) => TestResponse::ok(block.clone()),
+                None => TestResponse::status(404),
+            }
+        }
+    }
+
+    fn local_policy() -> FetchPolicy {
+        FetchPolicy {
+            allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+            ..FetchPolicy::default()
+        }
+    }
+
+    #[test]
+    fn unit_parse_cids() {
+        // sha256 of the empty string
+        let v0 = Cid::parse("QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n").unwrap();
+        assert_eq!((v0.version, v0.codec), (0, DAG_PB));
+        assert_eq!(
+            v0.to_string(),
+            "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR1n"
+        );
+
+        let v1 = Cid::parse("bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku").unwrap();
+        assert_eq!((v1.version, v1.codec), (1, RAW));
+        assert_eq!(
+            hex::encode(&v1.digest),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!(v1.verify(b"").is_ok());
+        assert_eq!(
+            v1.to_string(),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+
+        for bad in [
+            "",
+            "Qm",
+            "bafy",
+            "xyz",
+            "QmdfTbBqBPQ7VNxZEYEj14VmRuZBkqFbiwReogJgS1zR10",
+        ] {
+            assert!(Cid::parse(bad).is_err(), "{}", bad);
+        }
+        assert!(parse_ipfs_url("https://ipfs.io/ipfs/Qm").is_none());
+        assert!(parse_ipfs_url(
+            "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        )
+        .unwrap()
+        .is_ok());
+    }
+
+    #[test]
+    fn unit_decoders_survive_arbitrary_input() {
+        // a length of u64::MAX, which used to overflow `pos + length`
+        let huge = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        let cid = [&[1, RAW as u8, SHA2_256 as u8][..], &huge].concat();
+        assert!(Cid::from_bytes(&cid).is_none());
+        let field = [&[0x0a][..], &huge].concat();
+        assert!(parse_pb_node(&field).is_err());
+        assert!(parse_pb_node(&pb_bytes(2, &field)).is_err());
+
+        let mut rng = StdRng::seed_from_u64(0x1f5);
+        let node = file_node(&[raw_cid(b"move"), raw_cid(b"zkey")]);
+        let alphabet: Vec<char> =
+            "bzQm123456789abcdefghijkmnopqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ0Ol"
+                .chars()
+                .collect();
+        for _ in 0..20_000 {
+            // random bytes, or a valid node with a few bytes changed and maybe cut short
+            let bytes: Vec<u8> = if rng.gen() {
+                (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect()
+            } else {
+                let mut bytes = node.clone();
+                for _ in 0..rng.gen_range(1..4) {
+                    let at = rng.gen_range(0..bytes.len());
+                    bytes[at] = rng.gen();
+                }
+                bytes.truncate(rng.gen_range(0..=bytes.len()));
+                bytes
+            };
+            let _ = Cid::from_bytes(&bytes);
+            let _ = parse_pb_node(&bytes);
+            let text: String = (0..rng.gen_range(0..64))
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect();
+            let _ = Cid::parse(&text);
+
+            let mut digest = vec![0u8; 32];
+            rng.fill(&mut digest[..]);
+            for (version, codec) in [(0, DAG_PB), (1, DAG_PB), (1, RAW)] {
+                let cid = Cid {
+                    version,
+                    codec,
+                    digest: digest.clone(),
+                };
+                assert_eq!(Cid::parse(&cid.to_string()).unwrap(), cid);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_fetch_verified_file() {
+        let first = b"move.zkey part one ".to_vec();
+        let second = b"and part two".to_vec();
+        let (first_cid, second_cid) = (raw_cid(&first), raw_cid(&second));
+        let root = file_node(&[first_cid.clone(), second_cid.clone()]);
+        let root_cid = Cid {
+            version: 0,
+            codec: DAG_PB,
+            digest: Sha256::digest(&root).to_vec(),
+        };
+        let mut blocks = HashMap::new();
+        blocks.insert(first_cid.to_string(), first);
+        blocks.insert(second_cid.to_string(), second);
+        blocks.insert(root_cid.to_string(), root);
+        let base = serve(gateway(blocks)).await;
+
+        let mut dest = vec![];
+        let written = fetch_cid(&local_policy(), &base, &root_cid, &mut dest)
+            .await
+            .unwrap();
+        assert_eq!(dest, b"move.zkey part one and part two");
+        assert_eq!(written, dest.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn unit_gateway_cannot_swap_content() {
+        let honest = b"real zkey".to_vec();
+        let cid = raw_cid(&honest);
+        let mut blocks = HashMap::new();
+        blocks.insert(cid.to_string(), b"evil zkey".to_vec());
+        let base = serve(gateway(blocks)).await;
+
+        let mut dest = vec![];
+        let res = fetch_cid(&local_policy(), &base, &cid, &mut dest).await;
+        assert!(matches!(
+            res,
+            Err(ProvingServerError::ChecksumMismatch { .. })
+        ));
+        assert!(dest.is_empty());
+
+        // swapping a leaf under an honest root is caught too
+        let root = file_node(&[cid.clone()]);
+        let root_cid = raw_cid(&root);
+        let root_cid = Cid {
+            codec: DAG_PB,
+            ..root_cid
+        };
+        let mut blocks = HashMap::new();
+        blocks.insert(root_cid.to_string(), root);
+        blocks.insert(cid.to_string(), b"evil zkey".to_vec());
+        let base = serve(gateway(blocks)).await;
+        let res = fetch_cid(&local_policy(), &base, &root_cid, &mut vec![]).await;
+        assert!(matches!(
+            res,
+            Err(ProvingServerError::ChecksumMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,470 @@
+/// Per client rate limits and daily proving quotas for `/v1/prove`
+///
+/// Clients are told apart by api key, by signer for signed requests, and by address
+/// otherwise. Each client gets a token bucket per prover, refilled at `requests_per_minute`
+/// and holding at most `burst` requests, plus a budget of seconds spent in `prover::prove`
+/// per UTC day. Proving time is wall-clock time, since the prover fans out over a thread pool
+/// and the calling thread's CPU time would miss most of the work. Admitting a request reserves
+/// the seconds its prover usually takes, and the actual time is settled once the proof is
+/// done, so concurrent requests can't all slip in under the quota. Buckets live in memory,
+/// proving time is recorded in the `proving_usage` table so quotas survive restarts. Provers
+/// can override any of the `[limits]` defaults, and 0 turns a limit off.
+use crate::errors::ProvingServerError;
+use crate::models::{ProverConfig, ProvingUsage};
+use rocket::http::Header;
+use rocket::response::{self, Responder};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+/// Past this many buckets, full ones are dropped since they hold nothing worth keeping
+const MAX_BUCKETS: usize = 10_000;
+/// What a proof is expected to take before the scheduler has timed any
+const DEFAULT_EXPECTED_SECONDS: f64 = 1.0;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LimitsConfig {
+    pub requests_per_minute: u32,
+    /// Requests a client may make back to back before the rate applies
+    pub burst: u32,
+    /// Wall-clock seconds of proving each client gets per prover and UTC day
+    pub proving_seconds_per_day: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            requests_per_minute: 60,
+            burst: 10,
+            proving_seconds_per_day: 3600,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        if self.requests_per_minute > 0 && self.burst == 0 {
+            return Err(ProvingServerError::InvalidConfig {
+                message: String::from("limits.burst must be above 0 when requests are limited"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A prover's overrides of the `[limits]` defaults.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ProverLimits {
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>,
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub proving_seconds_per_day: Option<u64>,
+}
+
+impl ProverLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == ProverLimits::default()
+    }
+
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        if self.requests_per_minute.unwrap_or(0) > 0 && self.burst == Some(0) {
+            return Err(ProvingServerError::InvalidProverConfig {
+                message: String::from("limits.burst must be above 0 when requests are limited"),
+            });
+        }
+        Ok(())
+    }
+
+    /// The limits in force for a prover, falling back to `defaults`.
+    pub fn resolve(&self, defaults: &LimitsConfig) -> LimitsConfig {
+        LimitsConfig {
+            requests_per_minute: self
+                .requests_per_minute
+                .unwrap_or(defaults.requests_per_minute),
+            burst: self.burst.unwrap_or(defaults.burst).max(1),
+            proving_seconds_per_day: self
+                .proving_seconds_per_day
+                .unwrap_or(defaults.proving_seconds_per_day),
+        }
+    }
+}
+
+/// What a client has left, sent back as headers on proofs and on 429s.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Budget {
+    /// Bucket size and the requests left in it
+    pub requests: Option<(u32, u32)>,
+    /// Proving seconds per day and how many are left today
+    pub proving_seconds: Option<(u64, u64)>,
+    /// Seconds until the proving quota resets
+    pub resets_in: u64,
+    pub retry_after: Option<u64>,
+    /// Proving time charged up front by `admit`, settled by `record`
+    pub reservation: Option<Reservation>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reservation {
+    pub day: i64,
+    pub seconds: f64,
+}
+
+impl Budget {
+    pub fn headers(&self) -> Vec<Header<'static>> {
+        let mut headers = vec![];
+        if let Some((limit, remaining)) = self.requests {
+            headers.push(Header::new("X-RateLimit-Limit", limit.to_string()));
+            headers.push(Header::new("X-RateLimit-Remaining", remaining.to_string()));
+        }
+        if let Some((limit, remaining)) = self.proving_seconds {
+            headers.push(Header::new("X-Quota-Limit", limit.to_string()));
+            headers.push(Header::new("X-Quota-Remaining", remaining.to_string()));
+            headers.push(Header::new("X-Quota-Reset", self.resets_in.to_string()));
+        }
+        if let Some(retry_after) = self.retry_after {
+            headers.push(Header::new("Retry-After", retry_after.to_string()));
+        }
+        headers
+    }
+}
+
+/// Adds the client's remaining budget to a response.
+pub struct WithBudget<R>(pub R, pub Budget);
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for WithBudget<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.0.respond_to(req)?;
+        for header in self.1.headers() {
+            response.set_header(header);
+        }
+        Ok(response)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by client and prover.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(String, i64), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the client's bucket. Returns the whole tokens left, or how many
+    /// seconds to wait for the next one.
+    fn take(&self, key: (String, i64), limits: &LimitsConfig, now: Instant) -> Result<u32, u64> {
+        let capacity = limits.burst as f64;
+        let per_second = limits.requests_per_minute as f64 / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens
+                    + per_second * now.saturating_duration_since(bucket.updated).as_secs_f64()
+                    < capacity
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(((1.0 - bucket.tokens) / per_second).ceil() as u64);
+        }
+        bucket.tokens -= 1.0;
+        Ok(bucket.tokens.floor() as u32)
+    }
+}
+
+fn today(now: i64) -> i64 {
+    now / SECONDS_PER_DAY
+}
+
+fn rate_limited(message: String, budget: Budget) -> ProvingServerError {
+    ProvingServerError::RateLimited { message, budget }
+}
+
+/// Lets a proof request through if `client` has both a request and proving time left for
+/// `prover`, and reserves `expected_seconds` of proving time, the scheduler's estimate when it
+/// has one. `at` drives the buckets and `now` (unix seconds) picks the quota day. Every
+/// admitted request must be settled with `record`, with 0 seconds when it never got to prove.
+#[allow(clippy::too_many_arguments)]
+pub fn admit(
+    limiter: &RateLimiter,
+    client: &str,
+    prover: &ProverConfig,
+    defaults: &LimitsConfig,
+    expected_seconds: Option<f64>,
+    at: Instant,
+    now: i64,
+    conn: &Connection,
+) -> Result<Budget, ProvingServerError> {
+    let limits = prover.limits.resolve(defaults);
+    let mut budget = Budget {
+        resets_in: (SECONDS_PER_DAY - now.rem_euclid(SECONDS_PER_DAY)) as u64,
+        ..Budget::default()
+    };
+    if limits.proving_seconds_per_day > 0 {
+        let used = ProvingUsage::seconds(client, prover.id.unwrap(), today(now), conn)?;
+        let remaining = (limits.proving_seconds_per_day as f64 - used).max(0.0) as u64;
+        budget.proving_seconds = Some((limits.proving_seconds_per_day, remaining));
+        if remaining == 0 {
+            budget.retry_after = Some(budget.resets_in);
+            return Err(rate_limited(
+                format!(
+                    "the daily proving quota for {}@{} is used up",
+                    prover.name, prover.version
+                ),
+                budget,
+            ));
+        }
+    }
+    if limits.requests_per_minute > 0 {
+        let key = (client.to_string(), prover.id.unwrap());
+        match limiter.take(key, &limits, at) {
+            Ok(remaining) => budget.requests = Some((limits.burst, remaining)),
+            Err(retry_after) => {
+                budget.requests = Some((limits.burst, 0));
+                budget.retry_after = Some(retry_after);
+                return Err(rate_limited(
+                    format!(
+                        "more than {} requests a minute to {}@{}",
+                        limits.requests_per_minute, prover.name, prover.version
+                    ),
+                    budget,
+                ));
+            }
+        }
+    }
+    if let Some((limit, remaining)) = &mut budget.proving_seconds {
+        let reservation = Reservation {
+            day: today(now),
+            seconds: expected_seconds.unwrap_or(DEFAULT_EXPECTED_SECONDS),
+        };
+        let used = ProvingUsage::add(
+            client,
+            prover.id.unwrap(),
+            reservation.day,
+            reservation.seconds,
+            conn,
+        )?;
+        *remaining = (*limit as f64 - used).max(0.0) as u64;
+        budget.reservation = Some(reservation);
+    }
+    Ok(budget)
+}
+
+/// Charges `seconds` of proving to `client`, less what `admit` reserved, updating `budget` to
+/// match.
+pub fn record(
+    client: &str,
+    prover: &ProverConfig,
+    seconds: f64,
+    now: i64,
+    budget: &mut Budget,
+    conn: &Connection,
+) -> Result<(), ProvingServerError> {
+    // the reservation is settled on the day it was made, even past midnight
+    let (day, charge) = match budget.reservation.take() {
+        Some(reservation) => (reservation.day, seconds - reservation.seconds),
+        None => (today(now), seconds),
+    };
+    let used = ProvingUsage::add(client, prover.id.unwrap(), day, charge, conn)?;
+    if let Some((limit, remaining)) = &mut budget.proving_seconds {
+        *remaining = (*limit as f64 - used).max(0.0) as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Crud;
+    use crate::test::fixtures;
+    use std::time::Duration;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn limits(requests_per_minute: u32, burst: u32, seconds: u64) -> LimitsConfig {
+        LimitsConfig {
+            requests_per_minute,
+            burst,
+            proving_seconds_per_day: seconds,
+        }
+    }
+
+    #[test]
+    fn unit_bucket_refills() {
+        let limiter = RateLimiter::default();
+        let limits = limits(60, 2, 0);
+        let start = Instant::now();
+        let key = || (String::from("key:a"), 1);
+        assert_eq!(limiter.take(key(), &limits, start), Ok(1));
+        assert_eq!(limiter.take(key(), &limits, start), Ok(0));
+        assert_eq!(limiter.take(key(), &limits, start), Err(1));
+        // other clients and provers have buckets of their own
+        assert_eq!(
+            limiter.take((String::from("key:b"), 1), &limits, start),
+            Ok(1)
+        );
+        assert_eq!(
+            limiter.take((String::from("key:a"), 2), &limits, start),
+            Ok(1)
+        );
+        // one request a second
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(limiter.take(key(), &limits, later), Ok(0));
+        assert_eq!(limiter.take(key(), &limits, later), Err(1));
+    }
+
+    #[test]
+    fn unit_prover_limits_override_defaults() {
+        let overrides = ProverLimits {
+            burst: Some(1),
+            proving_seconds_per_day: Some(0),
+            ..ProverLimits::default()
+        };
+        assert_eq!(
+            overrides.resolve(&LimitsConfig::default()),
+            limits(60, 1, 0)
+        );
+        assert!(ProverLimits::default().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unit_quota_persists_in_the_database() {
+        let conn = fixtures::setup_db().await;
+        let mut prover = fixtures::df_prover_config();
+        prover.limits.proving_seconds_per_day = Some(10);
+        prover.create(&conn).unwrap();
+        let limiter = RateLimiter::default();
+        let defaults = limits(0, 0, 3600);
+        let at = Instant::now();
+
+        let mut budget =
+            admit(&limiter, "key:a", &prover, &defaults, None, at, NOW, &conn).unwrap();
+        // a second up front for a prover that hasn't proven anything yet
+        assert_eq!(budget.proving_seconds, Some((10, 9)));
+        record("key:a", &prover, 6.5, NOW, &mut budget, &conn).unwrap();
+        assert_eq!(budget.proving_seconds, Some((10, 3)));
+        assert!(budget.reservation.is_none());
+        assert!(budget.requests.is_none());
+
+        let mut budget =
+            admit(&limiter, "key:a", &prover, &defaults, None, at, NOW, &conn).unwrap();
+        record("key:a", &prover, 4.0, NOW, &mut budget, &conn).unwrap();
+        match admit(&limiter, "key:a", &prover, &defaults, None, at, NOW, &conn) {
+            Err(ProvingServerError::RateLimited { budget, .. }) => {
+                assert_eq!(budget.proving_seconds, Some((10, 0)));
+                assert_eq!(budget.retry_after, Some(budget.resets_in));
+            }
+            other => panic!("expected the quota to be used up, got {:?}", other),
+        }
+        // other clients and the next day start afresh
+        assert!(admit(&limiter, "key:b", &prover, &defaults, None, at, NOW, &conn).is_ok());
+        let tomorrow = NOW + SECONDS_PER_DAY;
+        assert!(admit(&limiter, "key:a", &prover, &defaults, None, at, tomorrow, &conn).is_ok());
+    }
+
+    #[tokio::test]
+    async fn unit_concurrent_requests_reserve_their_time() {
+        let conn = fixtures::setup_db().await;
+        let mut prover = fixtures::df_prover_config();
+        prover.limits.proving_seconds_per_day = Some(10);
+        prover.create(&conn).unwrap();
+        let limiter = RateLimiter::default();
+        let defaults = limits(0, 0, 3600);
+        let at = Instant::now();
+        let expected = Some(4.0);
+
+        // three requests arrive before any of them has proven
+        let mut first = admit(
+            &limiter, "key:a", &prover, &defaults, expected, at, NOW, &conn,
+        )
+        .unwrap();
+        let mut second = admit(
+            &limiter, "key:a", &prover, &defaults, expected, at, NOW, &conn,
+        )
+        .unwrap();
+        assert_eq!(second.proving_seconds, Some((10, 2)));
+        let mut third = admit(
+            &limiter, "key:a", &prover, &defaults, expected, at, NOW, &conn,
+        )
+        .unwrap();
+        assert_eq!(third.proving_seconds, Some((10, 0)));
+        assert!(admit(&limiter, "key:a", &prover, &defaults, expected, at, NOW, &conn).is_err());
+
+        // one finishes early, one never gets to prove, and one finishes on the next day
+        record("key:a", &prover, 3.0, NOW, &mut first, &conn).unwrap();
+        record("key:a", &prover, 0.0, NOW, &mut second, &conn).unwrap();
+        let tomorrow = NOW + SECONDS_PER_DAY;
+        record("key:a", &prover, 4.5, tomorrow, &mut third, &conn).unwrap();
+        assert_eq!(third.proving_seconds, Some((10, 2)));
+        let day = today(NOW);
+        let used = ProvingUsage::seconds("key:a", prover.id.unwrap(), day, &conn).unwrap();
+        assert_eq!(used, 7.5);
+        let next = ProvingUsage::seconds("key:a", prover.id.unwrap(), day + 1, &conn).unwrap();
+        assert_eq!(next, 0.0);
+    }
+
+    #[tokio::test]
+    async fn unit_rate_limit_headers() {
+        let conn = fixtures::setup_db().await;
+        let mut prover = fixtures::df_prover_config();
+        prover.create(&conn).unwrap();
+        let limiter = RateLimiter::default();
+        let defaults = limits(6, 1, 0);
+        let at = Instant::now();
+        admit(
+            &limiter,
+            "ip:127.0.0.1",
+            &prover,
+            &defaults,
+            None,
+            at,
+            NOW,
+            &conn,
+        )
+        .unwrap();
+        match admit(
+            &limiter,
+            "ip:127.0.0.1",
+            &prover,
+            &defaults,
+            None,
+            at,
+            NOW,
+            &conn,
+        ) {
+            Err(ProvingServerError::RateLimited { budget, .. }) => {
+                let headers: Vec<String> = budget
+                    .headers()
+                    .iter()
+                    .map(|h| format!("{}: {}", h.name(), h.value()))
+                    .collect();
+                assert_eq!(
+                    headers,
+                    vec![
+                        "X-RateLimit-Limit: 1",
+                        "X-RateLimit-Remaining: 0",
+                        "Retry-After: 10"
+                    ]
+                );
+            }
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+    }
+}
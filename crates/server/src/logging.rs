@@ -0,0 +1,182 @@
+/// Structured logs and request ids, driven by the `[logging]` config section
+///
+/// Everything logs through `tracing`, either as text or as one JSON object per line for
+/// collectors like Cloud Logging. Every request gets an id, the caller's `X-Request-Id` when it
+/// sends a usable one, which is echoed back and carried by the spans of the routes that prove
+/// and register provers. Load jobs run in spans of their own carrying the job and prover.
+use crate::errors::ProvingServerError;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Data, Request, Response};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", default)]
+pub struct LoggingConfig {
+    /// `error`, `warn`, `info`, `debug`, `trace` or `off`. `RUST_LOG` takes precedence when set.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: String::from("info"),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        match self.level.parse::<LevelFilter>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ProvingServerError::InvalidConfig {
+                message: format!(
+                    "logging.level must be one of error, warn, info, debug, trace or off, got {:?}",
+                    self.level
+                ),
+            }),
+        }
+    }
+}
+
+/// Installs the global subscriber. Only the first call takes effect, so building several
+/// servers in one process (as the tests do) keeps logging where the first one put it.
+pub fn init(config: &LoggingConfig) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let _ = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+    };
+}
+
+/// Milliseconds, as recorded on log events.
+pub fn millis(elapsed: Duration) -> u64 {
+    elapsed.as_millis() as u64
+}
+
+/// The id a request is logged under, also available to routes as a guard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+pub fn new_request_id() -> RequestId {
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    RequestId(hex::encode(bytes))
+}
+
+/// Ids from callers end up in every log line, so only short plain ones are kept.
+fn usable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn request_id<'r>(request: &'r Request<'_>) -> &'r RequestId {
+    request.local_cache(new_request_id)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request_id(request).clone())
+    }
+}
+
+struct Received(Instant);
+
+/// Assigns every request its id and logs it once it has been answered.
+pub struct RequestLog;
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Tag requests with an id and log them",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data) {
+        let id = match request.headers().get_one(REQUEST_ID_HEADER) {
+            Some(id) if usable(id) => RequestId(id.to_string()),
+            _ => new_request_id(),
+        };
+        request.local_cache(|| id);
+        request.local_cache(|| Received(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = request_id(request);
+        let received = request.local_cache(|| Received(Instant::now()));
+        tracing::info!(
+            request_id = %id,
+            method = %request.method(),
+            uri = %request.uri(),
+            status = response.status().code,
+            elapsed_ms = millis(received.0.elapsed()),
+            "request"
+        );
+        response.set_header(Header::new(REQUEST_ID_HEADER, id.0.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_levels_and_request_ids_are_checked() {
+        let mut config = LoggingConfig::default();
+        assert!(config.validate().is_ok());
+        config.level = String::from("DEBUG");
+        assert!(config.validate().is_ok());
+        config.level = String::from("loud");
+        assert!(matches!(
+            config.validate(),
+            Err(ProvingServerError::InvalidConfig { .. })
+        ));
+
+        assert!(usable("7f9c2ba4-e88f-11ea-adc1"));
+        assert!(!usable(""));
+        assert!(!usable("two words"));
+        assert!(!usable("line\nbreak"));
+        assert!(!usable(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+        assert_eq!(new_request_id().0.len(), 16);
+    }
+}
@@ -1,74 +1,145 @@
+mod acl;
+mod alias;
+mod auth;
+mod cli;
+mod config;
+mod cors;
 mod db;
 mod errors;
+mod fetch;
+mod health;
+mod inputs;
+mod ipfs;
+mod limits;
+mod logging;
+mod manifest;
+mod memory;
+mod metrics;
 mod models;
+mod npm;
 mod prover;
+mod queue;
 mod routes;
+mod s3;
+mod scheduler;
+mod shutdown;
+mod signature;
+mod storage;
 mod test;
 mod types;
 mod utils;
 mod worker;
-use std::sync::mpsc;
 extern crate dotenv;
 #[macro_use]
 extern crate rocket;
-use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::Header;
-use rocket::{Request, Response};
-
-pub struct CORS;
-
-#[rocket::async_trait]
-impl Fairing for CORS {
-    fn info(&self) -> Info {
-        Info {
-            name: "Add CORS headers to responses",
-            kind: Kind::Response,
-        }
-    }
-
-    async fn on_response<'r>(&self, _: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new(
-            "Access-Control-Allow-Methods",
-            "POST, GET, PATCH, OPTIONS",
-        ));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
-    }
-}
+use rocket::fairing::AdHoc;
 
 #[rocket::main]
 async fn main() -> Result<(), crate::errors::ProvingServerError> {
-    rocket().attach(CORS).launch().await.unwrap();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = cli::run(&args).await {
+        return result;
+    }
+    utils::load_environment_variables();
+    let config = utils::init_config()?;
+    rocket(config).launch().await.unwrap();
     Ok(())
 }
 
-fn rocket() -> rocket::Rocket<rocket::Build> {
-    utils::load_environment_variables();
-    let (tx, rx) = mpsc::sync_channel(1);
-    let config = utils::init_config();
+fn rocket(config: types::EnvConfig) -> rocket::Rocket<rocket::Build> {
+    logging::init(&config.logging);
     let conn: types::Db = db::init_async_database(config.clone()).unwrap();
     let provers = utils::init_provers();
-    let figment = rocket::Config::figment().merge(("port", &config.port.clone()));
+    let metrics = metrics::Metrics::default();
+    let worker_health = health::WorkerHealth::default();
+    let drain = shutdown::Drain::default();
+    let queue = queue::JobQueue::default();
+    let scheduler = scheduler::Scheduler::new(config.scheduler.clone());
+    // SIGTERM and ctrl-c drain the server first, see `shutdown`
+    let figment = rocket::Config::figment()
+        .merge(("port", &config.port.clone()))
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()));
+    let cors = cors::CORS::new(config.cors.clone());
+    let shared_config = utils::init_async_config(config.clone());
 
-    // Create pointers for thread to reference
-    let t_conn = conn.clone();
-    let t_provers = provers.clone();
-    tokio::spawn(async move { worker::worker(t_conn, config, t_provers, rx).await });
+    worker::Pool {
+        db: conn.clone(),
+        provers: provers.clone(),
+        metrics: metrics.clone(),
+        health: worker_health.clone(),
+        drain: drain.clone(),
+        queue: queue.clone(),
+        budget: memory::MemoryBudget::new(config.queue.memory_budget_bytes),
+        config,
+    }
+    .spawn();
 
     rocket::custom(figment)
-        .manage(types::JobSender(tx))
+        .attach(logging::RequestLog)
+        .attach(cors)
+        .attach(AdHoc::try_on_ignite(
+            "Prover manifest",
+            manifest::reconcile_at_boot,
+        ))
+        .attach(AdHoc::try_on_ignite(
+            "Resume interrupted jobs",
+            shutdown::resume_at_boot,
+        ))
+        .attach(AdHoc::on_liftoff("Drain on shutdown", |rocket| {
+            Box::pin(shutdown::drain_on_signal(rocket))
+        }))
+        .manage(queue)
         .manage(conn)
-        .manage(utils::init_async_config())
+        .manage(shared_config)
         .manage(provers)
-        .mount("/", routes![routes::index])
+        .manage(limits::RateLimiter::default())
+        .manage(metrics)
+        .manage(worker_health)
+        .manage(drain)
+        .manage(health::RequiredProvers::default())
+        .manage(scheduler)
+        .mount(
+            "/",
+            routes![
+                routes::index,
+                routes::live_handler,
+                routes::ready_handler,
+                routes::metrics_handler,
+                cors::preflight
+            ],
+        )
         .mount(
             "/v1/",
             routes![
                 routes::add_prover_handler,
+                routes::update_prover_handler,
+                routes::delete_prover_handler,
                 routes::list_provers_handler,
                 routes::execute_prover,
-                routes::get_prover
+                routes::get_prover,
+                routes::list_jobs_handler,
+                routes::proof_status_handler,
+                routes::list_blobs_handler,
+                routes::gc_handler,
+                routes::get_verifying_key,
+                routes::list_aliases_handler,
+                routes::set_alias_handler,
+                routes::delete_alias_handler,
+                routes::ns_add_prover_handler,
+                routes::ns_update_prover_handler,
+                routes::ns_delete_prover_handler,
+                routes::ns_list_provers_handler,
+                routes::ns_execute_prover,
+                routes::ns_get_prover,
+                routes::ns_list_jobs_handler,
+                routes::ns_proof_status_handler,
+                routes::ns_get_verifying_key,
+                routes::ns_list_aliases_handler,
+                routes::ns_set_alias_handler,
+                routes::ns_delete_alias_handler,
+                routes::cancel_job_handler,
+                routes::cancel_job_post_handler
             ],
         )
 }
@@ -81,16 +152,26 @@ fn rocket() -> rocket::Rocket<rocket::Build> {
 
 #[cfg(test)]
 mod main_tests {
-    use super::rocket;
     use crate::models::JobStatus;
     use crate::test::fixtures;
-    use crate::types::reqres::JobResponse;
-    use rocket::http::Status;
+    use crate::types::reqres::{JobRecord, JobResponse};
+    use crate::types::Db;
+    use rocket::http::{Header, Status};
     use rocket::local::asynchronous::Client as AsyncClient;
     use rocket::local::blocking::Client;
     use std::{thread, time};
+    /// The server as `main` builds it, configured from `.env.test`
+    fn rocket() -> rocket::Rocket<rocket::Build> {
+        crate::utils::load_environment_variables();
+        super::rocket(crate::utils::init_config().unwrap())
+    }
+    async fn async_key(client: &AsyncClient) -> Header<'static> {
+        let db = client.rocket().state::<Db>().unwrap();
+        fixtures::api_key_header(&*db.lock().await, "test")
+    }
     pub fn wait_for_job_status(
         client: &Client,
+        key: &Header<'static>,
         prover_name: String,
         prover_version: String,
         status: JobStatus,
@@ -100,6 +181,7 @@ mod main_tests {
         while status != curr_status {
             let response = client
                 .get(format!("/v1/prover/{}/{}", prover_name, prover_version))
+                .header(key.clone())
                 .dispatch();
             let job_status: JobResponse =
                 rocket::serde::json::from_str(&response.into_string().unwrap()).unwrap();
@@ -109,6 +191,7 @@ mod main_tests {
     }
     pub async fn async_wait_for_job_status(
         client: &AsyncClient,
+        key: &Header<'static>,
         prover_name: String,
         prover_version: String,
         status: JobStatus,
@@ -119,6 +202,7 @@ mod main_tests {
             thread::sleep(five);
             let response = client
                 .get(format!("/v1/prover/{}/{}", prover_name, prover_version))
+                .header(key.clone())
                 .dispatch()
                 .await;
             let job_status: JobResponse =
@@ -128,24 +212,640 @@ mod main_tests {
         }
         return;
     }
+    async fn async_list_jobs(
+        client: &AsyncClient,
+        uri: String,
+        key: Header<'static>,
+    ) -> Vec<JobRecord> {
+        let response = client.get(uri).header(key).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap()
+    }
+    #[rocket::async_test]
+    async fn int_signals_are_left_to_the_drain() {
+        let rocket = rocket().ignite().await.unwrap();
+        let shutdown = &rocket.config().shutdown;
+        assert!(!shutdown.ctrlc);
+        assert!(shutdown.signals.is_empty());
+    }
     #[rocket::async_test]
     async fn int_add_prover_route() {
         let rocket_instance = rocket();
         let client = AsyncClient::tracked(rocket_instance).await.unwrap();
+        let key = async_key(&client).await;
         let prover = crate::test::fixtures::df_prover_config_request();
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+    #[rocket::async_test]
+    async fn int_add_prover_rejects_path_traversal() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let mut prover = fixtures::df_prover_config_request();
+        prover.name = String::from("../../etc/x");
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let mut prover = fixtures::df_prover_config_request();
+        prover.version = String::from("../..");
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+    #[rocket::async_test]
+    async fn int_add_prover_rejects_mixed_npm_sources() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let mut prover = fixtures::df_prover_config_request();
+        prover.npm = Some(String::from("@darkforest_eth/snarks@6.6.6"));
+        prover.circuit = Some(String::from("move"));
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        prover.path_to_wasm = String::new();
+        prover.path_to_zkey = String::new();
+        prover.path_to_r1cs = String::new();
+        prover.circuit = Some(String::from("../move"));
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+    #[rocket::async_test]
+    async fn int_routes_require_scoped_keys() {
+        use crate::auth::Scope;
+        use crate::models::ApiKey;
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let prover = fixtures::df_prover_config_request();
         let response = client.post("/v1/prover").json(&prover).dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let db = client.rocket().state::<Db>().unwrap();
+        let (_, secret) = ApiKey::mint("prover", None, &[Scope::Prove], &*db.lock().await).unwrap();
+        let response = client
+            .post("/v1/prover")
+            .header(Header::new("Authorization", format!("Bearer {}", secret)))
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        ApiKey::revoke("prover", &*db.lock().await).unwrap();
+        let response = client
+            .post("/v1/prove/move/0.0.1")
+            .header(Header::new("Authorization", format!("Bearer {}", secret)))
+            .json(&fixtures::df_proof_request())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+    #[rocket::async_test]
+    async fn int_metrics_are_exported() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let response = client.get("/metrics").dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .post("/v1/prove/missing/0.0.1")
+            .header(key.clone())
+            .json(&fixtures::df_proof_request())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/metrics").header(key.clone()).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains("proving_server_queue_depth"));
+        assert!(body
+            .contains("proving_server_proofs_total{outcome=\"not_found\",prover=\"unknown\"} 1"));
+    }
+    #[rocket::async_test]
+    async fn int_health_probes_need_no_key() {
+        use crate::types::reqres::ReadinessResponse;
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let response = client.get("/health/live").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // the worker may not have been polled yet
+        let mut attempts = 0;
+        let (status, report) = loop {
+            let response = client.get("/health/ready").dispatch().await;
+            let status = response.status();
+            let report: ReadinessResponse =
+                rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap();
+            attempts += 1;
+            if report.ready || attempts == 50 {
+                break (status, report);
+            }
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+        };
+        assert_eq!(status, Status::Ok);
+        assert!(report.database && report.worker);
+        assert!(report.provers.is_empty());
+    }
+    #[rocket::async_test]
+    async fn int_unfinished_jobs_can_be_cancelled() {
+        use crate::models::{Crud, Job};
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let db = client.rocket().state::<Db>().unwrap();
+        let (id, tenant) = {
+            let conn = db.lock().await;
+            let mut prover = fixtures::df_prover_config();
+            prover.version = String::from("0.0.48");
+            prover.create(&conn).unwrap();
+            let id = Job::queue_for_prover(&prover, String::from("loading"), &conn).unwrap();
+            // interrupted jobs wait for the next boot, so no worker takes this one meanwhile
+            let mut job = Job::get(id, &conn).unwrap();
+            job.status = JobStatus::Interrupted;
+            job.update(&conn).unwrap();
+            let tenant = fixtures::tenant_key_header(&conn, "tenant", "team-a");
+            (id, tenant)
+        };
+
+        let response = client
+            .delete(format!("/v1/job/{}", id))
+            .header(tenant)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client
+            .delete(format!("/v1/job/{}", id))
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let job: JobResponse =
+            rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(job.status, JobStatus::Cancelled);
+
+        let response = client
+            .post(format!("/v1/job/{}/cancel", id))
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Conflict);
+        let response = client
+            .post("/v1/job/4242/cancel")
+            .header(key)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+    #[rocket::async_test]
+    async fn int_jobs_are_listed_with_filters() {
+        use crate::models::{Crud, Job, JobKind};
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let db = client.rocket().state::<Db>().unwrap();
+        let (superseded, latest, tenant) = {
+            let conn = db.lock().await;
+            let mut prover = fixtures::df_prover_config();
+            prover.version = String::from("0.0.49");
+            prover.create(&conn).unwrap();
+            let superseded =
+                Job::queue_for_prover(&prover, String::from("loading"), &conn).unwrap();
+            let latest = Job::queue_for_prover(&prover, String::from("loading"), &conn).unwrap();
+            // interrupted jobs wait for the next boot, so no worker takes this one meanwhile
+            let mut job = Job::get(latest, &conn).unwrap();
+            job.status = JobStatus::Interrupted;
+            job.update(&conn).unwrap();
+            let tenant = fixtures::tenant_key_header(&conn, "tenant", "team-a");
+            (superseded, latest, tenant)
+        };
+        let list = |uri: String| async_list_jobs(&client, uri, key.clone());
+
+        let jobs = list(String::from("/v1/jobs?prover=test&version=0.0.49")).await;
+        assert_eq!(
+            jobs.iter().map(|job| job.id).collect::<Vec<_>>(),
+            vec![latest, superseded]
+        );
+        assert_eq!(jobs[0].kind, JobKind::Load);
+        assert_eq!(jobs[1].status, JobStatus::Cancelled);
+        assert_eq!(jobs[1].message, "superseded by a newer load");
+        assert!(jobs[1].finished_at.is_some());
+
+        let cancelled = list(String::from(
+            "/v1/jobs?version=0.0.49&status=cancelled&kind=load",
+        ))
+        .await;
+        assert_eq!(cancelled.len(), 1);
+        assert_eq!(cancelled[0].id, superseded);
+        let created = jobs[0].created_at;
+        let later = list(format!(
+            "/v1/jobs?version=0.0.49&since={}&limit=1",
+            created + 1
+        ))
+        .await;
+        assert!(later.is_empty());
+
+        let response = client
+            .get("/v1/jobs?status=done")
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client.get("/v1/jobs").header(tenant).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+    #[rocket::async_test]
+    async fn int_proof_priorities_are_checked() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let response = client
+            .post("/v1/prove/move/0.0.1?priority=urgent")
+            .header(key.clone())
+            .json(&fixtures::df_proof_request())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client.get("/v1/proof/unknown").header(key).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+    #[rocket::async_test]
+    async fn int_requests_carry_an_id() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let response = client
+            .get("/")
+            .header(Header::new("X-Request-Id", "trace-42"))
+            .dispatch()
+            .await;
+        assert_eq!(response.headers().get_one("X-Request-Id"), Some("trace-42"));
+
+        // ids that can't go in a log line are replaced
+        let response = client
+            .get("/")
+            .header(Header::new("X-Request-Id", "not a usable id"))
+            .dispatch()
+            .await;
+        let id = response.headers().get_one("X-Request-Id").unwrap();
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+    #[rocket::async_test]
+    async fn int_update_and_delete_prover() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let mut prover = fixtures::df_prover_config_request();
+        let path = format!("/v1/prover/{}/{}", prover.name, prover.version);
+        let response = client
+            .delete(path.clone())
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        prover.builder_params.pop();
+        let response = client
+            .patch(path.clone())
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let db = client.rocket().state::<Db>().unwrap();
+        let stored = crate::models::ProverConfig::get_by_name_and_version(
+            crate::models::DEFAULT_NAMESPACE,
+            prover.name.clone(),
+            prover.version.clone(),
+            &*db.lock().await,
+        )
+        .unwrap();
+        assert_eq!(stored.builder_params, prover.builder_params);
+
+        prover.version = String::from("0.0.2");
+        let response = client
+            .patch(path.clone())
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client
+            .delete(path.clone())
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NoContent);
+        let response = client.get(path).header(key.clone()).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+    #[rocket::async_test]
+    async fn int_proofs_keep_working_during_a_reload() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let mut prover = fixtures::df_prover_config_request();
+        prover.version = String::from("0.0.3");
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        async_wait_for_job_status(
+            &client,
+            &key,
+            prover.name.clone(),
+            prover.version.clone(),
+            JobStatus::Ready,
+        )
+        .await;
+
+        let response = client
+            .patch(format!("/v1/prover/{}/{}", prover.name, prover.version))
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        // parsing the zkey again takes far longer than these requests
+        let response = client
+            .post(format!("/v1/prove/{}/{}", prover.name, prover.version))
+            .header(key.clone())
+            .json(&fixtures::df_proof_request())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(format!("/v1/vkey/{}/{}", prover.name, prover.version))
+            .header(key.clone())
+            .dispatch()
+            .await;
         assert_eq!(response.status(), Status::Ok);
+        async_wait_for_job_status(
+            &client,
+            &key,
+            prover.name.clone(),
+            prover.version.clone(),
+            JobStatus::Ready,
+        )
+        .await;
+    }
+    #[rocket::async_test]
+    async fn int_namespaces_are_isolated() {
+        use crate::types::reqres::ProverConfigRequest;
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let db = client.rocket().state::<Db>().unwrap();
+        let tenant = fixtures::tenant_key_header(&*db.lock().await, "tenant", "team-a");
+        let mut prover = fixtures::df_prover_config_request();
+        prover.name = String::from("team-a-move");
+
+        let response = client
+            .post("/v1/ns/team-a/prover")
+            .header(tenant.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        for (path, status) in [
+            ("/v1/ns/team-b/prover", Status::Forbidden),
+            ("/v1/prover", Status::Forbidden),
+            ("/v1/admin/blobs", Status::Forbidden),
+            ("/metrics", Status::Forbidden),
+            ("/v1/ns/team-a/prover/team-a-move/0.0.1", Status::Ok),
+            ("/v1/prover/team-a-move/0.0.1", Status::Forbidden),
+        ] {
+            let response = client.get(path).header(tenant.clone()).dispatch().await;
+            assert_eq!(response.status(), status, "{}", path);
+        }
+
+        let response = client
+            .get("/v1/ns/team-a/prover")
+            .header(tenant.clone())
+            .dispatch()
+            .await;
+        let listed: Vec<ProverConfigRequest> =
+            rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].namespace.as_deref(), Some("team-a"));
+
+        // the default namespace doesn't see it
+        let response = client
+            .get("/v1/prover/team-a-move/0.0.1")
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        prover.namespace = Some(String::from("team-b"));
+        let response = client
+            .post("/v1/ns/team-a/prover")
+            .header(tenant.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+    #[rocket::async_test]
+    async fn int_private_provers_follow_their_acl() {
+        use crate::acl::{AclEntry, Permission, Visibility};
+        use crate::types::reqres::ProverConfigRequest;
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let owner = async_key(&client).await;
+        let db = client.rocket().state::<Db>().unwrap();
+        let other = fixtures::api_key_header(&*db.lock().await, "other");
+        let mut prover = fixtures::df_prover_config_request();
+        prover.name = String::from("owned-move");
+        prover.visibility = Visibility::Private;
+        let path = format!("/v1/prover/{}/{}", prover.name, prover.version);
+
+        let response = client
+            .post("/v1/prover")
+            .header(owner.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/v1/prover")
+            .header(other.clone())
+            .dispatch()
+            .await;
+        let listed: Vec<ProverConfigRequest> =
+            rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert!(listed.iter().all(|p| p.name != prover.name));
+        let response = client
+            .get(path.clone())
+            .header(other.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client
+            .post(format!("/v1/prove/{}/{}", prover.name, prover.version))
+            .header(other.clone())
+            .json(&fixtures::df_proof_request())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .patch(path.clone())
+            .header(other.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        prover.acl = vec![AclEntry {
+            principal: String::from("key:other"),
+            permissions: vec![Permission::Update],
+        }];
+        let response = client
+            .patch(path.clone())
+            .header(owner.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get(path.clone())
+            .header(other.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .patch(path.clone())
+            .header(other.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        // but only the owner decides who has access
+        prover.visibility = Visibility::Public;
+        let response = client
+            .patch(path.clone())
+            .header(other.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .delete(path.clone())
+            .header(other.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.delete(path).header(owner.clone()).dispatch().await;
+        assert_eq!(response.status(), Status::NoContent);
+    }
+    #[rocket::async_test]
+    async fn int_aliases_point_at_registered_versions() {
+        use crate::models::ProverAlias;
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let mut prover = fixtures::df_prover_config_request();
+        prover.name = String::from("aliased-move");
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let set = |alias: &str, version: &str| {
+            client
+                .put(format!("/v1/alias/aliased-move/{}", alias))
+                .header(key.clone())
+                .json(&crate::types::reqres::AliasRequest {
+                    version: String::from(version),
+                })
+        };
+        assert_eq!(
+            set("latest", "9.9.9").dispatch().await.status(),
+            Status::NotFound
+        );
+        assert_eq!(
+            set("0.0.1", "0.0.1").dispatch().await.status(),
+            Status::BadRequest
+        );
+        let response = set("latest", "0.0.1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        let alias: ProverAlias =
+            rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(alias.version, "0.0.1");
+
+        let response = client
+            .get("/v1/alias/aliased-move")
+            .header(key.clone())
+            .dispatch()
+            .await;
+        let aliases: Vec<ProverAlias> =
+            rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(aliases, vec![alias]);
+
+        // the circuit may still be loading, either way the alias resolves
+        let response = client
+            .get("/v1/vkey/aliased-move/latest")
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_ne!(response.status(), Status::NotFound);
+        let response = client
+            .get("/v1/vkey/aliased-move/stable")
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .delete("/v1/alias/aliased-move/latest")
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::NoContent);
     }
     #[rocket::async_test]
     async fn int_proof_generation() {
         use rocket::local::asynchronous::Client;
         let rocket_instance = rocket();
         let client = Client::tracked(rocket_instance).await.unwrap();
+        let key = async_key(&client).await;
         let prover = crate::test::fixtures::df_prover_config_request();
-        let response = client.post("/v1/prover").json(&prover).dispatch().await;
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
         assert_eq!(response.status(), Status::Ok);
         async_wait_for_job_status(
             &client,
+            &key,
             prover.name.clone(),
             prover.version.clone(),
             JobStatus::Ready,
@@ -154,17 +854,182 @@ mod main_tests {
         let proof_request = fixtures::df_proof_request();
         let response = client
             .post(format!(
-                "/v1/prove/{}/{}",
+                "/v1/prove/{}/{}?priority=interactive",
                 prover.name.clone(),
                 prover.version.clone()
             ))
+            .header(key.clone())
+            .header(Header::new("X-Request-Id", "move-1"))
             .json(&proof_request)
             .dispatch()
             .await;
         assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("X-Prover-Version"),
+            Some(prover.version.as_str())
+        );
+        assert_eq!(response.headers().get_one("X-Proof-Id"), Some("move-1"));
+        let response = client
+            .get("/v1/proof/move-1")
+            .header(key.clone())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let status: crate::types::reqres::ProofStatus =
+            rocket::serde::json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(status.state, crate::scheduler::ProofState::Done);
+        assert_eq!(status.priority, crate::scheduler::Priority::Interactive);
+        assert!(status.finished_at.is_some());
 
-        let response = client.post("/v1/prover").json(&prover).dispatch().await;
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[rocket::async_test]
+    async fn int_failed_proofs_are_counted_but_not_charged() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let prover = fixtures::df_prover_config_request();
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        async_wait_for_job_status(
+            &client,
+            &key,
+            prover.name.clone(),
+            prover.version.clone(),
+            JobStatus::Ready,
+        )
+        .await;
+
+        // a move longer than distMax doesn't satisfy the circuit
+        let mut proof_request = fixtures::df_proof_request();
+        proof_request.insert(String::from("distMax"), 1);
+        let response = client
+            .post(format!("/v1/prove/{}/{}", prover.name, prover.version))
+            .header(key.clone())
+            .json(&proof_request)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::InternalServerError);
+
+        let db = client.rocket().state::<Db>().unwrap();
+        let stored = crate::models::ProverConfig::get_by_name_and_version(
+            crate::models::DEFAULT_NAMESPACE,
+            prover.name.clone(),
+            prover.version.clone(),
+            &*db.lock().await,
+        )
+        .unwrap();
+        let used = crate::models::ProvingUsage::seconds(
+            "key:test",
+            stored.id.unwrap(),
+            crate::models::unix_now() / 86_400,
+            &*db.lock().await,
+        )
+        .unwrap();
+        assert_eq!(used, 0.0);
+        let body = client
+            .get("/metrics")
+            .header(key.clone())
+            .dispatch()
+            .await
+            .into_string()
+            .await
+            .unwrap();
+        assert!(body.contains(&format!(
+            "proving_server_proofs_total{{outcome=\"failed\",prover=\"{}\"}} 1",
+            stored.key()
+        )));
+    }
+
+    #[rocket::async_test]
+    async fn int_proof_inputs_stay_out_of_logs_panics_and_jobs() {
+        let client = AsyncClient::tracked(rocket()).await.unwrap();
+        let key = async_key(&client).await;
+        let prover = fixtures::df_prover_config_request();
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
         assert_eq!(response.status(), Status::Ok);
+        async_wait_for_job_status(
+            &client,
+            &key,
+            prover.name.clone(),
+            prover.version.clone(),
+            JobStatus::Ready,
+        )
+        .await;
+
+        // values that can't turn up by coincidence, in a proof that passes and one that fails
+        let mut proof_request = fixtures::df_proof_request();
+        proof_request.insert(String::from("PLANETHASH_KEY"), 9_999_991);
+        proof_request.insert(String::from("SPACETYPE_KEY"), 8_388_593);
+        let mut failing = proof_request.clone();
+        failing.insert(String::from("distMax"), 1);
+        let path = format!("/v1/prove/{}/{}", prover.name, prover.version);
+        let ((statuses, logs), panics) =
+            fixtures::logs::capture_panics(fixtures::logs::capture_async(async {
+                let mut statuses = vec![];
+                for body in [&proof_request, &failing] {
+                    let response = client
+                        .post(path.clone())
+                        .header(key.clone())
+                        .json(body)
+                        .dispatch()
+                        .await;
+                    statuses.push(response.status());
+                }
+                statuses
+            }))
+            .await;
+        assert_eq!(statuses, [Status::Ok, Status::InternalServerError]);
+        for expected in ["built witness", "generated proof", "proof refused"] {
+            assert!(
+                logs.contains(expected),
+                "{:?} missing from\n{}",
+                expected,
+                logs
+            );
+        }
+
+        let db = client.rocket().state::<Db>().unwrap();
+        let conn = db.lock().await;
+        let mut statement = conn.prepare("SELECT * FROM job").unwrap();
+        let columns = statement.column_count();
+        let jobs = statement
+            .query_map([], |row| {
+                (0..columns)
+                    .map(|i| row.get_ref(i).map(|value| format!("{:?}", value)))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+            .concat()
+            .join(" ");
+        for secret in ["9999991", "8388593"] {
+            assert!(!logs.contains(secret), "{} leaked into\n{}", secret, logs);
+            assert!(
+                !panics.contains(secret),
+                "{} leaked into\n{}",
+                secret,
+                panics
+            );
+            assert!(!jobs.contains(secret), "{} leaked into\n{}", secret, jobs);
+        }
     }
 
     #[test]
@@ -172,13 +1037,22 @@ mod main_tests {
     fn int_bad_proof_generation() {
         let rocket_instance = rocket();
         let client = Client::tracked(rocket_instance).expect("valid rocket instance");
+        let key = fixtures::api_key_header(
+            &client.rocket().state::<Db>().unwrap().blocking_lock(),
+            "test",
+        );
 
         let prover = fixtures::df_prover_config_request();
-        let response = client.post("/v1/prover").json(&prover).dispatch();
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch();
         assert_eq!(response.status(), Status::Ok);
         let proof_request = fixtures::df_proof_request();
         wait_for_job_status(
             &client,
+            &key,
             prover.name.clone(),
             prover.version.clone(),
             JobStatus::Ready,
@@ -189,6 +1063,7 @@ mod main_tests {
                 prover.name.clone(),
                 prover.version.clone()
             ))
+            .header(key.clone())
             .json(&proof_request)
             .dispatch();
         assert_eq!(response.status(), Status::BadRequest);
@@ -197,12 +1072,19 @@ mod main_tests {
     async fn int_missing_proof_arg() {
         let rocket_instance = rocket();
         let client = AsyncClient::tracked(rocket_instance).await.unwrap();
+        let key = async_key(&client).await;
 
         let prover = fixtures::df_prover_config_request();
-        let response = client.post("/v1/prover").json(&prover).dispatch().await;
+        let response = client
+            .post("/v1/prover")
+            .header(key.clone())
+            .json(&prover)
+            .dispatch()
+            .await;
         assert_eq!(response.status(), Status::Ok);
         async_wait_for_job_status(
             &client,
+            &key,
             prover.name.clone(),
             prover.version.clone(),
             JobStatus::Ready,
@@ -217,6 +1099,7 @@ mod main_tests {
                 prover.name.clone(),
                 prover.version.clone()
             ))
+            .header(key.clone())
             .json(&proof_request)
             .dispatch()
             .await;
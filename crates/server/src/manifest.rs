@@ -0,0 +1,381 @@
+/// Declarative list of provers, reconciled against the database when the server boots
+///
+/// A manifest is a TOML or JSON file of prover registrations, the same shape `POST /v1/prover`
+/// takes plus optional artifact checksums. New entries are added, entries that disagree with
+/// what is already registered are reported as drift and left alone, and with pruning enabled
+/// provers that are no longer listed are deleted. Every listed prover gets a load job.
+use crate::errors::ProvingServerError;
+use crate::health::{RequiredProver, RequiredProvers};
+use crate::models::{Crud, Job, ProverConfig, DEFAULT_NAMESPACE};
+use crate::queue::JobQueue;
+use crate::types::reqres::ProverConfigRequest;
+use crate::types::{Config, Db, EnvConfig};
+use crate::utils::files::prover_from_request;
+use rocket::fairing;
+use rocket::serde::Deserialize;
+use rocket::{Build, Rocket};
+use rusqlite::OptionalExtension;
+use std::collections::HashSet;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Manifest {
+    /// `[[prover]]` tables in TOML, a `provers` array in JSON
+    #[serde(default, alias = "prover")]
+    pub provers: Vec<ProverConfigRequest>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReconcileReport {
+    pub added: Vec<String>,
+    /// `name@version: field, field` for every entry that differs from the database
+    pub drifted: Vec<String>,
+    pub pruned: Vec<String>,
+    /// Load jobs to queue, one per manifest entry
+    pub jobs: Vec<i64>,
+}
+
+fn namespace(prover: &ProverConfigRequest) -> &str {
+    prover.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE)
+}
+
+/// Same form as `ProverConfig::label`.
+fn label(prover: &ProverConfigRequest) -> String {
+    match namespace(prover) {
+        DEFAULT_NAMESPACE => format!("{}@{}", prover.name, prover.version),
+        ns => format!("{}/{}@{}", ns, prover.name, prover.version),
+    }
+}
+
+impl Manifest {
+    /// Reads a manifest, as JSON when the file ends in `.json` and TOML otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Manifest, ProvingServerError> {
+        let path = path.as_ref();
+        let invalid = |message: String| ProvingServerError::InvalidManifest {
+            path: path.to_string_lossy().to_string(),
+            message,
+        };
+        let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let manifest: Manifest = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => {
+                rocket::serde::json::from_str(&contents).map_err(|e| invalid(e.to_string()))?
+            }
+            _ => toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?,
+        };
+        let mut seen = HashSet::new();
+        for prover in &manifest.provers {
+            if !seen.insert((namespace(prover), &prover.name, &prover.version)) {
+                return Err(invalid(format!(
+                    "{} is listed more than once",
+                    label(prover)
+                )));
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// The listed provers, which have to be loaded before the server is ready.
+    pub fn required(&self) -> Vec<RequiredProver> {
+        self.provers
+            .iter()
+            .map(|p| RequiredProver {
+                namespace: namespace(p).to_string(),
+                name: p.name.clone(),
+                version: p.version.clone(),
+            })
+            .collect()
+    }
+
+    fn lists(&self, prover: &ProverConfig) -> bool {
+        self.provers.iter().any(|p| {
+            namespace(p) == prover.namespace && p.name == prover.name && p.version == prover.version
+        })
+    }
+}
+
+/// Fields where the registered prover no longer matches its manifest entry.
+pub fn drift(existing: &ProverConfig, wanted: &ProverConfigRequest) -> Vec<&'static str> {
+    let mut fields = vec![];
+    if existing.builder_params != wanted.builder_params {
+        fields.push("builder_params");
+    }
+    match (&existing.npm, &wanted.npm) {
+        (Some(source), Some(spec)) => {
+            if source.spec != *spec || Some(&source.circuit) != wanted.circuit.as_ref() {
+                fields.push("npm");
+            }
+        }
+        (None, None) => {
+            for (field, existing, wanted) in [
+                ("path_to_wasm", &existing.path_to_wasm, &wanted.path_to_wasm),
+                ("path_to_zkey", &existing.path_to_zkey, &wanted.path_to_zkey),
+                ("path_to_r1cs", &existing.path_to_r1cs, &wanted.path_to_r1cs),
+            ] {
+                if existing != wanted {
+                    fields.push(field);
+                }
+            }
+        }
+        _ => fields.push("npm"),
+    }
+    let normalized = ProverConfig::from(wanted.clone());
+    if existing.signers != normalized.signers {
+        fields.push("signers");
+    }
+    if existing.limits != wanted.limits {
+        fields.push("limits");
+    }
+    if normalized.owner.is_some() && existing.owner != normalized.owner {
+        fields.push("owner");
+    }
+    if existing.visibility != wanted.visibility {
+        fields.push("visibility");
+    }
+    if existing.acl != normalized.acl {
+        fields.push("acl");
+    }
+    let checksums = [
+        (
+            "wasm checksum",
+            &wanted.checksums.wasm,
+            &existing.pinned_wasm_hash,
+        ),
+        (
+            "zkey checksum",
+            &wanted.checksums.zkey,
+            &existing.pinned_zkey_hash,
+        ),
+        (
+            "r1cs checksum",
+            &wanted.checksums.r1cs,
+            &existing.pinned_r1cs_hash,
+        ),
+    ];
+    for (field, wanted, existing) in checksums {
+        if wanted.is_some() && wanted != existing {
+            fields.push(field);
+        }
+    }
+    fields
+}
+
+/// Brings the `prover` table in line with `manifest` and prepares a load job for each entry.
+pub async fn reconcile(
+    config: &EnvConfig,
+    db: &Db,
+    manifest: &Manifest,
+    prune: bool,
+) -> Result<ReconcileReport, ProvingServerError> {
+    let mut report = ReconcileReport::default();
+    for wanted in &manifest.provers {
+        let entry = label(wanted);
+        let existing = ProverConfig::get_by_name_and_version(
+            namespace(wanted),
+            wanted.name.clone(),
+            wanted.version.clone(),
+            &*db.lock().await,
+        )
+        .optional()?;
+        let (prover, message) = match existing {
+            Some(existing) => {
+                let fields = drift(&existing, wanted);
+                if fields.is_empty() {
+                    let message = format!("fetching deps for {}", existing.name);
+                    (existing, message)
+                } else {
+                    let fields = fields.join(", ");
+                    report.drifted.push(format!("{}: {}", entry, fields));
+                    let message = format!(
+                        "fetching deps for {}, drifted from manifest: {}",
+                        existing.name, fields
+                    );
+                    (existing, message)
+                }
+            }
+            None => {
+                // resolving npm specs may hit the network, so the connection isn't held here
+                let mut prover = prover_from_request(config, wanted.clone()).await?;
+                prover.create(&*db.lock().await)?;
+                report.added.push(entry);
+                let message = format!("fetching deps for {}", prover.name);
+                (prover, message)
+            }
+        };
+        report
+            .jobs
+            .push(Job::queue_for_prover(&prover, message, &*db.lock().await)?);
+    }
+    if prune {
+        let conn = db.lock().await;
+        for prover in ProverConfig::list(&conn)? {
+            if !manifest.lists(&prover) {
+                prover.delete(&conn)?;
+                report.pruned.push(prover.label());
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Ignite fairing that reconciles the configured manifest and queues its load jobs. A manifest
+/// that can't be read or applied stops the server from launching.
+pub async fn reconcile_at_boot(rocket: Rocket<Build>) -> fairing::Result {
+    let config = match rocket.state::<Config>() {
+        Some(config) => config.lock().await.clone(),
+        None => return Ok(rocket),
+    };
+    let path = match &config.manifest_path {
+        Some(path) => path.clone(),
+        None => return Ok(rocket),
+    };
+    let db = rocket.state::<Db>().unwrap().clone();
+    let queue = rocket.state::<JobQueue>().unwrap().clone();
+    let reconciled = match Manifest::load(&path) {
+        Ok(manifest) => reconcile(&config, &db, &manifest, config.manifest_prune)
+            .await
+            .map(|report| (manifest, report)),
+        Err(e) => Err(e),
+    };
+    match reconciled {
+        Ok((manifest, report)) => {
+            if let Some(required) = rocket.state::<RequiredProvers>() {
+                required.set(manifest.required()).await;
+            }
+            tracing::info!(
+                manifest = %path,
+                added = ?report.added,
+                pruned = ?report.pruned,
+                "reconciled manifest"
+            );
+            for drifted in &report.drifted {
+                tracing::warn!(manifest = %path, %drifted, "prover drifted from the database");
+            }
+            if !report.jobs.is_empty() {
+                queue.wake();
+            }
+            Ok(rocket)
+        }
+        Err(e) => {
+            tracing::error!(manifest = %path, error = %e, "could not apply manifest");
+            Err(rocket)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::JobStatus;
+    use crate::test::fixtures;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    const MANIFEST: &str = r#"
+[[prover]]
+name = "move"
+version = "6.6.6"
+path_to_wasm = "https://unpkg.com/@darkforest_eth/snarks@6.6.6/move.wasm"
+path_to_zkey = "https://unpkg.com/@darkforest_eth/snarks@6.6.6/move.zkey"
+path_to_r1cs = "https://unpkg.com/@darkforest_eth/snarks@6.6.6/move.r1cs"
+builder_params = ["x1", "y1", "x2", "y2", "r", "distMax"]
+
+[prover.checksums]
+wasm = "f57a84b124351d7db13d768de3417076eb5077426f9eeb77f49273d578a276be"
+
+[[prover]]
+name = "reveal"
+version = "6.6.6"
+path_to_wasm = "https://unpkg.com/@darkforest_eth/snarks@6.6.6/reveal.wasm"
+path_to_zkey = "https://unpkg.com/@darkforest_eth/snarks@6.6.6/reveal.zkey"
+path_to_r1cs = "https://unpkg.com/@darkforest_eth/snarks@6.6.6/reveal.r1cs"
+builder_params = ["x", "y"]
+"#;
+
+    fn write(config: &EnvConfig, file: &str, contents: &str) -> String {
+        let path = Path::new(&config.zk_file_path).join(file);
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn unit_load_toml_and_json() {
+        let config = fixtures::local_config("manifest-load");
+        let manifest = Manifest::load(write(&config, "provers.toml", MANIFEST)).unwrap();
+        assert_eq!(manifest.provers.len(), 2);
+        assert_eq!(
+            manifest.provers[0].checksums.wasm.as_deref(),
+            Some("f57a84b124351d7db13d768de3417076eb5077426f9eeb77f49273d578a276be")
+        );
+
+        let json = r#"{"provers": [{"name": "move", "version": "1", "npm": "@darkforest_eth/snarks@6.6.6",
+            "circuit": "move", "builder_params": ["x1"]}]}"#;
+        let manifest = Manifest::load(write(&config, "provers.json", json)).unwrap();
+        assert_eq!(manifest.provers[0].circuit.as_deref(), Some("move"));
+
+        let twice = format!(
+            "{}\n{}",
+            MANIFEST,
+            &MANIFEST[MANIFEST.find("[[prover]]\nname = \"reveal\"").unwrap()..]
+        );
+        match Manifest::load(write(&config, "twice.toml", &twice)) {
+            Err(ProvingServerError::InvalidManifest { message, .. }) => {
+                assert!(message.contains("reveal@6.6.6"))
+            }
+            other => panic!("expected a duplicate entry error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_reconcile_adds_flags_drift_and_prunes() {
+        let config = fixtures::local_config("manifest-reconcile");
+        let db: Db = Arc::new(Mutex::new(fixtures::setup_db().await));
+        let manifest = Manifest::load(write(&config, "provers.toml", MANIFEST)).unwrap();
+
+        let report = reconcile(&config, &db, &manifest, false).await.unwrap();
+        assert_eq!(report.added, vec!["move@6.6.6", "reveal@6.6.6"]);
+        assert!(report.drifted.is_empty());
+        assert_eq!(report.jobs.len(), 2);
+        let pinned = ProverConfig::get_by_name_and_version(
+            DEFAULT_NAMESPACE,
+            String::from("move"),
+            String::from("6.6.6"),
+            &*db.lock().await,
+        )
+        .unwrap();
+        assert_eq!(pinned.pinned_wasm_hash, manifest.provers[0].checksums.wasm);
+        // pins aren't taken for verified hashes, so nothing reuses or keeps blobs by them
+        assert_eq!(pinned.wasm_hash, None);
+
+        // a second boot finds everything in place and loads it under new jobs, superseding
+        // the ones the first boot never finished
+        let again = reconcile(&config, &db, &manifest, false).await.unwrap();
+        assert!(again.added.is_empty() && again.drifted.is_empty());
+        assert_eq!(again.jobs.len(), 2);
+        assert!(again.jobs.iter().all(|id| !report.jobs.contains(id)));
+        let superseded = Job::get(report.jobs[0], &*db.lock().await).unwrap();
+        assert_eq!(superseded.status, JobStatus::Cancelled);
+
+        let mut changed = manifest.clone();
+        changed.provers[1].builder_params.push(String::from("r"));
+        changed.provers[1].path_to_zkey = String::from("https://example.com/reveal.zkey");
+        let drifted = reconcile(&config, &db, &changed, false).await.unwrap();
+        assert_eq!(
+            drifted.drifted,
+            vec!["reveal@6.6.6: builder_params, path_to_zkey"]
+        );
+        let job = Job::get(drifted.jobs[1], &*db.lock().await).unwrap();
+        assert_eq!(job.status, JobStatus::Pending);
+        assert!(job.message.contains("drifted from manifest"));
+
+        let mut unlisted = fixtures::df_prover_config();
+        unlisted.create(&*db.lock().await).unwrap();
+        let mut smaller = manifest.clone();
+        smaller.provers.truncate(1);
+        let pruned = reconcile(&config, &db, &smaller, true).await.unwrap();
+        assert_eq!(pruned.pruned, vec!["reveal@6.6.6", "test@0.0.1"]);
+        let left = ProverConfig::list(&*db.lock().await).unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].name, "move");
+    }
+}
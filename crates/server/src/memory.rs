@@ -0,0 +1,159 @@
+/// Memory budget for loading provers
+///
+/// Instantiating a `CircuitProver` reads its zkey and circuit into memory, so several workers
+/// loading at once can run the server out of it. Before instantiating, a worker reserves the size
+/// of the prover's artifacts, the same rough estimate the metrics use, against
+/// `queue.memory_budget_bytes` together with the provers already loaded, less the one it replaces
+/// on a reload. A load that doesn't fit waits for the loads in progress to finish, and one that
+/// can never fit fails its job.
+use crate::errors::ProvingServerError;
+use crate::types::proof::Provers;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct BudgetState {
+    limit: Option<u64>,
+    reserved: Mutex<u64>,
+    released: Notify,
+}
+
+/// Bytes that loads in progress may take. Cloning shares it.
+#[derive(Clone, Default)]
+pub struct MemoryBudget(Arc<BudgetState>);
+
+/// Holds its bytes of the budget until it is dropped, by which time the loaded prover counts
+/// against the budget on its own.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        *self.budget.0.reserved.lock().unwrap() -= self.bytes;
+        self.budget.0.released.notify_waiters();
+    }
+}
+
+impl MemoryBudget {
+    /// Without a limit every reservation fits.
+    pub fn new(limit: Option<u64>) -> Self {
+        MemoryBudget(Arc::new(BudgetState {
+            limit,
+            ..Default::default()
+        }))
+    }
+
+    /// Reserves `bytes` for a prover about to be instantiated, waiting while other loads take
+    /// the room it needs. The loaded prover under `key`, if any, doesn't count since the new
+    /// one replaces it.
+    pub async fn reserve(
+        &self,
+        bytes: u64,
+        key: &str,
+        provers: &Provers,
+    ) -> Result<Reservation, ProvingServerError> {
+        let limit = match self.0.limit {
+            Some(limit) => limit,
+            None => return Ok(self.reservation(0)),
+        };
+        if bytes > limit {
+            return Err(ProvingServerError::OverMemoryBudget {
+                needed: bytes,
+                available: limit,
+            });
+        }
+        loop {
+            // created before checking, so a release in between isn't missed
+            let released = self.0.released.notified();
+            let loaded: u64 = provers
+                .lock()
+                .await
+                .iter()
+                .filter(|(loaded, _)| loaded.as_str() != key)
+                .map(|(_, prover)| prover.size_bytes)
+                .sum();
+            {
+                let mut reserved = self.0.reserved.lock().unwrap();
+                if loaded.saturating_add(*reserved + bytes) <= limit {
+                    *reserved += bytes;
+                    drop(reserved);
+                    return Ok(self.reservation(bytes));
+                }
+                // nothing in progress will free any room
+                if *reserved == 0 {
+                    return Err(ProvingServerError::OverMemoryBudget {
+                        needed: bytes,
+                        available: limit.saturating_sub(loaded),
+                    });
+                }
+            }
+            tracing::info!(bytes, "waiting for other loads to free memory");
+            released.await;
+        }
+    }
+
+    fn reservation(&self, bytes: u64) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::proof::CircuitProver;
+    use crate::utils::init_provers;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn unit_loads_wait_for_room_in_the_budget() {
+        let provers = init_provers();
+        let budget = MemoryBudget::new(Some(100));
+
+        let first = budget.reserve(60, "a", &provers).await.unwrap();
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(50), budget.reserve(50, "b", &provers));
+        assert!(waiting.await.is_err());
+        let waiting = {
+            let budget = budget.clone();
+            let provers = provers.clone();
+            tokio::spawn(async move { budget.reserve(50, "b", &provers).await.map(|r| r.bytes) })
+        };
+        drop(first);
+        assert_eq!(waiting.await.unwrap().unwrap(), 50);
+
+        assert!(matches!(
+            budget.reserve(101, "c", &provers).await,
+            Err(ProvingServerError::OverMemoryBudget {
+                needed: 101,
+                available: 100
+            })
+        ));
+
+        // loaded provers take their share until they are removed
+        let mut loaded = CircuitProver::new_path(
+            String::from("../../static/6.6.6/move.zkey"),
+            String::from("../../static/6.6.6/move.wasm"),
+            String::from("../../static/6.6.6/move.r1cs"),
+        )
+        .unwrap();
+        loaded.size_bytes = 80;
+        provers.lock().await.insert(String::from("loaded"), loaded);
+        assert!(matches!(
+            budget.reserve(30, "d", &provers).await,
+            Err(ProvingServerError::OverMemoryBudget { available: 20, .. })
+        ));
+        // reloading it frees its share once the new one is in
+        let reload = budget.reserve(90, "loaded", &provers).await.unwrap();
+        assert_eq!(reload.bytes, 90);
+        drop(reload);
+        assert!(MemoryBudget::default()
+            .reserve(u64::MAX, "e", &provers)
+            .await
+            .is_ok());
+    }
+}
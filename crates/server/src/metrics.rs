@@ -0,0 +1,278 @@
+/// Prometheus metrics for proving and prover loading, served at `/metrics`
+///
+/// Collectors live in one `Metrics` value that is managed by rocket and handed to the worker,
+/// and cloning it shares the collectors. Proofs are labelled with the prover they resolved to,
+/// requests that never resolved one with `unknown`, so made up names can't grow the series.
+/// Queue depth and the loaded provers are read when the metrics are scraped.
+use crate::errors::ProvingServerError;
+use crate::models::{Job, JobStatus};
+use crate::types::proof::Provers;
+use crate::types::Db;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::time::Duration;
+
+/// Label for proof requests that never got as far as resolving a prover
+pub const UNKNOWN_PROVER: &str = "unknown";
+
+const SECONDS_BUCKETS: [f64; 12] = [
+    0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+/// Where an artifact was downloaded from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DownloadSource {
+    Http,
+    Ipfs,
+    Npm,
+}
+
+impl DownloadSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadSource::Http => "http",
+            DownloadSource::Ipfs => "ipfs",
+            DownloadSource::Npm => "npm",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Seconds spent in each phase of a proof, `witness` generation or groth16 `proving`
+    pub proof_phase_seconds: HistogramVec,
+    /// Seconds per request to the prove route, whatever the outcome
+    pub proof_seconds: HistogramVec,
+    pub proofs: IntCounterVec,
+    /// Seconds proofs waited for a slot, by priority, see `scheduler`
+    pub proof_wait_seconds: HistogramVec,
+    pub queue_depth: IntGauge,
+    pub loaded_provers: IntGauge,
+    /// Size of the artifacts behind the loaded provers, a rough estimate of their memory
+    pub loaded_prover_bytes: IntGauge,
+    pub load_jobs: IntCounterVec,
+    pub load_seconds: Histogram,
+    pub download_bytes: IntCounterVec,
+    pub download_seconds: HistogramVec,
+}
+
+fn histogram(name: &str, help: &str) -> HistogramOpts {
+    HistogramOpts::new(name, help).buckets(SECONDS_BUCKETS.to_vec())
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let metrics = Metrics {
+            registry: Registry::new(),
+            proof_phase_seconds: HistogramVec::new(
+                histogram(
+                    "proving_server_proof_phase_seconds",
+                    "Seconds per proof phase, witness generation or groth16 proving",
+                ),
+                &["prover", "phase"],
+            )
+            .unwrap(),
+            proof_seconds: HistogramVec::new(
+                histogram(
+                    "proving_server_proof_seconds",
+                    "Seconds per proof request, from routing to response",
+                ),
+                &["prover"],
+            )
+            .unwrap(),
+            proofs: IntCounterVec::new(
+                Opts::new(
+                    "proving_server_proofs_total",
+                    "Proof requests by prover and outcome",
+                ),
+                &["prover", "outcome"],
+            )
+            .unwrap(),
+            proof_wait_seconds: HistogramVec::new(
+                histogram(
+                    "proving_server_proof_wait_seconds",
+                    "Seconds proofs waited for a proving slot by priority",
+                ),
+                &["priority"],
+            )
+            .unwrap(),
+            queue_depth: IntGauge::new(
+                "proving_server_queue_depth",
+                "Load jobs waiting for the worker",
+            )
+            .unwrap(),
+            loaded_provers: IntGauge::new(
+                "proving_server_loaded_provers",
+                "Provers loaded and ready to prove",
+            )
+            .unwrap(),
+            loaded_prover_bytes: IntGauge::new(
+                "proving_server_loaded_prover_bytes",
+                "Artifact bytes behind the loaded provers, an estimate of their memory",
+            )
+            .unwrap(),
+            load_jobs: IntCounterVec::new(
+                Opts::new(
+                    "proving_server_load_jobs_total",
+                    "Finished load jobs by outcome",
+                ),
+                &["outcome"],
+            )
+            .unwrap(),
+            load_seconds: Histogram::with_opts(histogram(
+                "proving_server_load_seconds",
+                "Seconds per load job, downloads included",
+            ))
+            .unwrap(),
+            download_bytes: IntCounterVec::new(
+                Opts::new(
+                    "proving_server_artifact_download_bytes_total",
+                    "Artifact bytes downloaded by source",
+                ),
+                &["source"],
+            )
+            .unwrap(),
+            download_seconds: HistogramVec::new(
+                histogram(
+                    "proving_server_artifact_download_seconds",
+                    "Seconds per artifact download by source",
+                ),
+                &["source"],
+            )
+            .unwrap(),
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(metrics.proof_phase_seconds.clone()),
+            Box::new(metrics.proof_seconds.clone()),
+            Box::new(metrics.proofs.clone()),
+            Box::new(metrics.proof_wait_seconds.clone()),
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.loaded_provers.clone()),
+            Box::new(metrics.loaded_prover_bytes.clone()),
+            Box::new(metrics.load_jobs.clone()),
+            Box::new(metrics.load_seconds.clone()),
+            Box::new(metrics.download_bytes.clone()),
+            Box::new(metrics.download_seconds.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+}
+
+/// How a proof request ended, as the `outcome` label.
+pub fn proof_outcome<T>(result: &Result<T, ProvingServerError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(ProvingServerError::BadProofInputsError { .. }) => "invalid_inputs",
+        Err(ProvingServerError::ProofFailed { .. }) => "failed",
+        Err(ProvingServerError::Unauthorized { .. })
+        | Err(ProvingServerError::Forbidden { .. }) => "denied",
+        Err(ProvingServerError::RateLimited { .. }) => "rate_limited",
+        Err(ProvingServerError::ProverNotFound { .. }) => "not_found",
+        Err(ProvingServerError::ProverNotReady { .. }) => "not_ready",
+        Err(ProvingServerError::ShuttingDown) => "shutting_down",
+        Err(_) => "error",
+    }
+}
+
+impl Metrics {
+    pub fn observe_phase(&self, prover: &str, phase: &str, elapsed: Duration) {
+        self.proof_phase_seconds
+            .with_label_values(&[prover, phase])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_proof<T>(
+        &self,
+        prover: &str,
+        result: &Result<T, ProvingServerError>,
+        elapsed: Duration,
+    ) {
+        self.proof_seconds
+            .with_label_values(&[prover])
+            .observe(elapsed.as_secs_f64());
+        self.proofs
+            .with_label_values(&[prover, proof_outcome(result)])
+            .inc();
+    }
+
+    pub fn observe_proof_wait(&self, priority: &str, elapsed: Duration) {
+        self.proof_wait_seconds
+            .with_label_values(&[priority])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_download(&self, source: DownloadSource, bytes: u64, elapsed: Duration) {
+        self.download_bytes
+            .with_label_values(&[source.as_str()])
+            .inc_by(bytes);
+        self.download_seconds
+            .with_label_values(&[source.as_str()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_load(&self, outcome: &str, elapsed: Duration) {
+        self.load_jobs.with_label_values(&[outcome]).inc();
+        self.load_seconds.observe(elapsed.as_secs_f64());
+    }
+
+    /// Refreshes the gauges and renders everything in the prometheus text format.
+    pub async fn render(&self, db: &Db, provers: &Provers) -> Result<String, ProvingServerError> {
+        let pending = Job::count_with_status(JobStatus::Pending, &*db.lock().await)?;
+        self.queue_depth.set(pending);
+        let provers = provers.lock().await;
+        self.loaded_provers.set(provers.len() as i64);
+        let bytes: u64 = provers.values().map(|prover| prover.size_bytes).sum();
+        self.loaded_prover_bytes.set(bytes as i64);
+        drop(provers);
+
+        let mut buffer = vec![];
+        // only fails on writer errors, and a Vec doesn't have any
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        Ok(String::from_utf8(buffer).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::fixtures;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn unit_render_exports_every_family() {
+        let metrics = Metrics::default();
+        let db: Db = Arc::new(Mutex::new(fixtures::setup_db().await));
+        let provers = crate::utils::init_provers();
+        metrics.observe_phase("default/move@1", "witness", Duration::from_millis(30));
+        metrics.observe_proof::<()>(
+            "default/move@1",
+            &Err(ProvingServerError::BadProofInputsError {
+                message: String::from("x1"),
+            }),
+            Duration::from_millis(40),
+        );
+        metrics.observe_download(DownloadSource::Ipfs, 1024, Duration::from_secs(2));
+        metrics.observe_load("ready", Duration::from_secs(3));
+
+        let rendered = metrics.render(&db, &provers).await.unwrap();
+        for line in [
+            "proving_server_proof_phase_seconds_count{phase=\"witness\",prover=\"default/move@1\"} 1",
+            "proving_server_proofs_total{outcome=\"invalid_inputs\",prover=\"default/move@1\"} 1",
+            "proving_server_artifact_download_bytes_total{source=\"ipfs\"} 1024",
+            "proving_server_load_jobs_total{outcome=\"ready\"} 1",
+            "proving_server_queue_depth 0",
+            "proving_server_loaded_provers 0",
+        ] {
+            assert!(rendered.contains(line), "{} missing from\n{}", line, rendered);
+        }
+    }
+}
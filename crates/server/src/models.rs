@@ -1,9 +1,14 @@
+use crate::acl::{normalize_principal, AclEntry, Permission, Visibility};
+use crate::auth::{generate_secret, hash_secret, Scope};
 use crate::errors::ProvingServerError;
-use crate::types::proof::ProofInputs;
-use crate::types::reqres::ProverConfigRequest;
+use crate::inputs::PrivateInputs;
+use crate::limits::ProverLimits;
+use crate::signature::parse_address;
+use crate::storage::{is_sha256, validate_identifier};
+use crate::types::reqres::{ArtifactChecksums, ProverConfigRequest};
 use rocket::serde::{Deserialize, Serialize};
 use rusqlite::types::{FromSql, FromSqlError, ToSql, ToSqlOutput, ValueRef};
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result};
 pub trait Crud {
     fn create(&mut self, conn: &Connection) -> Result<usize, rusqlite::Error>;
     fn get(id: i64, conn: &Connection) -> Result<Self, rusqlite::Error>
@@ -13,34 +18,214 @@ pub trait Crud {
     fn delete(&self, conn: &Connection) -> Result<usize, rusqlite::Error>;
 }
 
+/// Provers registered without a namespace, and through the un-namespaced `/v1/` routes, live here
+pub const DEFAULT_NAMESPACE: &str = "default";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProverConfig {
     pub id: Option<i64>,
+    pub namespace: String,
     pub name: String,
     pub version: String,
     pub path_to_wasm: String,
     pub path_to_zkey: String,
     pub path_to_r1cs: String,
     pub builder_params: Vec<String>,
+    /// sha256 of each artifact once it has been downloaded into the blob store
+    pub wasm_hash: Option<String>,
+    pub zkey_hash: Option<String>,
+    pub r1cs_hash: Option<String>,
+    /// sha256 each download must match, from the registration's `checksums`
+    pub pinned_wasm_hash: Option<String>,
+    pub pinned_zkey_hash: Option<String>,
+    pub pinned_r1cs_hash: Option<String>,
+    /// Set when the artifacts come out of an npm package rather than individual urls
+    pub npm: Option<NpmSource>,
+    /// Lowercase addresses allowed to send signed proof requests
+    pub signers: Vec<String>,
+    pub limits: ProverLimits,
+    /// `key:<name>` or a lowercase address, `None` for provers registered before owners existed
+    pub owner: Option<String>,
+    pub visibility: Visibility,
+    pub acl: Vec<AclEntry>,
+}
+
+/// The npm package a prover's artifacts were extracted from, as resolved at registration.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct NpmSource {
+    /// What was asked for, e.g. `@darkforest_eth/snarks@latest`
+    pub spec: String,
+    pub package: String,
+    /// The exact version `spec` resolved to
+    pub version: String,
+    pub circuit: String,
+    pub tarball: String,
+    /// Subresource integrity string published by the registry, e.g. `sha512-...`
+    pub integrity: String,
+}
+
+impl NpmSource {
+    /// The synthetic artifact source recorded in `path_to_*`, e.g.
+    /// `npm:@darkforest_eth/snarks@6.6.6/move.wasm`
+    pub fn artifact_source(&self, extension: &str) -> String {
+        format!(
+            "npm:{}@{}/{}.{}",
+            self.package, self.version, self.circuit, extension
+        )
+    }
+
+    pub fn get_for_prover(
+        prover: i64,
+        conn: &Connection,
+    ) -> Result<Option<NpmSource>, rusqlite::Error> {
+        let mut stmt = conn.prepare(
+            "SELECT spec, package, version, circuit, tarball, integrity FROM npm_source where prover = ?1",
+        )?;
+        let mut sources = stmt.query_map(params![prover], |row| {
+            Ok(NpmSource {
+                spec: row.get(0)?,
+                package: row.get(1)?,
+                version: row.get(2)?,
+                circuit: row.get(3)?,
+                tarball: row.get(4)?,
+                integrity: row.get(5)?,
+            })
+        })?;
+        sources.next().transpose()
+    }
+
+    fn create(&self, prover: i64, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.execute(
+            "insert into npm_source (prover, spec, package, version, circuit, tarball, integrity) values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                prover,
+                self.spec,
+                self.package,
+                self.version,
+                self.circuit,
+                self.tarball,
+                self.integrity
+            ],
+        )
+    }
 }
 
+const PROVER_COLUMNS: &str =
+    "id, name, version, path_to_wasm, path_to_zkey, path_to_r1cs, wasm_hash, zkey_hash, r1cs_hash, namespace, owner, visibility, pinned_wasm_hash, pinned_zkey_hash, pinned_r1cs_hash";
+
 impl From<ProverConfigRequest> for ProverConfig {
     fn from(r: ProverConfigRequest) -> ProverConfig {
         ProverConfig {
             id: None,
+            namespace: r
+                .namespace
+                .unwrap_or_else(|| String::from(DEFAULT_NAMESPACE)),
             name: r.name,
             version: r.version,
             path_to_wasm: r.path_to_wasm,
             path_to_zkey: r.path_to_zkey,
             path_to_r1cs: r.path_to_r1cs,
             builder_params: r.builder_params,
+            wasm_hash: None,
+            zkey_hash: None,
+            r1cs_hash: None,
+            pinned_wasm_hash: r.checksums.wasm,
+            pinned_zkey_hash: r.checksums.zkey,
+            pinned_r1cs_hash: r.checksums.r1cs,
+            npm: None,
+            // anything unparsable is kept as given for `validate` to reject
+            signers: r
+                .signers
+                .iter()
+                .map(|signer| parse_address(signer).unwrap_or_else(|_| signer.clone()))
+                .collect(),
+            limits: r.limits,
+            owner: r
+                .owner
+                .map(|owner| normalize_principal(&owner).unwrap_or(owner)),
+            visibility: r.visibility,
+            acl: r
+                .acl
+                .into_iter()
+                .map(|entry| AclEntry {
+                    principal: normalize_principal(&entry.principal).unwrap_or(entry.principal),
+                    permissions: entry.permissions,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<ProverConfig> for ProverConfigRequest {
+    fn from(p: ProverConfig) -> ProverConfigRequest {
+        let (npm, circuit) = match p.npm {
+            Some(source) => (Some(source.spec), Some(source.circuit)),
+            None => (None, None),
+        };
+        let uses_paths = npm.is_none();
+        let path = |path: String| if uses_paths { path } else { String::new() };
+        ProverConfigRequest {
+            namespace: Some(p.namespace),
+            name: p.name,
+            version: p.version,
+            path_to_wasm: path(p.path_to_wasm),
+            path_to_zkey: path(p.path_to_zkey),
+            path_to_r1cs: path(p.path_to_r1cs),
+            builder_params: p.builder_params,
+            npm,
+            circuit,
+            checksums: ArtifactChecksums {
+                wasm: p.pinned_wasm_hash,
+                zkey: p.pinned_zkey_hash,
+                r1cs: p.pinned_r1cs_hash,
+            },
+            signers: p.signers,
+            limits: p.limits,
+            owner: p.owner,
+            visibility: p.visibility,
+            acl: p.acl,
         }
     }
 }
 impl ProverConfig {
-    pub fn validate_inputs(&self, inputs: &ProofInputs) -> Result<bool, ProvingServerError> {
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        validate_identifier("namespace", &self.namespace)?;
+        validate_identifier("name", &self.name)?;
+        validate_identifier("version", &self.version)?;
+        for (artifact, hash) in self.pinned() {
+            match hash {
+                Some(hash) if !is_sha256(hash) => {
+                    return Err(ProvingServerError::InvalidProverConfig {
+                        message: format!("{} checksum must be a lowercase hex sha256", artifact),
+                    })
+                }
+                _ => {}
+            }
+        }
+        for signer in &self.signers {
+            parse_address(signer)?;
+        }
+        self.limits.validate()?;
+        for principal in self
+            .owner
+            .iter()
+            .chain(self.acl.iter().map(|entry| &entry.principal))
+        {
+            normalize_principal(principal)?;
+        }
+        Ok(())
+    }
+    /// Takes the artifacts from an npm package, recording synthetic `npm:` paths for them.
+    pub fn set_npm_source(&mut self, source: NpmSource) {
+        self.path_to_wasm = source.artifact_source("wasm");
+        self.path_to_zkey = source.artifact_source("zkey");
+        self.path_to_r1cs = source.artifact_source("r1cs");
+        self.npm = Some(source);
+    }
+    pub fn validate_inputs(&self, inputs: &PrivateInputs) -> Result<bool, ProvingServerError> {
         for param in &self.builder_params {
-            if !inputs.contains_key(&param.clone()) {
+            if !inputs.contains(param) {
                 return Err(ProvingServerError::BadProofInputsError {
                     message: param.clone(),
                 });
@@ -59,78 +244,285 @@ impl ProverConfig {
         Ok(res)
     }
 
+    pub fn get_signers(id: i64, conn: &Connection) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt =
+            conn.prepare("SELECT address FROM prover_signer where prover = ?1 order by id")?;
+        let signers: Result<Vec<String>, rusqlite::Error> =
+            stmt.query_map(params![id], |row| row.get(0))?.collect();
+        signers
+    }
+
+    pub fn get_limits(id: i64, conn: &Connection) -> Result<ProverLimits, rusqlite::Error> {
+        conn.query_row(
+            "SELECT requests_per_minute, burst, proving_seconds_per_day FROM prover_limit where prover = ?1",
+            params![id],
+            |row| {
+                Ok(ProverLimits {
+                    requests_per_minute: row.get(0)?,
+                    burst: row.get(1)?,
+                    proving_seconds_per_day: row.get(2)?,
+                })
+            },
+        )
+        .optional()
+        .map(Option::unwrap_or_default)
+    }
+
+    pub fn get_acl(id: i64, conn: &Connection) -> Result<Vec<AclEntry>, rusqlite::Error> {
+        let mut stmt = conn.prepare(
+            "SELECT principal, permission FROM prover_acl where prover = ?1 order by id",
+        )?;
+        let rows = stmt.query_map(params![id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Permission>(1)?))
+        })?;
+        let mut acl: Vec<AclEntry> = vec![];
+        for row in rows {
+            let (principal, permission) = row?;
+            match acl.iter_mut().find(|entry| entry.principal == principal) {
+                Some(entry) => entry.permissions.push(permission),
+                None => acl.push(AclEntry {
+                    principal,
+                    permissions: vec![permission],
+                }),
+            }
+        }
+        Ok(acl)
+    }
+
+    fn from_row(row: &rusqlite::Row, conn: &Connection) -> Result<ProverConfig, rusqlite::Error> {
+        let id: i64 = row.get(0)?;
+        Ok(ProverConfig {
+            id: Some(id),
+            name: row.get(1)?,
+            version: row.get(2)?,
+            path_to_wasm: row.get(3)?,
+            path_to_zkey: row.get(4)?,
+            path_to_r1cs: row.get(5)?,
+            builder_params: ProverConfig::get_builder_params(id, conn)?,
+            wasm_hash: row.get(6)?,
+            zkey_hash: row.get(7)?,
+            r1cs_hash: row.get(8)?,
+            pinned_wasm_hash: row.get(12)?,
+            pinned_zkey_hash: row.get(13)?,
+            pinned_r1cs_hash: row.get(14)?,
+            npm: NpmSource::get_for_prover(id, conn)?,
+            signers: ProverConfig::get_signers(id, conn)?,
+            limits: ProverConfig::get_limits(id, conn)?,
+            namespace: row.get(9)?,
+            owner: row.get(10)?,
+            visibility: row.get(11)?,
+            acl: ProverConfig::get_acl(id, conn)?,
+        })
+    }
+
     pub fn get_by_name_and_version(
+        namespace: &str,
         name: String,
         version: String,
         conn: &Connection,
     ) -> Result<ProverConfig, rusqlite::Error> {
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM prover where namespace = ?1 and name = ?2 and version = ?3",
+                PROVER_COLUMNS
+            ),
+            params![namespace, name, version],
+            |row| ProverConfig::from_row(row, conn),
+        )
+    }
+
+    pub fn list_in_namespace(
+        namespace: &str,
+        conn: &Connection,
+    ) -> Result<Vec<ProverConfig>, rusqlite::Error> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM prover where namespace = ?1 order by id",
+            PROVER_COLUMNS
+        ))?;
+        let provers: Result<Vec<ProverConfig>, rusqlite::Error> = stmt
+            .query_map(params![namespace], |row| ProverConfig::from_row(row, conn))?
+            .collect();
+        provers
+    }
+
+    /// Every namespace some prover is registered in.
+    pub fn namespaces(conn: &Connection) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt = conn.prepare("SELECT distinct namespace FROM prover order by namespace")?;
+        let namespaces: Result<Vec<String>, rusqlite::Error> =
+            stmt.query_map([], |row| row.get(0))?.collect();
+        namespaces
+    }
+
+    /// Identifies the prover across namespaces, e.g. in the map of loaded circuits.
+    pub fn key(&self) -> String {
+        format!("{}/{}@{}", self.namespace, self.name, self.version)
+    }
+
+    /// `name@version`, prefixed with the namespace outside the default one.
+    pub fn label(&self) -> String {
+        if self.namespace == DEFAULT_NAMESPACE {
+            format!("{}@{}", self.name, self.version)
+        } else {
+            self.key()
+        }
+    }
+
+    pub fn list(conn: &Connection) -> Result<Vec<ProverConfig>, rusqlite::Error> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM prover order by id",
+            PROVER_COLUMNS
+        ))?;
+        let provers: Result<Vec<ProverConfig>, rusqlite::Error> = stmt
+            .query_map([], |row| ProverConfig::from_row(row, conn))?
+            .collect();
+        provers
+    }
+
+    pub fn hashes(&self) -> [(&'static str, Option<&String>); 3] {
+        [
+            ("wasm", self.wasm_hash.as_ref()),
+            ("zkey", self.zkey_hash.as_ref()),
+            ("r1cs", self.r1cs_hash.as_ref()),
+        ]
+    }
+
+    /// The checksums each artifact was registered with, if any.
+    pub fn pinned(&self) -> [(&'static str, Option<&String>); 3] {
+        [
+            ("wasm", self.pinned_wasm_hash.as_ref()),
+            ("zkey", self.pinned_zkey_hash.as_ref()),
+            ("r1cs", self.pinned_r1cs_hash.as_ref()),
+        ]
+    }
+
+    pub fn set_hashes(
+        &mut self,
+        wasm_hash: String,
+        zkey_hash: String,
+        r1cs_hash: String,
+        conn: &Connection,
+    ) -> Result<usize, rusqlite::Error> {
+        self.wasm_hash = Some(wasm_hash);
+        self.zkey_hash = Some(zkey_hash);
+        self.r1cs_hash = Some(r1cs_hash);
+        conn.execute(
+            "update prover set wasm_hash = ?2, zkey_hash = ?3, r1cs_hash = ?4 where id = ?1",
+            params![self.id, self.wasm_hash, self.zkey_hash, self.r1cs_hash],
+        )
+    }
+
+    /// Finds the blob some other prover in `namespace` already downloaded from `url`, so shared
+    /// artifacts are only fetched once. Urls are assumed to be immutable, which holds for
+    /// versioned packages. Namespaces have their own blob stores, so they don't share.
+    pub fn find_hash_for_url(
+        namespace: &str,
+        url: &str,
+        conn: &Connection,
+    ) -> Result<Option<String>, rusqlite::Error> {
         let mut stmt = conn.prepare(
-            "SELECT id, name, version, path_to_wasm, path_to_zkey,path_to_r1cs FROM Prover where name = ?1 and version =?2"
+            "SELECT wasm_hash FROM prover where namespace = ?2 and path_to_wasm = ?1 and wasm_hash is not null
+            union all SELECT zkey_hash FROM prover where namespace = ?2 and path_to_zkey = ?1 and zkey_hash is not null
+            union all SELECT r1cs_hash FROM prover where namespace = ?2 and path_to_r1cs = ?1 and r1cs_hash is not null",
         )?;
+        let mut hashes = stmt.query_map(params![url, namespace], |row| row.get::<_, String>(0))?;
+        hashes.next().transpose()
+    }
+}
 
-        let mut prover_iter = stmt.query_map(params![name, version], |row| {
-            let id: i64 = row.get(0).unwrap();
-            let b_params = ProverConfig::get_builder_params(id, conn).unwrap();
-            Ok(ProverConfig {
-                id: Some(id),
-                name: row.get(1)?,
-                version: row.get(2)?,
-                path_to_wasm: row.get(3)?,
-                path_to_zkey: row.get(4)?,
-                path_to_r1cs: row.get(5)?,
-                builder_params: b_params,
-            })
-        })?;
-        // Gross
-        Ok(prover_iter.next().unwrap().unwrap())
+impl ProverConfig {
+    fn create_children(&self, conn: &Connection) -> Result<(), rusqlite::Error> {
+        let prover_id = self.id.unwrap();
+        if let Some(npm) = &self.npm {
+            npm.create(prover_id, conn)?;
+        }
+        for param in &self.builder_params {
+            conn.execute(
+                "insert into builder_params (name, prover) values (?1, ?2)",
+                params![param, prover_id],
+            )?;
+        }
+        for signer in &self.signers {
+            conn.execute(
+                "insert into prover_signer (address, prover) values (?1, ?2)",
+                params![signer, prover_id],
+            )?;
+        }
+        for entry in &self.acl {
+            for permission in &entry.permissions {
+                conn.execute(
+                    "insert into prover_acl (prover, principal, permission) values (?1, ?2, ?3)",
+                    params![prover_id, entry.principal, permission],
+                )?;
+            }
+        }
+        if !self.limits.is_empty() {
+            conn.execute(
+                "insert into prover_limit (prover, requests_per_minute, burst, proving_seconds_per_day) values (?1, ?2, ?3, ?4)",
+                params![
+                    prover_id,
+                    self.limits.requests_per_minute,
+                    self.limits.burst,
+                    self.limits.proving_seconds_per_day
+                ],
+            )?;
+        }
+        Ok(())
+    }
+    fn delete_children(&self, conn: &Connection) -> Result<(), rusqlite::Error> {
+        conn.execute(
+            "delete from builder_params where prover = ?1",
+            params![self.id],
+        )?;
+        conn.execute("delete from npm_source where prover = ?1", params![self.id])?;
+        conn.execute(
+            "delete from prover_signer where prover = ?1",
+            params![self.id],
+        )?;
+        conn.execute(
+            "delete from prover_limit where prover = ?1",
+            params![self.id],
+        )?;
+        conn.execute("delete from prover_acl where prover = ?1", params![self.id])?;
+        Ok(())
     }
 }
 
 impl Crud for ProverConfig {
     fn create(&mut self, conn: &Connection) -> Result<usize, rusqlite::Error> {
         let initial =  conn.execute(
-            "insert into Prover (name, version, path_to_wasm, path_to_zkey, path_to_r1cs) values (?1,?2,?3, ?4, ?5) ",
-            params![self.name,self.version, self.path_to_wasm, self.path_to_zkey, self.path_to_r1cs],
+            "insert into Prover (name, version, path_to_wasm, path_to_zkey, path_to_r1cs, wasm_hash, zkey_hash, r1cs_hash, namespace, owner, visibility, pinned_wasm_hash, pinned_zkey_hash, pinned_r1cs_hash) values (?1,?2,?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14) ",
+            params![self.name,self.version, self.path_to_wasm, self.path_to_zkey, self.path_to_r1cs, self.wasm_hash, self.zkey_hash, self.r1cs_hash, self.namespace, self.owner, self.visibility, self.pinned_wasm_hash, self.pinned_zkey_hash, self.pinned_r1cs_hash],
         );
         let prover_id = conn.last_insert_rowid();
         self.id = Some(prover_id);
-        for param in &self.builder_params {
-            match conn.execute(
-                "insert into builder_params (name, prover) values (?1, ?2)",
-                params![param, prover_id],
-            ) {
-                Ok(_) => (),
-                Err(e) => return Err(e),
-            }
-        }
+        self.create_children(conn)?;
         initial
     }
     fn get(id: i64, conn: &Connection) -> Result<ProverConfig, rusqlite::Error> {
-        let mut stmt = conn.prepare(
-            "SELECT id, name, version, path_to_wasm, path_to_zkey,path_to_r1cs FROM Prover where id = ?1"
-        )?;
-
-        let mut prover_iter = stmt.query_map(params![id], |row| {
-            let b_params: Vec<String> = ProverConfig::get_builder_params(id, conn).unwrap();
-
-            Ok(ProverConfig {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                version: row.get(2)?,
-                path_to_wasm: row.get(3)?,
-                path_to_zkey: row.get(4)?,
-                path_to_r1cs: row.get(5)?,
-                builder_params: b_params,
-            })
-        })?;
-        // Gross
-        Ok(prover_iter.next().unwrap().unwrap())
+        conn.query_row(
+            &format!("SELECT {} FROM prover where id = ?1", PROVER_COLUMNS),
+            params![id],
+            |row| ProverConfig::from_row(row, conn),
+        )
     }
-    fn update(&mut self, _conn: &Connection) -> Result<usize, rusqlite::Error> {
-        todo!()
+    /// Replaces everything but the name, version and namespace.
+    fn update(&mut self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        let updated = conn.execute(
+            "update prover set path_to_wasm = ?2, path_to_zkey = ?3, path_to_r1cs = ?4, wasm_hash = ?5, zkey_hash = ?6, r1cs_hash = ?7, owner = ?8, visibility = ?9, pinned_wasm_hash = ?10, pinned_zkey_hash = ?11, pinned_r1cs_hash = ?12 where id = ?1",
+            params![self.id, self.path_to_wasm, self.path_to_zkey, self.path_to_r1cs, self.wasm_hash, self.zkey_hash, self.r1cs_hash, self.owner, self.visibility, self.pinned_wasm_hash, self.pinned_zkey_hash, self.pinned_r1cs_hash],
+        )?;
+        self.delete_children(conn)?;
+        self.create_children(conn)?;
+        Ok(updated)
     }
-    fn delete(&self, _conn: &Connection) -> Result<usize, rusqlite::Error> {
-        todo!()
+    fn delete(&self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        self.delete_children(conn)?;
+        conn.execute("delete from job where prover = ?1", params![self.id])?;
+        conn.execute(
+            "delete from prover_alias where namespace = ?1 and name = ?2 and version = ?3",
+            params![self.namespace, self.name, self.version],
+        )?;
+        conn.execute("delete from prover where id = ?1", params![self.id])
     }
 }
 
@@ -140,6 +532,27 @@ pub enum JobStatus {
     Queued = 1,
     Processing = 2,
     Ready = 3,
+    Failed = 4,
+    /// Stopped by a shutdown, queued again on the next boot
+    Interrupted = 5,
+    /// Stopped on request, see `Job::cancel`
+    Cancelled = 6,
+}
+
+impl JobStatus {
+    /// Reads a status by name, in any case.
+    pub fn parse(status: &str) -> Option<JobStatus> {
+        match status.to_ascii_lowercase().as_str() {
+            "pending" => Some(JobStatus::Pending),
+            "queued" => Some(JobStatus::Queued),
+            "processing" => Some(JobStatus::Processing),
+            "ready" => Some(JobStatus::Ready),
+            "failed" => Some(JobStatus::Failed),
+            "interrupted" => Some(JobStatus::Interrupted),
+            "cancelled" => Some(JobStatus::Cancelled),
+            _ => None,
+        }
+    }
 }
 
 impl FromSql for JobStatus {
@@ -150,6 +563,9 @@ impl FromSql for JobStatus {
             x if x == JobStatus::Queued as i64 => Ok(JobStatus::Queued),
             x if x == JobStatus::Processing as i64 => Ok(JobStatus::Processing),
             x if x == JobStatus::Ready as i64 => Ok(JobStatus::Ready),
+            x if x == JobStatus::Failed as i64 => Ok(JobStatus::Failed),
+            x if x == JobStatus::Interrupted as i64 => Ok(JobStatus::Interrupted),
+            x if x == JobStatus::Cancelled as i64 => Ok(JobStatus::Cancelled),
             _ => Err(FromSqlError::OutOfRange(inter)),
         }
     }
@@ -159,79 +575,676 @@ impl ToSql for JobStatus {
         Ok(ToSqlOutput::from(*self as i64))
     }
 }
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    /// Fetches a prover's artifacts and instantiates its circuit
+    Load,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Load => "load",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<JobKind> {
+        match kind {
+            "load" => Some(JobKind::Load),
+            _ => None,
+        }
+    }
+}
+
+impl FromSql for JobKind {
+    fn column_result(value: ValueRef<'_>) -> Result<Self, FromSqlError> {
+        JobKind::parse(value.as_str()?).ok_or(FromSqlError::InvalidType)
+    }
+}
+impl ToSql for JobKind {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
 #[derive(Debug, Serialize, Clone, PartialEq)]
 pub struct Job {
     pub id: Option<i64>,
+    pub kind: JobKind,
     pub status: JobStatus,
     pub message: String,
     pub prover: i64,
+    /// Seconds since the unix epoch, like the other timestamps
+    pub created_at: i64,
+    /// When the job last joined the queue, which workers take jobs from oldest first
+    pub queued_at: i64,
+    /// When a worker last started on the job
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    /// The worker holding the job, see `Job::claim`
+    pub lease_owner: Option<String>,
+    /// When the lease runs out and another worker may take the job
+    pub leased_until: Option<i64>,
+    /// Claims since the job was last queued
+    pub attempts: i64,
+}
+
+const JOB_COLUMNS: &str = "job.id, job.kind, job.status, job.message, job.prover, job.created_at, job.queued_at, job.started_at, job.finished_at, job.lease_owner, job.leased_until, job.attempts";
+
+fn job_from_row(row: &rusqlite::Row) -> Result<Job, rusqlite::Error> {
+    Ok(Job {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        status: row.get(2)?,
+        message: row.get(3)?,
+        prover: row.get(4)?,
+        created_at: row.get(5)?,
+        queued_at: row.get(6)?,
+        started_at: row.get(7)?,
+        finished_at: row.get(8)?,
+        lease_owner: row.get(9)?,
+        leased_until: row.get(10)?,
+        attempts: row.get(11)?,
+    })
+}
+
+/// Which jobs `Job::list` returns, newest first.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct JobQuery {
+    pub provers: Vec<i64>,
+    pub status: Option<JobStatus>,
+    pub kind: Option<JobKind>,
+    /// Only jobs created at or after this time
+    pub since: Option<i64>,
+    /// Only jobs created before this time
+    pub until: Option<i64>,
+    pub limit: usize,
 }
 
 impl Job {
-    pub fn get_by_name_and_version(
-        prover_name: String,
-        prover_version: String,
+    /// Queues a new load job for the prover, returning its id. Each load keeps a job of its
+    /// own, so earlier ones stay around as history, and those that hadn't finished are
+    /// superseded by the new one.
+    pub fn queue_for_prover(
+        prover: &ProverConfig,
+        message: String,
         conn: &Connection,
-    ) -> Result<Job, rusqlite::Error> {
-        let mut stmt = conn.prepare("SELECT job.id, job.status, job.message, job.prover FROM job join prover on prover.id = job.prover where prover.name = ?1 and prover.version = ?2")?;
-        let jobs: Vec<Job> = stmt
-            .query_map(params![prover_name, prover_version], |row| {
-                Ok(Job {
-                    id: row.get(0)?,
-                    status: row.get(1)?,
-                    message: row.get(2)?,
-                    prover: row.get(3)?,
-                })
-            })
-            .unwrap()
-            .map(|r| r.unwrap())
-            .collect();
-        match jobs.get(0) {
-            None => Err(rusqlite::Error::InvalidQuery),
-            Some(f) => Ok(f.clone()),
+    ) -> Result<i64, rusqlite::Error> {
+        let now = unix_now();
+        conn.execute(
+            "UPDATE job set status = ?2, message = ?3, finished_at = ?4, lease_owner = NULL, leased_until = NULL
+             where prover = ?1 and status in (?5, ?6, ?7, ?8)",
+            params![
+                prover.id,
+                JobStatus::Cancelled,
+                "superseded by a newer load",
+                now,
+                JobStatus::Pending,
+                JobStatus::Queued,
+                JobStatus::Processing,
+                JobStatus::Interrupted
+            ],
+        )?;
+        let mut job = Job {
+            id: None,
+            kind: JobKind::Load,
+            status: JobStatus::Pending,
+            prover: prover.id.unwrap(),
+            message,
+            created_at: now,
+            queued_at: now,
+            started_at: None,
+            finished_at: None,
+            lease_owner: None,
+            leased_until: None,
+            attempts: 0,
+        };
+        job.create(conn)?;
+        Ok(job.id.unwrap())
+    }
+
+    /// Jobs of the given provers that match the query, newest first.
+    pub fn list(query: &JobQuery, conn: &Connection) -> Result<Vec<Job>, rusqlite::Error> {
+        if query.provers.is_empty() {
+            return Ok(vec![]);
+        }
+        let provers: Vec<String> = query.provers.iter().map(|id| id.to_string()).collect();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM job where prover in ({})
+             and (?1 is null or status = ?1) and (?2 is null or kind = ?2)
+             and (?3 is null or created_at >= ?3) and (?4 is null or created_at < ?4)
+             order by id desc limit ?5",
+            JOB_COLUMNS,
+            provers.join(",")
+        ))?;
+        let jobs = stmt.query_map(
+            params![
+                query.status,
+                query.kind,
+                query.since,
+                query.until,
+                query.limit as i64
+            ],
+            job_from_row,
+        )?;
+        jobs.collect()
+    }
+
+    /// Seconds the job waited in the queue before its last start.
+    pub fn wait_secs(&self) -> Option<i64> {
+        self.started_at.map(|started| started - self.queued_at)
+    }
+
+    /// Seconds the last attempt ran for.
+    pub fn run_secs(&self) -> Option<i64> {
+        match (self.started_at, self.finished_at) {
+            (Some(started), Some(finished)) => Some(finished - started),
+            _ => None,
+        }
+    }
+
+    /// Takes the oldest pending job, or one whose worker let its lease run out, and leases it
+    /// to `owner` for `lease_secs`. Jobs that were already claimed `max_attempts` times without
+    /// finishing fail instead, so a circuit that kills its worker can't take every worker down.
+    pub fn claim(
+        owner: &str,
+        lease_secs: u64,
+        max_attempts: u32,
+        conn: &Connection,
+    ) -> Result<Option<Job>, rusqlite::Error> {
+        let now = unix_now();
+        conn.execute(
+            "UPDATE job set status = ?1, message = ?2, finished_at = ?4, lease_owner = NULL, leased_until = NULL
+             where status = ?3 and ifnull(leased_until, 0) < ?4 and attempts >= ?5",
+            params![
+                JobStatus::Failed,
+                format!("gave up after {} attempts", max_attempts),
+                JobStatus::Processing,
+                now,
+                max_attempts
+            ],
+        )?;
+        loop {
+            let next: Option<i64> = conn
+                .query_row(
+                    "SELECT id FROM job where kind = ?4
+                     and (status = ?1 or (status = ?2 and ifnull(leased_until, 0) < ?3))
+                     order by queued_at, id limit 1",
+                    params![
+                        JobStatus::Pending,
+                        JobStatus::Processing,
+                        now,
+                        JobKind::Load
+                    ],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let id = match next {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            // another worker sharing the database may claim it between the select and here
+            let claimed = conn.execute(
+                "UPDATE job set status = ?2, lease_owner = ?3, leased_until = ?4, started_at = ?5, attempts = attempts + 1
+                 where id = ?1 and (status = ?6 or (status = ?2 and ifnull(leased_until, 0) < ?5))",
+                params![
+                    id,
+                    JobStatus::Processing,
+                    owner,
+                    now + lease_secs as i64,
+                    now,
+                    JobStatus::Pending
+                ],
+            )?;
+            if claimed == 1 {
+                return Job::get(id, conn).map(Some);
+            }
         }
     }
+
+    /// Extends the lease of a job `owner` is still working on. Returns false once the lease
+    /// was lost to another worker or the job was reset.
+    pub fn renew_lease(
+        id: i64,
+        owner: &str,
+        lease_secs: u64,
+        conn: &Connection,
+    ) -> Result<bool, rusqlite::Error> {
+        let renewed = conn.execute(
+            "UPDATE job set leased_until = ?3 where id = ?1 and status = ?4 and lease_owner = ?2",
+            params![
+                id,
+                owner,
+                unix_now() + lease_secs as i64,
+                JobStatus::Processing
+            ],
+        )?;
+        Ok(renewed == 1)
+    }
+
+    /// Records how the job ended and gives up its lease. Returns false when the job had
+    /// already stopped processing, because it was cancelled or reset in the meantime.
+    pub fn finish(
+        &mut self,
+        status: JobStatus,
+        conn: &Connection,
+    ) -> Result<bool, rusqlite::Error> {
+        self.status = status;
+        self.finished_at = Some(unix_now());
+        self.lease_owner = None;
+        self.leased_until = None;
+        let finished = conn.execute(
+            "UPDATE job set status = ?2, message = ?3, finished_at = ?4, lease_owner = NULL, leased_until = NULL
+             where id = ?1 and status = ?5",
+            params![
+                self.id,
+                self.status,
+                self.message,
+                self.finished_at,
+                JobStatus::Processing
+            ],
+        )?;
+        Ok(finished == 1)
+    }
+
+    /// Cancels a job that hasn't finished yet. Returns false when it already had.
+    pub fn cancel(id: i64, conn: &Connection) -> Result<bool, rusqlite::Error> {
+        let cancelled = conn.execute(
+            "UPDATE job set status = ?2, message = ?3, finished_at = ?8, lease_owner = NULL, leased_until = NULL
+             where id = ?1 and status in (?4, ?5, ?6, ?7)",
+            params![
+                id,
+                JobStatus::Cancelled,
+                "cancelled",
+                JobStatus::Pending,
+                JobStatus::Queued,
+                JobStatus::Processing,
+                JobStatus::Interrupted,
+                unix_now()
+            ],
+        )?;
+        Ok(cancelled == 1)
+    }
+
+    pub fn count_with_status(status: JobStatus, conn: &Connection) -> Result<i64, rusqlite::Error> {
+        conn.query_row(
+            "SELECT count(*) FROM job where status = ?1",
+            params![status],
+            |row| row.get(0),
+        )
+    }
+
+    /// Marks every job that is waiting or running as interrupted, returning how many were.
+    pub fn interrupt_unfinished(conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.execute(
+            "UPDATE job set status = ?1, message = ?2, lease_owner = NULL, leased_until = NULL
+             where status in (?3, ?4, ?5)",
+            params![
+                JobStatus::Interrupted,
+                "interrupted by a shutdown",
+                JobStatus::Pending,
+                JobStatus::Queued,
+                JobStatus::Processing
+            ],
+        )
+    }
+
+    /// Sets interrupted jobs back to pending, keeping their place in the queue, and returns
+    /// their ids.
+    pub fn resume_interrupted(conn: &Connection) -> Result<Vec<i64>, rusqlite::Error> {
+        let mut stmt = conn.prepare("SELECT id FROM job where status = ?1 order by id")?;
+        let ids: Result<Vec<i64>, rusqlite::Error> = stmt
+            .query_map(params![JobStatus::Interrupted], |row| row.get(0))?
+            .collect();
+        let ids = ids?;
+        conn.execute(
+            "UPDATE job set status = ?1, message = ?2, attempts = 0 where status = ?3",
+            params![
+                JobStatus::Pending,
+                "resuming after a shutdown",
+                JobStatus::Interrupted
+            ],
+        )?;
+        Ok(ids)
+    }
+
+    /// The prover's latest load job.
+    pub fn get_for_prover(prover: i64, conn: &Connection) -> Result<Option<Job>, rusqlite::Error> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM job where prover = ?1 order by id desc limit 1",
+            JOB_COLUMNS
+        ))?;
+        let mut jobs = stmt.query_map(params![prover], job_from_row)?;
+        jobs.next().transpose()
+    }
 }
 
 impl Crud for Job {
     fn create(&mut self, conn: &Connection) -> Result<usize, rusqlite::Error> {
         let init = conn.execute(
-            "insert into job (status, message, prover) values (?1,?2,?3) ",
-            params![self.status, self.message, self.prover],
+            "insert into job (kind, status, message, prover, created_at, queued_at, started_at, finished_at, lease_owner, leased_until, attempts) values (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11) ",
+            params![self.kind, self.status, self.message, self.prover, self.created_at, self.queued_at, self.started_at, self.finished_at, self.lease_owner, self.leased_until, self.attempts],
         );
         let prover_id = conn.last_insert_rowid();
         self.id = Some(prover_id);
         init
     }
     fn get(id: i64, conn: &Connection) -> Result<Job, rusqlite::Error> {
-        let mut stmt = conn.prepare("SELECT id, status, message, prover FROM job where id = ?1")?;
-        let jobs: Vec<Job> = stmt
-            .query_map(params![id], |row| {
-                Ok(Job {
-                    id: row.get(0)?,
-                    status: row.get(1)?,
-                    message: row.get(2)?,
-                    prover: row.get(3)?,
-                })
-            })
-            .unwrap()
-            .map(|r| r.unwrap())
-            .collect();
-        // also gross
-        Ok(jobs.get(0).unwrap().clone())
+        conn.query_row(
+            &format!("SELECT {} FROM job where id = ?1", JOB_COLUMNS),
+            params![id],
+            job_from_row,
+        )
     }
     fn update(&mut self, conn: &Connection) -> Result<usize, rusqlite::Error> {
         let init = conn.execute(
-            "update job set status = ?2, message = ?3 where id = ?1",
-            params![self.id, self.status, self.message],
+            "update job set status = ?2, message = ?3, queued_at = ?4, started_at = ?5, finished_at = ?6, lease_owner = ?7, leased_until = ?8, attempts = ?9 where id = ?1",
+            params![self.id, self.status, self.message, self.queued_at, self.started_at, self.finished_at, self.lease_owner, self.leased_until, self.attempts],
         );
-        let prover_id = conn.last_insert_rowid();
-        self.id = Some(prover_id);
         init
     }
-    fn delete(&self, _conn: &Connection) -> Result<usize, rusqlite::Error> {
-        todo!()
+    fn delete(&self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.execute("delete from job where id = ?1", params![self.id])
+    }
+}
+
+/// A minted api key. Only the sha256 of the secret is kept.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: Option<i64>,
+    pub name: String,
+    #[serde(skip)]
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    /// The tenant owning the key, which may only act within that namespace. Keys without one
+    /// work in every namespace and on the admin routes.
+    pub namespace: Option<String>,
+    /// Seconds since the unix epoch
+    pub created_at: i64,
+    pub revoked: bool,
+}
+
+const API_KEY_COLUMNS: &str = "id, name, hash, scopes, created_at, revoked_at, namespace";
+
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl ApiKey {
+    /// Stores a new key and returns it along with its secret, which is never seen again.
+    pub fn mint(
+        name: &str,
+        namespace: Option<&str>,
+        scopes: &[Scope],
+        conn: &Connection,
+    ) -> Result<(ApiKey, String), rusqlite::Error> {
+        let secret = generate_secret();
+        let mut key = ApiKey {
+            id: None,
+            name: name.to_string(),
+            hash: hash_secret(&secret),
+            scopes: scopes.to_vec(),
+            namespace: namespace.map(str::to_string),
+            created_at: unix_now(),
+            revoked: false,
+        };
+        key.create(conn)?;
+        Ok((key, secret))
+    }
+
+    pub fn find_by_secret(
+        secret: &str,
+        conn: &Connection,
+    ) -> Result<Option<ApiKey>, rusqlite::Error> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_key where hash = ?1 and revoked_at is null",
+            API_KEY_COLUMNS
+        ))?;
+        let mut keys = stmt.query_map(params![hash_secret(secret)], ApiKey::from_row)?;
+        keys.next().transpose()
+    }
+
+    pub fn list(conn: &Connection) -> Result<Vec<ApiKey>, rusqlite::Error> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM api_key order by id",
+            API_KEY_COLUMNS
+        ))?;
+        let keys: Result<Vec<ApiKey>, rusqlite::Error> =
+            stmt.query_map([], ApiKey::from_row)?.collect();
+        keys
+    }
+
+    /// Revokes the live key called `name`, returning how many keys were revoked.
+    pub fn revoke(name: &str, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.execute(
+            "update api_key set revoked_at = ?2 where name = ?1 and revoked_at is null",
+            params![name, unix_now()],
+        )
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<ApiKey, rusqlite::Error> {
+        let scopes: String = row.get(3)?;
+        let revoked_at: Option<i64> = row.get(5)?;
+        Ok(ApiKey {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            hash: row.get(2)?,
+            // unknown scopes from a newer version are dropped rather than granted
+            scopes: scopes.split(' ').filter_map(|s| s.parse().ok()).collect(),
+            namespace: row.get(6)?,
+            created_at: row.get(4)?,
+            revoked: revoked_at.is_some(),
+        })
+    }
+}
+
+impl Crud for ApiKey {
+    fn create(&mut self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        let scopes: Vec<&str> = self.scopes.iter().map(|s| s.as_str()).collect();
+        let created = conn.execute(
+            "insert into api_key (name, hash, scopes, created_at, namespace) values (?1, ?2, ?3, ?4, ?5)",
+            params![
+                self.name,
+                self.hash,
+                scopes.join(" "),
+                self.created_at,
+                self.namespace
+            ],
+        )?;
+        self.id = Some(conn.last_insert_rowid());
+        Ok(created)
+    }
+    fn get(id: i64, conn: &Connection) -> Result<ApiKey, rusqlite::Error> {
+        conn.query_row(
+            &format!("SELECT {} FROM api_key where id = ?1", API_KEY_COLUMNS),
+            params![id],
+            ApiKey::from_row,
+        )
     }
+    /// Replaces the name, scopes and namespace, and revokes or restores the key. The secret
+    /// can't be changed, mint a new key instead.
+    fn update(&mut self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        let scopes: Vec<&str> = self.scopes.iter().map(|s| s.as_str()).collect();
+        // a key that stays revoked keeps the time it was first revoked at
+        conn.execute(
+            "update api_key set name = ?2, scopes = ?3, namespace = ?4,
+            revoked_at = case when ?5 then coalesce(revoked_at, ?6) else null end
+            where id = ?1",
+            params![
+                self.id,
+                self.name,
+                scopes.join(" "),
+                self.namespace,
+                self.revoked,
+                unix_now()
+            ],
+        )
+    }
+    fn delete(&self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.execute("delete from api_key where id = ?1", params![self.id])
+    }
+}
+
+/// A nonce seen on a signed proof request, kept until the request would have expired anyway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignatureNonce {
+    pub signer: String,
+    pub nonce: String,
+    pub expires_at: i64,
+}
+
+impl SignatureNonce {
+    /// Records the nonce, returning false if `signer` already used it. Nonces that expired
+    /// before `now` are forgotten first, their requests are refused on expiry alone.
+    pub fn claim(&self, now: i64, conn: &Connection) -> Result<bool, rusqlite::Error> {
+        conn.execute(
+            "delete from signature_nonce where expires_at < ?1",
+            params![now],
+        )?;
+        let claimed = conn.execute(
+            "insert or ignore into signature_nonce (signer, nonce, expires_at) values (?1, ?2, ?3)",
+            params![self.signer, self.nonce, self.expires_at],
+        )?;
+        Ok(claimed == 1)
+    }
+}
+
+/// Seconds a client spent proving with one prover on one UTC day.
+pub struct ProvingUsage;
+
+impl ProvingUsage {
+    pub fn seconds(
+        client: &str,
+        prover: i64,
+        day: i64,
+        conn: &Connection,
+    ) -> Result<f64, rusqlite::Error> {
+        conn.query_row(
+            "SELECT seconds FROM proving_usage where client = ?1 and prover = ?2 and day = ?3",
+            params![client, prover, day],
+            |row| row.get(0),
+        )
+        .optional()
+        .map(|seconds| seconds.unwrap_or(0.0))
+    }
+
+    /// Adds to the day's total and returns the new total.
+    pub fn add(
+        client: &str,
+        prover: i64,
+        day: i64,
+        seconds: f64,
+        conn: &Connection,
+    ) -> Result<f64, rusqlite::Error> {
+        conn.execute(
+            "insert into proving_usage (client, prover, day, seconds) values (?1, ?2, ?3, ?4)
+            on conflict(client, prover, day) do update set seconds = seconds + excluded.seconds",
+            params![client, prover, day, seconds],
+        )?;
+        ProvingUsage::seconds(client, prover, day, conn)
+    }
+}
+
+/// A movable name for one version of a prover, e.g. `latest` or `season-5`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ProverAlias {
+    pub namespace: String,
+    pub name: String,
+    pub alias: String,
+    pub version: String,
+    pub updated_at: i64,
+}
+
+impl ProverAlias {
+    /// Points the alias at `version` in a single statement, so no request sees it half moved.
+    pub fn set(&self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.execute(
+            "insert into prover_alias (namespace, name, alias, version, updated_at) values (?1, ?2, ?3, ?4, ?5)
+            on conflict(namespace, name, alias) do update set version = excluded.version, updated_at = excluded.updated_at",
+            params![self.namespace, self.name, self.alias, self.version, self.updated_at],
+        )
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<ProverAlias, rusqlite::Error> {
+        Ok(ProverAlias {
+            namespace: row.get(0)?,
+            name: row.get(1)?,
+            alias: row.get(2)?,
+            version: row.get(3)?,
+            updated_at: row.get(4)?,
+        })
+    }
+
+    pub fn get(
+        namespace: &str,
+        name: &str,
+        alias: &str,
+        conn: &Connection,
+    ) -> Result<Option<ProverAlias>, rusqlite::Error> {
+        conn.query_row(
+            "SELECT namespace, name, alias, version, updated_at FROM prover_alias where namespace = ?1 and name = ?2 and alias = ?3",
+            params![namespace, name, alias],
+            ProverAlias::from_row,
+        )
+        .optional()
+    }
+
+    pub fn list(
+        namespace: &str,
+        name: &str,
+        conn: &Connection,
+    ) -> Result<Vec<ProverAlias>, rusqlite::Error> {
+        let mut stmt = conn.prepare(
+            "SELECT namespace, name, alias, version, updated_at FROM prover_alias where namespace = ?1 and name = ?2 order by alias",
+        )?;
+        let aliases: Result<Vec<ProverAlias>, rusqlite::Error> = stmt
+            .query_map(params![namespace, name], ProverAlias::from_row)?
+            .collect();
+        aliases
+    }
+
+    pub fn delete(&self, conn: &Connection) -> Result<usize, rusqlite::Error> {
+        conn.execute(
+            "delete from prover_alias where namespace = ?1 and name = ?2 and alias = ?3",
+            params![self.namespace, self.name, self.alias],
+        )
+    }
+}
+
+#[tokio::test]
+async fn unit_aliases_move_and_go_with_their_version() {
+    use crate::test::fixtures;
+    let conn = fixtures::setup_db().await;
+    let mut prover = fixtures::df_prover_config();
+    prover.create(&conn).unwrap();
+    let mut alias = ProverAlias {
+        namespace: prover.namespace.clone(),
+        name: prover.name.clone(),
+        alias: String::from("latest"),
+        version: String::from("0.0.0"),
+        updated_at: 1,
+    };
+    alias.set(&conn).unwrap();
+    alias.version = prover.version.clone();
+    alias.updated_at = 2;
+    alias.set(&conn).unwrap();
+    let found = ProverAlias::get(&prover.namespace, &prover.name, "latest", &conn).unwrap();
+    assert_eq!(found, Some(alias.clone()));
+    assert_eq!(
+        ProverAlias::list(&prover.namespace, &prover.name, &conn).unwrap(),
+        vec![alias]
+    );
+
+    prover.delete(&conn).unwrap();
+    assert_eq!(
+        ProverAlias::get(&prover.namespace, &prover.name, "latest", &conn).unwrap(),
+        None
+    );
 }
 
 #[tokio::test]
@@ -246,9 +1259,19 @@ async fn unit_create_job() {
         status: JobStatus::Pending,
         message: String::from("test initiatization"),
         prover: prover.id.unwrap(),
+        kind: JobKind::Load,
+        created_at: unix_now(),
+        queued_at: unix_now(),
+        started_at: None,
+        finished_at: None,
+        lease_owner: None,
+        leased_until: None,
+        attempts: 0,
     };
     job.create(&conn).unwrap();
     job.create(&conn).unwrap();
     let j2 = Job::get(job.id.unwrap(), &conn).unwrap();
     assert_eq!(j2, job);
+    assert_eq!(job.delete(&conn).unwrap(), 1);
+    assert!(Job::get(job.id.unwrap(), &conn).is_err());
 }
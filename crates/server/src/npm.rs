@@ -0,0 +1,434 @@
+/// Provers registered from an npm package, e.g. `@darkforest_eth/snarks@6.6.6`
+///
+/// The spec is resolved against the configured registry when the prover is registered, so
+/// the exact version and the registry's integrity string are recorded up front. The load job
+/// downloads the tarball, checks it against that integrity string and extracts
+/// `<circuit>.wasm`, `<circuit>.zkey` and `<circuit>.r1cs` into the blob store.
+use crate::errors::ProvingServerError;
+use crate::fetch::fetch_to_writer;
+use crate::logging::millis;
+use crate::metrics::{DownloadSource, Metrics};
+use crate::models::NpmSource;
+use crate::storage::{ArtifactStore, HashingWriter, StorageBackend, TempFile};
+use crate::types::EnvConfig;
+use flate2::read::GzDecoder;
+use rocket::serde::Deserialize;
+use sha2::Sha512;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::copy;
+use std::path::{Component, Path};
+use std::time::Instant;
+
+pub const ARTIFACT_EXTENSIONS: [&str; 3] = ["wasm", "zkey", "r1cs"];
+
+fn invalid_spec(spec: &str, reason: &str) -> ProvingServerError {
+    ProvingServerError::InvalidProverConfig {
+        message: format!("{} is not a supported npm spec: {}", spec, reason),
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || "-._~".contains(c)
+}
+
+/// `name`, `name@version` or `name@tag`, optionally scoped. Ranges are not supported, the
+/// server has to know exactly which artifacts it is proving with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackageSpec {
+    pub package: String,
+    /// An exact version or a dist-tag, `latest` when the spec has neither
+    pub reference: String,
+}
+
+impl PackageSpec {
+    pub fn parse(spec: &str) -> Result<PackageSpec, ProvingServerError> {
+        // the scope's leading `@` is not a version separator
+        let split = spec
+            .char_indices()
+            .skip(1)
+            .find(|(_, c)| *c == '@')
+            .map(|(i, _)| i);
+        let (package, reference) = match split {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => (spec, "latest"),
+        };
+        let name = match package.strip_prefix('@') {
+            Some(scoped) => match scoped.split_once('/') {
+                Some((scope, name)) if !scope.is_empty() && scope.chars().all(is_name_char) => name,
+                _ => return Err(invalid_spec(spec, "malformed scope")),
+            },
+            None => package,
+        };
+        if name.is_empty()
+            || name.len() > 214
+            || name.starts_with('.')
+            || !name.chars().all(is_name_char)
+        {
+            return Err(invalid_spec(spec, "malformed package name"));
+        }
+        if reference.is_empty()
+            || !reference
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.+_".contains(c))
+        {
+            return Err(invalid_spec(
+                spec,
+                "expected an exact version or a dist-tag",
+            ));
+        }
+        Ok(PackageSpec {
+            package: package.to_string(),
+            reference: reference.to_string(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Packument {
+    #[serde(rename = "dist-tags", default)]
+    dist_tags: HashMap<String, String>,
+    #[serde(default)]
+    versions: HashMap<String, PackageVersion>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PackageVersion {
+    dist: Dist,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Dist {
+    tarball: String,
+    integrity: Option<String>,
+}
+
+/// Looks `spec` up in the registry and pins it to an exact version and tarball.
+pub async fn resolve(
+    config: &EnvConfig,
+    spec: &str,
+    circuit: &str,
+) -> Result<NpmSource, ProvingServerError> {
+    let parsed = PackageSpec::parse(spec)?;
+    let url = format!(
+        "{}/{}",
+        config.npm_registry.trim_end_matches('/'),
+        parsed.package.replace('/', "%2f")
+    );
+    let mut body = vec![];
+    fetch_to_writer(&config.fetch_policy, &url, &mut body).await?;
+    let packument: Packument = String::from_utf8(body)
+        .ok()
+        .and_then(|body| rocket::serde::json::from_str(&body).ok())
+        .ok_or_else(|| invalid_spec(spec, "the registry returned a malformed packument"))?;
+    let version = match packument.dist_tags.get(&parsed.reference) {
+        Some(tagged) => tagged.clone(),
+        None => parsed.reference.clone(),
+    };
+    let dist = match packument.versions.get(&version) {
+        Some(release) => &release.dist,
+        None => return Err(invalid_spec(spec, "no such version")),
+    };
+    let integrity = match &dist.integrity {
+        Some(integrity) if integrity.contains("sha512-") => integrity.clone(),
+        _ => {
+            return Err(invalid_spec(
+                spec,
+                "the registry has no sha512 integrity for it",
+            ))
+        }
+    };
+    Ok(NpmSource {
+        spec: spec.to_string(),
+        package: parsed.package,
+        version,
+        circuit: circuit.to_string(),
+        tarball: dist.tarball.clone(),
+        integrity,
+    })
+}
+
+/// Checks a sha512 digest against a subresource integrity string such as `sha512-<base64>`.
+pub fn verify_integrity(integrity: &str, sha512_hex: &str) -> Result<(), ProvingServerError> {
+    let actual = format!(
+        "sha512-{}",
+        base64::encode(hex::decode(sha512_hex).unwrap_or_default())
+    );
+    if integrity
+        .split_whitespace()
+        .any(|expected| expected == actual)
+    {
+        Ok(())
+    } else {
+        Err(ProvingServerError::ChecksumMismatch {
+            expected: integrity.to_string(),
+            actual,
+        })
+    }
+}
+
+/// Downloads and verifies the package tarball, then stores the circuit's artifacts.
+/// Returns the blob hashes in `ARTIFACT_EXTENSIONS` order.
+pub async fn fetch_artifacts(
+    config: &EnvConfig,
+    backend: &dyn StorageBackend,
+    source: &NpmSource,
+    metrics: &Metrics,
+) -> Result<Vec<String>, ProvingServerError> {
+    let (tarball, file) = backend.scratch().temp_file()?;
+    tracing::debug!(
+        tarball = %source.tarball,
+        temp = ?tarball.as_os_str(),
+        "downloading package"
+    );
+    let mut dest = HashingWriter::<_, Sha512>::with_digest(file);
+    let started = Instant::now();
+    let extracted = match fetch_to_writer(&config.fetch_policy, &source.tarball, &mut dest).await {
+        Ok(bytes) => {
+            let elapsed = started.elapsed();
+            metrics.observe_download(DownloadSource::Npm, bytes, elapsed);
+            tracing::info!(
+                tarball = %source.tarball,
+                bytes,
+                elapsed_ms = millis(elapsed),
+                "downloaded package"
+            );
+            let (file, sha512) = dest.finish();
+            drop(file);
+            match verify_integrity(&source.integrity, &sha512) {
+                Ok(()) => {
+                    let store = backend.scratch().clone();
+                    let path = tarball.to_path_buf();
+                    let source = source.clone();
+                    let max_bytes = config.fetch_policy.max_bytes;
+                    // decompressing a package takes a while, like loading a prover
+                    tokio::task::spawn_blocking(move || extract(&store, &path, &source, max_bytes))
+                        .await
+                        .map_err(|e| ProvingServerError::Storage {
+                            message: format!("could not unpack the package: {}", e),
+                        })?
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    drop(tarball);
+    let mut hashes = vec![];
+    for (temp, hash) in extracted? {
+        backend.put(&hash, &temp).await?;
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
+/// Unpacks the circuit's artifacts into scratch files, returning each path and its sha256.
+fn extract(
+    store: &ArtifactStore,
+    tarball: &Path,
+    source: &NpmSource,
+    max_bytes: u64,
+) -> Result<Vec<(TempFile, String)>, ProvingServerError> {
+    let wanted: Vec<String> = ARTIFACT_EXTENSIONS
+        .iter()
+        .map(|extension| format!("{}.{}", source.circuit, extension))
+        .collect();
+    let mut found: Vec<Option<(TempFile, String)>> = vec![None, None, None];
+    // what was unpacked before an error is removed along with `found`
+    unpack(store, tarball, &wanted, max_bytes, &mut found)?;
+    if let Some(missing) = found.iter().position(Option::is_none) {
+        return Err(ProvingServerError::InvalidProverConfig {
+            message: format!(
+                "{}@{} does not contain {}",
+                source.package, source.version, wanted[missing]
+            ),
+        });
+    }
+    Ok(found.into_iter().flatten().collect())
+}
+
+fn unpack(
+    store: &ArtifactStore,
+    tarball: &Path,
+    wanted: &[String],
+    max_bytes: u64,
+    found: &mut [Option<(TempFile, String)>],
+) -> Result<(), ProvingServerError> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(tarball)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // npm tarballs wrap the package in a single top level directory, usually `package/`
+        let path = entry.path()?.into_owned();
+        let mut components = path.components().skip(1);
+        let name = match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => name.to_string_lossy().to_string(),
+            _ => continue,
+        };
+        let slot = match wanted.iter().position(|w| *w == name) {
+            Some(slot) if found[slot].is_none() && entry.header().entry_type().is_file() => slot,
+            _ => continue,
+        };
+        // the gzip stream is verified, but its contents can still be far bigger than it
+        if entry.header().size()? > max_bytes {
+            return Err(ProvingServerError::ArtifactTooLarge { limit: max_bytes });
+        }
+        let (temp, file) = store.temp_file()?;
+        let mut dest = HashingWriter::new(file);
+        copy(&mut entry, &mut dest)?;
+        let (_, hash) = dest.finish();
+        found[slot] = Some((temp, hash));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::fixtures;
+    use crate::test::fixtures::http::{serve, TestResponse};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use sha2::{Digest, Sha256};
+
+    fn tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *contents).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn integrity(bytes: &[u8]) -> String {
+        format!("sha512-{}", base64::encode(Sha512::digest(bytes)))
+    }
+
+    /// A registry stand-in serving one package with a single `6.6.6` release tagged latest.
+    async fn registry(package: Vec<u8>, integrity: String) -> String {
+        let base = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+        let tarball_base = base.clone();
+        let url = serve(move |req| match req.path.as_str() {
+            "/@darkforest_eth%2fsnarks" => TestResponse::ok(format!(
+                r#"{{"name":"@darkforest_eth/snarks","dist-tags":{{"latest":"6.6.6"}},
+                "versions":{{"6.6.6":{{"dist":{{"tarball":"{}/snarks-6.6.6.tgz","integrity":"{}"}}}}}}}}"#,
+                tarball_base.lock().unwrap(),
+                integrity
+            )),
+            "/snarks-6.6.6.tgz" => TestResponse::ok(package.clone()),
+            _ => TestResponse::status(404),
+        })
+        .await;
+        *base.lock().unwrap() = url.clone();
+        url
+    }
+
+    #[test]
+    fn unit_parse_specs() {
+        let scoped = PackageSpec::parse("@darkforest_eth/snarks@6.6.6").unwrap();
+        assert_eq!(scoped.package, "@darkforest_eth/snarks");
+        assert_eq!(scoped.reference, "6.6.6");
+        let tagged = PackageSpec::parse("snarks").unwrap();
+        assert_eq!(
+            (tagged.package.as_str(), tagged.reference.as_str()),
+            ("snarks", "latest")
+        );
+        for bad in [
+            "",
+            "@",
+            "@darkforest_eth",
+            "@/snarks",
+            "Snarks",
+            "snarks@^6.0.0",
+            "snarks@>=6 <7",
+            "snarks@",
+            "../snarks",
+        ] {
+            assert!(
+                PackageSpec::parse(bad).is_err(),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unit_resolve_and_extract_circuit() {
+        let package = tarball(&[
+            ("package/package.json", b"{}"),
+            ("package/move.wasm", b"move.wasm"),
+            ("package/move.zkey", b"move.zkey"),
+            ("package/move.r1cs", b"move.r1cs"),
+            ("package/init.wasm", b"init.wasm"),
+        ]);
+        let mut config = fixtures::local_config("npm-extract");
+        config.npm_registry = registry(package.clone(), integrity(&package)).await;
+        let store = config.artifact_store();
+
+        let source = resolve(&config, "@darkforest_eth/snarks", "move")
+            .await
+            .unwrap();
+        assert_eq!(source.version, "6.6.6");
+        assert_eq!(source.integrity, integrity(&package));
+        assert_eq!(
+            source.artifact_source("zkey"),
+            "npm:@darkforest_eth/snarks@6.6.6/move.zkey"
+        );
+
+        let hashes = fetch_artifacts(&config, &store, &source, &Metrics::default())
+            .await
+            .unwrap();
+        for (hash, extension) in hashes.iter().zip(ARTIFACT_EXTENSIONS) {
+            let expected = hex::encode(Sha256::digest(format!("move.{}", extension).as_bytes()));
+            assert_eq!(*hash, expected);
+            assert!(store.has_blob(hash));
+        }
+
+        let missing = resolve(&config, "@darkforest_eth/snarks@6.6.5", "move").await;
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn unit_tampered_tarball_is_rejected() {
+        let package = tarball(&[
+            ("package/move.wasm", b"move.wasm"),
+            ("package/move.zkey", b"move.zkey"),
+            ("package/move.r1cs", b"move.r1cs"),
+        ]);
+        let published = integrity(b"what the registry was told");
+        let mut config = fixtures::local_config("npm-tampered");
+        config.npm_registry = registry(package, published).await;
+        let store = config.artifact_store();
+
+        let source = resolve(&config, "@darkforest_eth/snarks@6.6.6", "move")
+            .await
+            .unwrap();
+        match fetch_artifacts(&config, &store, &source, &Metrics::default()).await {
+            Err(ProvingServerError::ChecksumMismatch { .. }) => {}
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+        assert!(store.list_blobs().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unit_missing_circuit_is_reported() {
+        let package = tarball(&[("package/move.wasm", b"move.wasm")]);
+        let mut config = fixtures::local_config("npm-missing");
+        config.npm_registry = registry(package.clone(), integrity(&package)).await;
+        let store = config.artifact_store();
+
+        let source = resolve(&config, "@darkforest_eth/snarks", "move")
+            .await
+            .unwrap();
+        let error = fetch_artifacts(&config, &store, &source, &Metrics::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("move.zkey"));
+        assert!(store.list_blobs().unwrap().is_empty());
+    }
+}
@@ -4,38 +4,82 @@ use ark_circom::{CircomCircuit, CircomReduction};
 use ark_groth16::{create_random_proof_with_reduction, ProvingKey};
 use ark_std::rand::thread_rng;
 use num_bigint::ToBigInt;
-use std::collections::HashMap;
+use std::time::Instant;
+use zeroize::Zeroize;
 
+use crate::errors::ProvingServerError;
+use crate::inputs::PrivateInputs;
+use crate::logging::millis;
+use crate::metrics::Metrics;
 use crate::models::ProverConfig;
 use crate::types::proof::{CircuitProver, ProofWithInputs};
 
+/// Computes the witness for `params`, timed as the `witness` phase. `params` is zeroized once
+/// its values have been copied into the builder, the builder's copies are not. Inputs the
+/// circuit's constraints reject fail here, with an error that doesn't carry their values.
 pub fn build_inputs(
     circuit: &CircuitProver,
     cfg: ProverConfig,
-    params: HashMap<String, u64>,
-) -> CircomCircuit<Bn254> {
+    mut params: PrivateInputs,
+    metrics: &Metrics,
+) -> Result<CircomCircuit<Bn254>, ProvingServerError> {
+    let started = Instant::now();
+    let prover = cfg.key();
     let mut builder = circuit.builder.clone();
 
     for param in cfg.builder_params {
-        builder.push_input(
-            param.clone(),
-            ToBigInt::to_bigint(params.get(&param).unwrap()).unwrap(),
-        )
+        let value = params
+            .get(&param)
+            .ok_or_else(|| ProvingServerError::BadProofInputsError {
+                message: param.clone(),
+            })?;
+        builder.push_input(param, ToBigInt::to_bigint(&value).unwrap())
     }
-    builder.build().unwrap()
+    params.zeroize();
+    let circuit = builder
+        .build()
+        .map_err(|_| ProvingServerError::ProofFailed {
+            message: String::from("the inputs don't satisfy the circuit"),
+        })?;
+    let elapsed = started.elapsed();
+    metrics.observe_phase(&prover, "witness", elapsed);
+    tracing::info!(
+        phase = "witness",
+        elapsed_ms = millis(elapsed),
+        "built witness"
+    );
+    Ok(circuit)
 }
 
+/// Runs groth16 over a built circuit, timed as the `proving` phase.
 pub fn prove(
     circuit: CircomCircuit<Bn254>,
     params: &ProvingKey<Bn254>,
-) -> Result<ProofWithInputs, ()> {
-    let public_inputs = circuit.get_public_inputs().unwrap();
+    prover: &str,
+    metrics: &Metrics,
+) -> Result<ProofWithInputs, ProvingServerError> {
+    let started = Instant::now();
+    let public_inputs =
+        circuit
+            .get_public_inputs()
+            .ok_or_else(|| ProvingServerError::ProofFailed {
+                message: String::from("the circuit has no witness"),
+            })?;
     let proof = create_random_proof_with_reduction::<_, _, _, CircomReduction>(
         circuit,
         params,
         &mut thread_rng(),
     )
-    .unwrap();
+    .map_err(|error| ProvingServerError::ProofFailed {
+        message: error.to_string(),
+    })?;
+    let elapsed = started.elapsed();
+    metrics.observe_phase(prover, "proving", elapsed);
+    tracing::info!(
+        phase = "prove",
+        elapsed_ms = millis(elapsed),
+        "generated proof"
+    );
     Ok((proof, public_inputs))
 }
 
@@ -49,6 +93,58 @@ mod tests {
     };
     use ark_std::rand::thread_rng;
     #[test]
+    fn unit_proofs_log_no_inputs() {
+        use super::{build_inputs, prove};
+        use crate::errors::ProvingServerError;
+        use crate::inputs::PrivateInputs;
+        use crate::metrics::Metrics;
+        use crate::test::fixtures;
+        use rocket::serde::json;
+
+        // values that can't turn up in a log line by coincidence
+        let mut body = fixtures::df_proof_request();
+        body.insert(String::from("PLANETHASH_KEY"), 7_919_113);
+        body.insert(String::from("SPACETYPE_KEY"), 6_700_417);
+        let body = json::to_string(&body).unwrap();
+        let circuit = CircuitProver::new_path(
+            String::from("../../static/6.6.6/move.zkey"),
+            String::from("../../static/6.6.6/move.wasm"),
+            String::from("../../static/6.6.6/move.r1cs"),
+        )
+        .unwrap();
+        let prover = fixtures::df_prover_config();
+
+        let logs = fixtures::logs::capture(|| {
+            let inputs: PrivateInputs = json::from_str(&body).unwrap();
+            tracing::info!(?inputs, "received inputs");
+            let mut partial = fixtures::df_proof_request();
+            partial.remove("x2");
+            let error = prover
+                .validate_inputs(&PrivateInputs::from(partial))
+                .unwrap_err();
+            tracing::warn!(%error, "refused inputs");
+            let metrics = Metrics::default();
+            let built = build_inputs(&circuit, prover.clone(), inputs, &metrics).unwrap();
+            prove(built, &circuit.params, &prover.key(), &metrics).unwrap();
+        });
+        for expected in [
+            "received inputs",
+            "refused inputs",
+            "built witness",
+            "generated proof",
+        ] {
+            assert!(
+                logs.contains(expected),
+                "{:?} missing from\n{}",
+                expected,
+                logs
+            );
+        }
+        for secret in ["7919113", "6700417"] {
+            assert!(!logs.contains(secret), "{} leaked into\n{}", secret, logs);
+        }
+    }
+    #[test]
     fn unit_circom_config_init() {
         CircomConfig::<Bn254>::new(
             "../../static/6.6.6/move.wasm",
@@ -0,0 +1,313 @@
+/// Durable load job queue
+///
+/// Load jobs wait in the `job` table instead of in memory, so registering a prover never waits
+/// on a busy worker and queued jobs survive a restart. A pool of `queue.workers` workers claims
+/// the oldest pending jobs with leases that they renew while they work on them. A job whose lease
+/// runs out, because its worker died, is claimed again by the next worker to look. Queuing a job
+/// wakes a worker, and workers also poll the table in case a wake-up is missed. Cancelling a job
+/// stops the worker running it straight away when it is in this process, and when its lease
+/// can't be renewed otherwise.
+use crate::errors::ProvingServerError;
+use crate::models::{Job, JobStatus};
+use rocket::serde::{Deserialize, Serialize};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", default)]
+pub struct QueueConfig {
+    /// Workers taking load jobs at the same time
+    pub workers: usize,
+    /// Memory that loaded provers and provers being loaded may take together, see `memory`.
+    /// Unlimited when unset.
+    pub memory_budget_bytes: Option<u64>,
+    /// How long a claimed job stays with its worker without the lease being renewed
+    pub lease_secs: u64,
+    /// How often idle workers look for jobs they weren't woken for
+    pub poll_interval_ms: u64,
+    /// Claims a job gets before it fails instead of being retried
+    pub max_attempts: u32,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            workers: 2,
+            memory_budget_bytes: None,
+            lease_secs: 60,
+            poll_interval_ms: 1000,
+            max_attempts: 3,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        let invalid = |message: &str| ProvingServerError::InvalidConfig {
+            message: String::from(message),
+        };
+        if self.workers == 0 {
+            return Err(invalid("queue.workers must be at least 1"));
+        }
+        if self.memory_budget_bytes == Some(0) {
+            return Err(invalid("queue.memory_budget_bytes must be above 0"));
+        }
+        if self.lease_secs == 0 {
+            return Err(invalid("queue.lease_secs must be above 0"));
+        }
+        if self.poll_interval_ms == 0 {
+            return Err(invalid("queue.poll_interval_ms must be above 0"));
+        }
+        if self.max_attempts == 0 {
+            return Err(invalid("queue.max_attempts must be at least 1"));
+        }
+        Ok(())
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    /// Leases are renewed well before they run out, so a slow database doesn't lose them.
+    pub fn renew_interval(&self) -> Duration {
+        Duration::from_secs(self.lease_secs) / 3
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    queued: Notify,
+    running: Mutex<HashMap<i64, Arc<Notify>>>,
+}
+
+/// Wakes workers when jobs are queued, and stops the jobs they run when those are cancelled.
+/// Cloning shares it.
+#[derive(Clone, Default)]
+pub struct JobQueue(Arc<QueueState>);
+
+impl JobQueue {
+    pub fn wake(&self) {
+        // keeps the wake-up for a worker that is about to wait
+        self.0.queued.notify_one();
+    }
+
+    /// Waits to be woken, or for `poll` to pass.
+    pub async fn wait(&self, poll: Duration) {
+        tokio::select! {
+            _ = self.0.queued.notified() => {}
+            _ = tokio::time::sleep(poll) => {}
+        }
+    }
+
+    /// Registers a job a worker in this process is about to run.
+    pub fn running(&self, id: i64) -> RunningJob {
+        let cancelled = Arc::new(Notify::new());
+        self.0.running.lock().unwrap().insert(id, cancelled.clone());
+        RunningJob {
+            queue: self.clone(),
+            id,
+            cancelled,
+        }
+    }
+
+    /// Stops the job if a worker in this process runs it. Workers in other processes notice
+    /// when they fail to renew their lease.
+    pub fn cancel(&self, id: i64) {
+        if let Some(cancelled) = self.0.running.lock().unwrap().get(&id) {
+            cancelled.notify_one();
+        }
+    }
+}
+
+/// A job being run, unregistered when it is dropped.
+pub struct RunningJob {
+    queue: JobQueue,
+    id: i64,
+    cancelled: Arc<Notify>,
+}
+
+impl RunningJob {
+    /// Resolves once the job is cancelled.
+    pub async fn cancelled(&self) {
+        self.cancelled.notified().await
+    }
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        self.queue.0.running.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Refuses new load jobs once `capacity` jobs are already waiting. Checked before a prover is
+/// changed, so a refused registration leaves nothing behind.
+pub fn check_capacity(capacity: usize, conn: &Connection) -> Result<(), ProvingServerError> {
+    let waiting = Job::count_with_status(JobStatus::Pending, conn)?;
+    if waiting as usize >= capacity {
+        return Err(ProvingServerError::QueueFull { capacity });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{unix_now, Crud, JobKind, JobQuery, ProverConfig};
+    use crate::test::fixtures;
+
+    fn enqueue(version: &str, conn: &Connection) -> Result<i64, ProvingServerError> {
+        check_capacity(2, conn)?;
+        let mut prover: ProverConfig = fixtures::df_prover_config();
+        prover.version = String::from(version);
+        prover.create(conn).unwrap();
+        Ok(Job::queue_for_prover(
+            &prover,
+            String::from("loading"),
+            conn,
+        )?)
+    }
+
+    #[tokio::test]
+    async fn unit_jobs_are_claimed_in_order_with_leases() {
+        let conn = fixtures::setup_db().await;
+        let first = enqueue("0.0.1", &conn).unwrap();
+        let second = enqueue("0.0.2", &conn).unwrap();
+        assert!(matches!(
+            enqueue("0.0.3", &conn),
+            Err(ProvingServerError::QueueFull { capacity: 2 })
+        ));
+
+        let claimed = Job::claim("worker-a", 60, 3, &conn).unwrap().unwrap();
+        assert_eq!(claimed.id, Some(first));
+        assert_eq!(claimed.status, JobStatus::Processing);
+        assert_eq!(claimed.lease_owner.as_deref(), Some("worker-a"));
+        assert_eq!(claimed.attempts, 1);
+        let claimed = Job::claim("worker-b", 60, 3, &conn).unwrap().unwrap();
+        assert_eq!(claimed.id, Some(second));
+        assert!(Job::claim("worker-c", 60, 3, &conn).unwrap().is_none());
+
+        assert!(Job::renew_lease(first, "worker-a", 60, &conn).unwrap());
+        assert!(!Job::renew_lease(first, "worker-b", 60, &conn).unwrap());
+
+        // worker-a died, so its lease runs out and another worker takes the job
+        let mut stale = Job::get(first, &conn).unwrap();
+        stale.leased_until = Some(unix_now() - 1);
+        stale.update(&conn).unwrap();
+        let retried = Job::claim("worker-c", 60, 3, &conn).unwrap().unwrap();
+        assert_eq!(retried.id, Some(first));
+        assert_eq!(retried.lease_owner.as_deref(), Some("worker-c"));
+        assert_eq!(retried.attempts, 2);
+        assert!(!Job::renew_lease(first, "worker-a", 60, &conn).unwrap());
+
+        let mut stale = Job::get(first, &conn).unwrap();
+        stale.leased_until = Some(unix_now() - 1);
+        stale.update(&conn).unwrap();
+        assert!(Job::claim("worker-d", 60, 2, &conn).unwrap().is_none());
+        let failed = Job::get(first, &conn).unwrap();
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert_eq!(failed.lease_owner, None);
+
+        let mut done = Job::get(second, &conn).unwrap();
+        done.finish(JobStatus::Ready, &conn).unwrap();
+        let done = Job::get(second, &conn).unwrap();
+        assert_eq!(done.status, JobStatus::Ready);
+        assert_eq!(done.leased_until, None);
+    }
+
+    #[tokio::test]
+    async fn unit_cancelling_stops_the_running_job() {
+        let conn = fixtures::setup_db().await;
+        let queued = enqueue("0.0.1", &conn).unwrap();
+        let running = enqueue("0.0.2", &conn).unwrap();
+        Job::claim("worker-a", 60, 3, &conn).unwrap().unwrap();
+        let claimed = Job::claim("worker-a", 60, 3, &conn).unwrap().unwrap();
+        assert_eq!(claimed.id, Some(running));
+
+        let queue = JobQueue::default();
+        let job = queue.running(running);
+        assert!(Job::cancel(running, &conn).unwrap());
+        queue.cancel(running);
+        tokio::time::timeout(Duration::from_secs(1), job.cancelled())
+            .await
+            .unwrap();
+        drop(job);
+        assert!(queue.0.running.lock().unwrap().is_empty());
+
+        // the worker that ran it can't record it as loaded, or keep its lease
+        let mut cancelled = Job::get(running, &conn).unwrap();
+        assert_eq!(cancelled.status, JobStatus::Cancelled);
+        assert!(!Job::renew_lease(running, "worker-a", 60, &conn).unwrap());
+        assert!(!cancelled.finish(JobStatus::Ready, &conn).unwrap());
+        assert_eq!(
+            Job::get(running, &conn).unwrap().status,
+            JobStatus::Cancelled
+        );
+
+        assert!(!Job::cancel(running, &conn).unwrap());
+        let mut done = Job::get(queued, &conn).unwrap();
+        assert!(done.finish(JobStatus::Ready, &conn).unwrap());
+        assert!(!Job::cancel(queued, &conn).unwrap());
+    }
+
+    #[tokio::test]
+    async fn unit_jobs_keep_their_history() {
+        let conn = fixtures::setup_db().await;
+        let mut prover: ProverConfig = fixtures::df_prover_config();
+        prover.create(&conn).unwrap();
+        let first = Job::queue_for_prover(&prover, String::from("loading"), &conn).unwrap();
+        let claimed = Job::claim("worker-a", 60, 3, &conn).unwrap().unwrap();
+        assert!(claimed.started_at.is_some());
+        assert_eq!(claimed.wait_secs(), Some(0));
+        assert_eq!(claimed.run_secs(), None);
+
+        // loading again supersedes the unfinished job instead of reusing it
+        let second = Job::queue_for_prover(&prover, String::from("loading"), &conn).unwrap();
+        assert_ne!(first, second);
+        let superseded = Job::get(first, &conn).unwrap();
+        assert_eq!(superseded.status, JobStatus::Cancelled);
+        assert!(superseded.run_secs().is_some());
+        let mut latest = Job::claim("worker-a", 60, 3, &conn).unwrap().unwrap();
+        latest.message = String::from("zkey is not a valid zkey");
+        latest.finish(JobStatus::Failed, &conn).unwrap();
+        assert_eq!(
+            Job::get_for_prover(prover.id.unwrap(), &conn).unwrap(),
+            Some(latest)
+        );
+
+        let all = JobQuery {
+            provers: vec![prover.id.unwrap()],
+            limit: 10,
+            ..Default::default()
+        };
+        let ids = |query: &JobQuery| -> Vec<i64> {
+            Job::list(query, &conn)
+                .unwrap()
+                .into_iter()
+                .map(|job| job.id.unwrap())
+                .collect()
+        };
+        assert_eq!(ids(&all), vec![second, first]);
+        let failed = JobQuery {
+            status: Some(JobStatus::Failed),
+            kind: Some(JobKind::Load),
+            ..all.clone()
+        };
+        assert_eq!(ids(&failed), vec![second]);
+        let later = JobQuery {
+            since: Some(unix_now() + 1),
+            ..all.clone()
+        };
+        assert!(ids(&later).is_empty());
+        assert_eq!(
+            ids(&JobQuery {
+                limit: 1,
+                ..all.clone()
+            }),
+            vec![second]
+        );
+        assert!(ids(&JobQuery::default()).is_empty());
+    }
+}
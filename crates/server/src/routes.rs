@@ -1,23 +1,93 @@
+use crate::acl::{self, Permission};
+use crate::alias::{self, WithVersion};
+use crate::auth::{Authorized, ProverAdmin, Read};
 use crate::errors::ProvingServerError;
-use crate::models::{Crud, Job, JobStatus, ProverConfig};
+use crate::health::{self, RequiredProvers, WorkerHealth};
+use crate::limits::{self, RateLimiter, WithBudget};
+use crate::logging::{millis, RequestId};
+use crate::metrics::{Metrics, UNKNOWN_PROVER};
+use crate::models::{
+    unix_now, Crud, Job, JobKind, JobQuery, JobStatus, ProverAlias, ProverConfig, DEFAULT_NAMESPACE,
+};
 use crate::prover;
-use crate::types::proof::{to_eth_type, Abc, Provers};
-use crate::types::reqres::{JobResponse, ProofRequest, ProverConfigRequest};
-use crate::types::Db;
+use crate::queue::{check_capacity, JobQueue};
+use crate::scheduler::{Priority, ProofInfo, Scheduler, WithProofId};
+use crate::shutdown::Drain;
+use crate::signature::{self, ProofCredential};
+use crate::storage::{collect_all_garbage, report_all, validate_identifier, GC_GRACE};
+use crate::types::proof::{to_eth_type, vk_to_eth_type, Abc, Provers};
+use crate::types::reqres::{
+    AliasRequest, BlobReport, GcReport, JobFilter, JobRecord, JobResponse, ProofRequest,
+    ProofStatus, ProverConfigRequest, ReadinessResponse, VerifyingKeyResponse,
+};
+use crate::types::{Config, Db};
+use crate::utils::files::prover_from_request;
 use ark_circom::ethereum::Proof;
 use rocket::http::Status;
 use rocket::serde::json::Json;
+use rusqlite::OptionalExtension;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+use tracing::Instrument;
 
 #[get("/")]
 pub fn index() -> &'static str {
     "Hello, world!"
 }
 
+#[get("/health/live")]
+pub fn live_handler() -> &'static str {
+    "ok"
+}
+
+/// 503 until the server can take traffic, see `health`.
+#[get("/health/ready")]
+pub async fn ready_handler(
+    db: &rocket::State<Db>,
+    prover_storage: &rocket::State<Provers>,
+    required: &rocket::State<RequiredProvers>,
+    worker: &rocket::State<WorkerHealth>,
+    drain: &rocket::State<Drain>,
+) -> (Status, Json<ReadinessResponse>) {
+    let report = health::readiness(db, prover_storage, required, worker, drain).await;
+    let status = if report.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(report))
+}
+
+/// Prometheus text format. Spans every namespace, so tenant keys can't scrape it.
+#[get("/metrics")]
+pub async fn metrics_handler(
+    metrics: &rocket::State<Metrics>,
+    db: &rocket::State<Db>,
+    prover_storage: &rocket::State<Provers>,
+    auth: Authorized<Read>,
+) -> Result<String, ProvingServerError> {
+    auth.check_server_wide()?;
+    metrics.render(db, prover_storage).await
+}
+
+// The routes without a namespace act on the default namespace.
+
 #[get("/prover")]
 pub async fn list_provers_handler(
-    _db: &rocket::State<Db>,
-) -> Option<Json<Vec<ProverConfigRequest>>> {
-    todo!();
+    db: &rocket::State<Db>,
+    auth: Authorized<Read>,
+) -> Result<Json<Vec<ProverConfigRequest>>, ProvingServerError> {
+    ns_list_provers_handler(db, DEFAULT_NAMESPACE, auth).await
+}
+
+#[get("/jobs?<filter..>")]
+pub async fn list_jobs_handler(
+    db: &rocket::State<Db>,
+    filter: JobFilter,
+    auth: Authorized<Read>,
+) -> Result<Json<Vec<JobRecord>>, ProvingServerError> {
+    ns_list_jobs_handler(db, DEFAULT_NAMESPACE, filter, auth).await
 }
 
 #[get("/prover/<prover_name>/<prover_version>")]
@@ -25,78 +95,846 @@ pub async fn get_prover(
     db: &rocket::State<Db>,
     prover_name: &str,
     prover_version: &str,
+    auth: Authorized<Read>,
 ) -> Result<Json<JobResponse>, ProvingServerError> {
-    let db = db.lock().await;
-
-    let job =
-        Job::get_by_name_and_version(String::from(prover_name), String::from(prover_version), &db)
-            .unwrap();
-    Ok(Json(JobResponse::from(job)))
+    ns_get_prover(db, DEFAULT_NAMESPACE, prover_name, prover_version, auth).await
 }
 
-#[post("/prove/<prover_name>/<prover_version>", data = "<inputs>")]
+#[allow(clippy::too_many_arguments)]
+#[post("/prove/<prover_name>/<prover_version>?<priority>", data = "<inputs>")]
 pub async fn execute_prover(
     prover_storage: &rocket::State<Provers>,
     db: &rocket::State<Db>,
     prover_name: &str,
     prover_version: &str,
+    priority: Option<&str>,
     inputs: Json<ProofRequest>,
-) -> Result<Json<Abc>, ProvingServerError> {
-    println!("fetching prover");
-    let prover_storage_guard = prover_storage.lock().await;
-    let p: crate::types::proof::CircuitProver =
-        prover_storage_guard.get(prover_name).unwrap().clone();
-    drop(prover_storage_guard);
-
-    let db_guard = db.lock().await;
-    println!("fetching prover config");
-    let prover = ProverConfig::get_by_name_and_version(
-        String::from(prover_name),
-        String::from(prover_version),
-        &db_guard,
+    config: &rocket::State<Config>,
+    limiter: &rocket::State<RateLimiter>,
+    credential: ProofCredential,
+    client_ip: Option<IpAddr>,
+    metrics: &rocket::State<Metrics>,
+    request_id: RequestId,
+    drain: &rocket::State<Drain>,
+    scheduler: &rocket::State<Scheduler>,
+) -> Result<WithBudget<WithProofId<WithVersion<Json<Abc>>>>, ProvingServerError> {
+    ns_execute_prover(
+        prover_storage,
+        db,
+        DEFAULT_NAMESPACE,
+        prover_name,
+        prover_version,
+        priority,
+        inputs,
+        config,
+        limiter,
+        credential,
+        client_ip,
+        metrics,
+        request_id,
+        drain,
+        scheduler,
+    )
+    .await
+}
+
+#[get("/proof/<id>")]
+pub async fn proof_status_handler(
+    scheduler: &rocket::State<Scheduler>,
+    id: &str,
+    auth: Authorized<Read>,
+) -> Result<Json<ProofStatus>, ProvingServerError> {
+    ns_proof_status_handler(scheduler, DEFAULT_NAMESPACE, id, auth).await
+}
+
+#[post("/prover", format = "json", data = "<prover>")]
+pub async fn add_prover_handler(
+    db: &rocket::State<Db>,
+    prover: Json<ProverConfigRequest>,
+    queue: &rocket::State<JobQueue>,
+    config: &rocket::State<Config>,
+    auth: Authorized<ProverAdmin>,
+    request_id: RequestId,
+) -> Result<Status, ProvingServerError> {
+    ns_add_prover_handler(
+        db,
+        DEFAULT_NAMESPACE,
+        prover,
+        queue,
+        config,
+        auth,
+        request_id,
     )
-    .unwrap();
-    drop(db_guard);
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[patch(
+    "/prover/<prover_name>/<prover_version>",
+    format = "json",
+    data = "<prover>"
+)]
+pub async fn update_prover_handler(
+    db: &rocket::State<Db>,
+    queue: &rocket::State<JobQueue>,
+    config: &rocket::State<Config>,
+    prover_name: &str,
+    prover_version: &str,
+    prover: Json<ProverConfigRequest>,
+    auth: Authorized<ProverAdmin>,
+    request_id: RequestId,
+) -> Result<Status, ProvingServerError> {
+    ns_update_prover_handler(
+        db,
+        queue,
+        config,
+        DEFAULT_NAMESPACE,
+        prover_name,
+        prover_version,
+        prover,
+        auth,
+        request_id,
+    )
+    .await
+}
+
+#[delete("/prover/<prover_name>/<prover_version>")]
+pub async fn delete_prover_handler(
+    db: &rocket::State<Db>,
+    prover_storage: &rocket::State<Provers>,
+    queue: &rocket::State<JobQueue>,
+    prover_name: &str,
+    prover_version: &str,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Status, ProvingServerError> {
+    ns_delete_prover_handler(
+        db,
+        prover_storage,
+        queue,
+        DEFAULT_NAMESPACE,
+        prover_name,
+        prover_version,
+        auth,
+    )
+    .await
+}
 
-    let proof_inputs = inputs.into_inner();
-    if let Err(error) = prover.validate_inputs(&proof_inputs) {
-        return Err(error);
+#[get("/vkey/<prover_name>/<prover_version>")]
+pub async fn get_verifying_key(
+    db: &rocket::State<Db>,
+    prover_storage: &rocket::State<Provers>,
+    prover_name: &str,
+    prover_version: &str,
+    auth: Authorized<Read>,
+) -> Result<WithVersion<Json<VerifyingKeyResponse>>, ProvingServerError> {
+    ns_get_verifying_key(
+        db,
+        prover_storage,
+        DEFAULT_NAMESPACE,
+        prover_name,
+        prover_version,
+        auth,
+    )
+    .await
+}
+
+#[get("/alias/<prover_name>")]
+pub async fn list_aliases_handler(
+    db: &rocket::State<Db>,
+    prover_name: &str,
+    auth: Authorized<Read>,
+) -> Result<Json<Vec<ProverAlias>>, ProvingServerError> {
+    ns_list_aliases_handler(db, DEFAULT_NAMESPACE, prover_name, auth).await
+}
+
+#[put(
+    "/alias/<prover_name>/<alias_name>",
+    format = "json",
+    data = "<target>"
+)]
+pub async fn set_alias_handler(
+    db: &rocket::State<Db>,
+    prover_name: &str,
+    alias_name: &str,
+    target: Json<AliasRequest>,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Json<ProverAlias>, ProvingServerError> {
+    ns_set_alias_handler(db, DEFAULT_NAMESPACE, prover_name, alias_name, target, auth).await
+}
+
+#[delete("/alias/<prover_name>/<alias_name>")]
+pub async fn delete_alias_handler(
+    db: &rocket::State<Db>,
+    prover_name: &str,
+    alias_name: &str,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Status, ProvingServerError> {
+    ns_delete_alias_handler(db, DEFAULT_NAMESPACE, prover_name, alias_name, auth).await
+}
+
+/// Lists the provers registered in `ns`, never those of another namespace, leaving out private
+/// provers the caller has no access to.
+#[get("/ns/<ns>/prover")]
+pub async fn ns_list_provers_handler(
+    db: &rocket::State<Db>,
+    ns: &str,
+    auth: Authorized<Read>,
+) -> Result<Json<Vec<ProverConfigRequest>>, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let principal = auth.principal();
+    let provers = ProverConfig::list_in_namespace(ns, &*db.lock().await)?;
+    Ok(Json(
+        provers
+            .into_iter()
+            .filter(|prover| acl::visible_to(prover, principal.as_ref()))
+            .map(ProverConfigRequest::from)
+            .collect(),
+    ))
+}
+
+#[get("/ns/<ns>/prover/<prover_name>/<prover_version>")]
+pub async fn ns_get_prover(
+    db: &rocket::State<Db>,
+    ns: &str,
+    prover_name: &str,
+    prover_version: &str,
+    auth: Authorized<Read>,
+) -> Result<Json<JobResponse>, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let db = db.lock().await;
+    let prover = find_prover(ns, prover_name, prover_version, &db)?;
+    // private provers look the same as missing ones to those who can't see them
+    if !acl::visible_to(&prover, auth.principal().as_ref()) {
+        return Err(ProvingServerError::ProverNotFound {
+            name: prover.name,
+            version: prover.version,
+        });
     }
+    let job = Job::get_for_prover(prover.id.unwrap(), &db)?.ok_or_else(|| {
+        ProvingServerError::ProverNotFound {
+            name: prover.name.clone(),
+            version: prover.version.clone(),
+        }
+    })?;
+    Ok(Json(JobResponse::from(job)))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post(
+    "/ns/<ns>/prove/<prover_name>/<prover_version>?<priority>",
+    data = "<inputs>"
+)]
+pub async fn ns_execute_prover(
+    prover_storage: &rocket::State<Provers>,
+    db: &rocket::State<Db>,
+    ns: &str,
+    prover_name: &str,
+    prover_version: &str,
+    priority: Option<&str>,
+    inputs: Json<ProofRequest>,
+    config: &rocket::State<Config>,
+    limiter: &rocket::State<RateLimiter>,
+    credential: ProofCredential,
+    client_ip: Option<IpAddr>,
+    metrics: &rocket::State<Metrics>,
+    request_id: RequestId,
+    drain: &rocket::State<Drain>,
+    scheduler: &rocket::State<Scheduler>,
+) -> Result<WithBudget<WithProofId<WithVersion<Json<Abc>>>>, ProvingServerError> {
+    let span = tracing::info_span!(
+        "prove",
+        %request_id,
+        namespace = ns,
+        prover = prover_name,
+        version = prover_version
+    );
+    let requested = Instant::now();
+    let mut label = String::from(UNKNOWN_PROVER);
+    let result: Result<_, ProvingServerError> = async {
+        let _in_flight = drain.admit()?;
+        let priority = priority
+            .map(Priority::parse)
+            .transpose()?
+            .unwrap_or_default();
+        match &credential {
+            ProofCredential::ApiKey(auth) => auth.check_namespace(ns)?,
+            // the signature covers the namespace, checked against the prover's signers below
+            ProofCredential::Signed(_) => validate_identifier("namespace", ns)?,
+        }
+        let proof_inputs = inputs.into_inner();
+        let (max_ttl, defaults) = {
+            let config = config.lock().await;
+            (config.auth.signature_max_ttl_secs, config.limits.clone())
+        };
+        let db_guard = db.lock().await;
+        let prover = alias::resolve(ns, prover_name, prover_version, &db_guard)?;
+        label = prover.key();
+        let client = match &credential {
+            ProofCredential::Signed(signed) => {
+                let signer = signature::verify(
+                    signed,
+                    &prover,
+                    prover_version,
+                    &proof_inputs,
+                    max_ttl,
+                    unix_now(),
+                    &db_guard,
+                )?;
+                tracing::info!(%signer, "proof request signed");
+                format!("signer:{}", signer)
+            }
+            ProofCredential::ApiKey(auth) => {
+                acl::check(&prover, auth.principal().as_ref(), Permission::Prove)?;
+                match (&auth.key, client_ip) {
+                    (Some(key), _) => format!("key:{}", key.name),
+                    (None, Some(ip)) => format!("ip:{}", ip),
+                    (None, None) => String::from("ip:unknown"),
+                }
+            }
+        };
+        if let Err(error) = prover.validate_inputs(&proof_inputs) {
+            return Err(error);
+        }
+        let mut budget = limits::admit(
+            limiter,
+            &client,
+            &prover,
+            &defaults,
+            scheduler.expected_seconds(&label),
+            Instant::now(),
+            unix_now(),
+            &db_guard,
+        )?;
+        drop(db_guard);
+
+        // the seconds admit reserved are given back when the proof never runs or fails
+        let proving = async {
+            let prover_storage_guard = prover_storage.lock().await;
+            let p: crate::types::proof::CircuitProver = prover_storage_guard
+                .get(&prover.key())
+                .ok_or_else(|| ProvingServerError::ProverNotReady {
+                    name: prover.name.clone(),
+                    version: prover.version.clone(),
+                })?
+                .clone();
+            drop(prover_storage_guard);
 
-    println!("generating circuit");
-    let circuit = prover::build_inputs(&p.clone(), prover, proof_inputs);
-    let (proof, _) = prover::prove(circuit, &p.params).unwrap();
+            let ticket = scheduler.enqueue(ProofInfo {
+                id: request_id.0.clone(),
+                namespace: String::from(ns),
+                prover: label.clone(),
+                priority,
+            });
+            let proof_id = ticket.id().to_string();
+            let queued = Instant::now();
+            let slot = ticket.start().await;
+            metrics.observe_proof_wait(priority.as_str(), queued.elapsed());
+            tracing::info!(
+                priority = priority.as_str(),
+                waited_ms = millis(queued.elapsed()),
+                "got a proving slot"
+            );
 
-    Ok(Json(to_eth_type(Proof::from(proof))))
+            // the witness and groth16 keep a core busy for seconds, so they run off the
+            // executor. The slot goes along and is only given back once they're done, and
+            // dropping the slot of a failed proof records it as failed. The span and dispatcher
+            // go along too, so their logs still carry the request id.
+            let (cfg, key, metrics, span, dispatch) = (
+                prover.clone(),
+                label.clone(),
+                (*metrics).clone(),
+                tracing::Span::current(),
+                tracing::dispatcher::get_default(|current| current.clone()),
+            );
+            let proved = tokio::task::spawn_blocking(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    span.in_scope(|| {
+                        let circuit = prover::build_inputs(&p, cfg, proof_inputs, &metrics)?;
+                        // wall-clock time, see the limits module
+                        let started = Instant::now();
+                        let (proof, _) = prover::prove(circuit, &p.params, &key, &metrics)?;
+                        let seconds = started.elapsed().as_secs_f64();
+                        slot.done();
+                        Ok::<_, ProvingServerError>((proof, seconds))
+                    })
+                })
+            })
+            .await
+            .map_err(|_| ProvingServerError::ProofFailed {
+                message: String::from("the prover panicked"),
+            })?;
+            let (proof, seconds) = proved?;
+            Ok::<_, ProvingServerError>((proof, seconds, proof_id))
+        };
+        let proved = proving.await;
+        let seconds = proved
+            .as_ref()
+            .map(|(_, seconds, _)| *seconds)
+            .unwrap_or(0.0);
+        limits::record(
+            &client,
+            &prover,
+            seconds,
+            unix_now(),
+            &mut budget,
+            &*db.lock().await,
+        )?;
+        let (proof, _, proof_id) = proved?;
+
+        Ok(WithBudget(
+            WithProofId(
+                WithVersion(Json(to_eth_type(Proof::from(proof))), prover.version),
+                proof_id,
+            ),
+            budget,
+        ))
+    }
+    .instrument(span.clone())
+    .await;
+    if let Err(error) = &result {
+        span.in_scope(|| tracing::info!(%error, "proof refused"));
+    }
+    metrics.observe_proof(&label, &result, requested.elapsed());
+    result
 }
 
-#[post("/prover", format = "json", data = "<prover>")]
-pub async fn add_prover_handler(
+/// Registering a prover that already exists changes nothing, PATCH replaces it.
+#[post("/ns/<ns>/prover", format = "json", data = "<prover>")]
+pub async fn ns_add_prover_handler(
     db: &rocket::State<Db>,
+    ns: &str,
     prover: Json<ProverConfigRequest>,
-    queue: &rocket::State<crate::types::JobSender>,
-) -> Status {
+    queue: &rocket::State<JobQueue>,
+    config: &rocket::State<Config>,
+    auth: Authorized<ProverAdmin>,
+    request_id: RequestId,
+) -> Result<Status, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let prover = in_namespace(ns, prover.into_inner())?;
+    let config = config.lock().await.clone();
+    let mut p = prover_from_request(&config, prover).await?;
+    if p.owner.is_none() {
+        p.owner = auth.principal().map(|principal| principal.to_string());
+    }
+    let db = db.lock().await;
+    if find_prover(ns, &p.name, &p.version, &db).is_ok() {
+        return Ok(Status::Ok);
+    }
+    check_capacity(config.queue_size, &db)?;
+    p.create(&db)?;
+    let id = Job::queue_for_prover(&p, format!("fetching deps for {}", p.name), &db)?;
+    queue.wake();
+    log_queued(&request_id, id, &p);
+
+    Ok(Status::Ok)
+}
+
+/// Replaces a prover's artifact sources and builder params and loads it again.
+#[allow(clippy::too_many_arguments)]
+#[patch(
+    "/ns/<ns>/prover/<prover_name>/<prover_version>",
+    format = "json",
+    data = "<prover>"
+)]
+pub async fn ns_update_prover_handler(
+    db: &rocket::State<Db>,
+    queue: &rocket::State<JobQueue>,
+    config: &rocket::State<Config>,
+    ns: &str,
+    prover_name: &str,
+    prover_version: &str,
+    prover: Json<ProverConfigRequest>,
+    auth: Authorized<ProverAdmin>,
+    request_id: RequestId,
+) -> Result<Status, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let prover = in_namespace(ns, prover.into_inner())?;
+    if prover.name != prover_name || prover.version != prover_version {
+        return Err(ProvingServerError::InvalidProverConfig {
+            message: String::from("name and version can't be changed, register a new version"),
+        });
+    }
+    let config = config.lock().await.clone();
+    let mut updated = prover_from_request(&config, prover).await?;
+    let db = db.lock().await;
+    let existing = find_prover(ns, prover_name, prover_version, &db)?;
+    let principal = auth.principal();
+    acl::check(&existing, principal.as_ref(), Permission::Update)?;
+    if updated.owner.is_none() {
+        updated.owner = existing.owner.clone();
+    }
+    let access_changed = updated.owner != existing.owner
+        || updated.visibility != existing.visibility
+        || updated.acl != existing.acl;
+    if access_changed && !acl::owns(&existing, principal.as_ref()) {
+        return Err(ProvingServerError::Forbidden {
+            message: format!(
+                "only the owner of {} can change its owner, visibility or acl",
+                existing.label()
+            ),
+        });
+    }
+    check_capacity(config.queue_size, &db)?;
+    updated.id = existing.id;
+    updated.update(&db)?;
+    let id = Job::queue_for_prover(
+        &updated,
+        format!("reloading deps for {}", updated.name),
+        &db,
+    )?;
+    drop(db);
+    // proofs keep using the old circuit until the worker's insert replaces it
+    queue.wake();
+    log_queued(&request_id, id, &updated);
+    Ok(Status::Ok)
+}
+
+/// Ties the request that registered or changed a prover to the load job it queued.
+fn log_queued(request_id: &RequestId, job_id: i64, prover: &ProverConfig) {
+    tracing::info!(
+        %request_id,
+        job_id,
+        namespace = %prover.namespace,
+        prover = %prover.name,
+        version = %prover.version,
+        "queued load job"
+    );
+}
+
+#[delete("/ns/<ns>/prover/<prover_name>/<prover_version>")]
+pub async fn ns_delete_prover_handler(
+    db: &rocket::State<Db>,
+    prover_storage: &rocket::State<Provers>,
+    queue: &rocket::State<JobQueue>,
+    ns: &str,
+    prover_name: &str,
+    prover_version: &str,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Status, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let db = db.lock().await;
+    let prover = find_prover(ns, prover_name, prover_version, &db)?;
+    acl::check(&prover, auth.principal().as_ref(), Permission::Delete)?;
+    let job = Job::get_for_prover(prover.id.unwrap(), &db)?;
+    prover.delete(&db)?;
+    drop(db);
+    // a load still running would only put the prover back
+    if let Some(id) = job.and_then(|job| job.id) {
+        queue.cancel(id);
+    }
+    prover_storage.lock().await.remove(&prover.key());
+    Ok(Status::NoContent)
+}
+
+/// Where a proof request is: its place in the queue and estimated start while it waits for a
+/// slot, and when it started and finished after that. Proofs are known by the id sent back in
+/// their `X-Proof-Id` header, their request id unless another proof was using it.
+#[get("/ns/<ns>/proof/<id>")]
+pub async fn ns_proof_status_handler(
+    scheduler: &rocket::State<Scheduler>,
+    ns: &str,
+    id: &str,
+    auth: Authorized<Read>,
+) -> Result<Json<ProofStatus>, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    scheduler
+        .status(ns, id)
+        .map(Json)
+        .ok_or_else(|| ProvingServerError::ProofNotFound { id: id.to_string() })
+}
+
+/// Jobs listed without a `limit`, and the most one request may list.
+const JOB_LIST_LIMIT: usize = 100;
+const JOB_LIST_MAX: usize = 1000;
+
+/// Lists the jobs of the provers in `ns` the caller can see, newest first, so operators can find
+/// out why a load failed or how long loads wait without reading logs.
+#[get("/ns/<ns>/jobs?<filter..>")]
+pub async fn ns_list_jobs_handler(
+    db: &rocket::State<Db>,
+    ns: &str,
+    filter: JobFilter,
+    auth: Authorized<Read>,
+) -> Result<Json<Vec<JobRecord>>, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let invalid = |message: String| ProvingServerError::InvalidJobFilter { message };
+    let status = match filter.status.as_deref() {
+        Some(status) => Some(
+            JobStatus::parse(status)
+                .ok_or_else(|| invalid(format!("unknown status {}", status)))?,
+        ),
+        None => None,
+    };
+    let kind = match filter.kind.as_deref() {
+        Some(kind) => {
+            Some(JobKind::parse(kind).ok_or_else(|| invalid(format!("unknown kind {}", kind)))?)
+        }
+        None => None,
+    };
+    let limit = filter.limit.unwrap_or(JOB_LIST_LIMIT);
+    if limit == 0 || limit > JOB_LIST_MAX {
+        return Err(invalid(format!(
+            "limit must be between 1 and {}",
+            JOB_LIST_MAX
+        )));
+    }
+    let principal = auth.principal();
+    let db = db.lock().await;
+    let provers: HashMap<i64, ProverConfig> = ProverConfig::list_in_namespace(ns, &db)?
+        .into_iter()
+        .filter(|prover| acl::visible_to(prover, principal.as_ref()))
+        .filter(|prover| {
+            filter
+                .prover
+                .as_ref()
+                .map_or(true, |name| &prover.name == name)
+        })
+        .filter(|prover| {
+            filter
+                .version
+                .as_ref()
+                .map_or(true, |version| &prover.version == version)
+        })
+        .filter_map(|prover| prover.id.map(|id| (id, prover)))
+        .collect();
+    let query = JobQuery {
+        provers: provers.keys().copied().collect(),
+        status,
+        kind,
+        since: filter.since,
+        until: filter.until,
+        limit,
+    };
+    let jobs = Job::list(&query, &db)?;
+    Ok(Json(
+        jobs.into_iter()
+            .map(|job| {
+                let prover = &provers[&job.prover];
+                JobRecord::new(job, prover)
+            })
+            .collect(),
+    ))
+}
+
+/// Cancels a load job that hasn't finished, aborting its downloads if a worker already took
+/// it. Loading the prover again takes a PATCH.
+#[delete("/job/<id>")]
+pub async fn cancel_job_handler(
+    db: &rocket::State<Db>,
+    queue: &rocket::State<JobQueue>,
+    id: i64,
+    auth: Authorized<ProverAdmin>,
+    request_id: RequestId,
+) -> Result<Json<JobResponse>, ProvingServerError> {
+    let db = db.lock().await;
+    let job = Job::get(id, &db)
+        .optional()?
+        .ok_or(ProvingServerError::JobNotFound { id })?;
+    let prover = ProverConfig::get(job.prover, &db)?;
+    auth.check_namespace(&prover.namespace)?;
+    acl::check(&prover, auth.principal().as_ref(), Permission::Update)?;
+    if !Job::cancel(id, &db)? {
+        return Err(ProvingServerError::JobFinished {
+            id,
+            status: job.status,
+        });
+    }
+    let job = Job::get(id, &db)?;
+    drop(db);
+    queue.cancel(id);
+    tracing::info!(
+        %request_id,
+        job_id = id,
+        namespace = %prover.namespace,
+        prover = %prover.name,
+        version = %prover.version,
+        "cancelled load job"
+    );
+    Ok(Json(JobResponse::from(job)))
+}
+
+#[post("/job/<id>/cancel")]
+pub async fn cancel_job_post_handler(
+    db: &rocket::State<Db>,
+    queue: &rocket::State<JobQueue>,
+    id: i64,
+    auth: Authorized<ProverAdmin>,
+    request_id: RequestId,
+) -> Result<Json<JobResponse>, ProvingServerError> {
+    cancel_job_handler(db, queue, id, auth, request_id).await
+}
+
+/// The verifying key a prover's proofs check against, for deploying verifier contracts.
+#[get("/ns/<ns>/vkey/<prover_name>/<prover_version>")]
+pub async fn ns_get_verifying_key(
+    db: &rocket::State<Db>,
+    prover_storage: &rocket::State<Provers>,
+    ns: &str,
+    prover_name: &str,
+    prover_version: &str,
+    auth: Authorized<Read>,
+) -> Result<WithVersion<Json<VerifyingKeyResponse>>, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let prover = alias::resolve(ns, prover_name, prover_version, &*db.lock().await)?;
+    if !acl::visible_to(&prover, auth.principal().as_ref()) {
+        return Err(ProvingServerError::ProverNotFound {
+            name: prover_name.to_string(),
+            version: prover_version.to_string(),
+        });
+    }
+    let key = match prover_storage.lock().await.get(&prover.key()) {
+        Some(loaded) => vk_to_eth_type(&loaded.params.vk),
+        None => {
+            return Err(ProvingServerError::ProverNotReady {
+                name: prover.name,
+                version: prover.version,
+            })
+        }
+    };
+    let version = prover.version.clone();
+    Ok(WithVersion(
+        Json(VerifyingKeyResponse {
+            namespace: prover.namespace,
+            name: prover.name,
+            version: prover.version,
+            key,
+        }),
+        version,
+    ))
+}
+
+/// Aliases of a prover name, leaving out those pointing at versions the caller can't see.
+#[get("/ns/<ns>/alias/<prover_name>")]
+pub async fn ns_list_aliases_handler(
+    db: &rocket::State<Db>,
+    ns: &str,
+    prover_name: &str,
+    auth: Authorized<Read>,
+) -> Result<Json<Vec<ProverAlias>>, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let principal = auth.principal();
     let db = db.lock().await;
-    let prover = prover.into_inner();
-    let mut p = ProverConfig::from(prover.clone());
-    p.create(&db).unwrap();
-
-    match Job::get_by_name_and_version(prover.name.clone(), prover.version.clone(), &db) {
-        Err(why) => {
-            //Doesn't exist
-            println!("{}", why);
-            let j = &mut Job {
-                id: None,
-                status: JobStatus::Pending,
-                prover: p.id.unwrap(),
-                message: format!("fetching deps for {}", p.name.clone()),
-            };
-            Job::create(j, &db).unwrap();
-            let id = j.id.unwrap();
-            queue.0.try_send(id).unwrap();
+    let mut aliases = vec![];
+    for alias in ProverAlias::list(ns, prover_name, &db)? {
+        let target = find_prover(ns, prover_name, &alias.version, &db)?;
+        if acl::visible_to(&target, principal.as_ref()) {
+            aliases.push(alias);
         }
-        Ok(_) => {}
     }
+    Ok(Json(aliases))
+}
 
-    Status::Ok
+/// Points an alias at a registered version, creating the alias if needed. Moving an alias
+/// takes `update` on the version it will point at.
+#[put(
+    "/ns/<ns>/alias/<prover_name>/<alias_name>",
+    format = "json",
+    data = "<target>"
+)]
+pub async fn ns_set_alias_handler(
+    db: &rocket::State<Db>,
+    ns: &str,
+    prover_name: &str,
+    alias_name: &str,
+    target: Json<AliasRequest>,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Json<ProverAlias>, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    validate_identifier("alias", alias_name)?;
+    let db = db.lock().await;
+    if find_prover(ns, prover_name, alias_name, &db).is_ok() {
+        return Err(ProvingServerError::InvalidProverConfig {
+            message: format!(
+                "{} is a version of {}, an alias can't shadow it",
+                alias_name, prover_name
+            ),
+        });
+    }
+    let prover = find_prover(ns, prover_name, &target.version, &db)?;
+    acl::check(&prover, auth.principal().as_ref(), Permission::Update)?;
+    let alias = ProverAlias {
+        namespace: prover.namespace,
+        name: prover.name,
+        alias: alias_name.to_string(),
+        version: prover.version,
+        updated_at: unix_now(),
+    };
+    alias.set(&db)?;
+    Ok(Json(alias))
+}
+
+/// Removes an alias, which takes `update` on the version it points at.
+#[delete("/ns/<ns>/alias/<prover_name>/<alias_name>")]
+pub async fn ns_delete_alias_handler(
+    db: &rocket::State<Db>,
+    ns: &str,
+    prover_name: &str,
+    alias_name: &str,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Status, ProvingServerError> {
+    auth.check_namespace(ns)?;
+    let db = db.lock().await;
+    let alias = ProverAlias::get(ns, prover_name, alias_name, &db)?.ok_or_else(|| {
+        ProvingServerError::ProverNotFound {
+            name: prover_name.to_string(),
+            version: alias_name.to_string(),
+        }
+    })?;
+    let prover = find_prover(ns, prover_name, &alias.version, &db)?;
+    acl::check(&prover, auth.principal().as_ref(), Permission::Update)?;
+    alias.delete(&db)?;
+    Ok(Status::NoContent)
+}
+
+/// Puts a registration in the route's namespace, refusing a body that names another one.
+fn in_namespace(
+    ns: &str,
+    mut prover: ProverConfigRequest,
+) -> Result<ProverConfigRequest, ProvingServerError> {
+    match &prover.namespace {
+        Some(namespace) if namespace != ns => Err(ProvingServerError::InvalidProverConfig {
+            message: format!("namespace {} doesn't match the route's {}", namespace, ns),
+        }),
+        _ => {
+            prover.namespace = Some(ns.to_string());
+            Ok(prover)
+        }
+    }
+}
+
+fn find_prover(
+    namespace: &str,
+    name: &str,
+    version: &str,
+    conn: &rusqlite::Connection,
+) -> Result<ProverConfig, ProvingServerError> {
+    ProverConfig::get_by_name_and_version(namespace, name.to_string(), version.to_string(), conn)
+        .optional()?
+        .ok_or_else(|| ProvingServerError::ProverNotFound {
+            name: name.to_string(),
+            version: version.to_string(),
+        })
+}
+
+/// Blobs of every namespace, so only server wide keys may see them.
+#[get("/admin/blobs")]
+pub async fn list_blobs_handler(
+    db: &rocket::State<Db>,
+    config: &rocket::State<Config>,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Json<Vec<BlobReport>>, ProvingServerError> {
+    auth.check_server_wide()?;
+    let config = config.lock().await.clone();
+    Ok(Json(report_all(&config, db).await?))
+}
+
+#[post("/admin/gc")]
+pub async fn gc_handler(
+    db: &rocket::State<Db>,
+    config: &rocket::State<Config>,
+    auth: Authorized<ProverAdmin>,
+) -> Result<Json<GcReport>, ProvingServerError> {
+    auth.check_server_wide()?;
+    let config = config.lock().await.clone();
+    Ok(Json(collect_all_garbage(&config, db, GC_GRACE).await?))
 }
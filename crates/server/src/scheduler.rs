@@ -0,0 +1,655 @@
+/// Fair scheduling of proof requests
+///
+/// Proofs take every core they get, so only `scheduler.slots` of them run at once and the rest
+/// wait for a slot. Each request carries a priority class, and waiting requests are served by
+/// stride scheduling over the classes, each getting slots in proportion to its weight, while the
+/// namespaces waiting in a class take turns, so one tenant's backfill can't crowd out another
+/// tenant. A proof is known within its namespace by its request id, or a fresh id when a proof
+/// of the namespace still queued or running already has that one, and the id is sent back in
+/// `X-Proof-Id`. `GET /v1/proof/<id>` looks it up for its place in the queue and an estimated
+/// start, worked out from how long the latest proofs of each prover held their slot.
+use crate::errors::ProvingServerError;
+use crate::logging::new_request_id;
+use crate::models::unix_now;
+use crate::types::reqres::ProofStatus;
+use rocket::http::Header;
+use rocket::response::{self, Responder};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::oneshot;
+
+pub const PROOF_ID_HEADER: &str = "X-Proof-Id";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Priority {
+    /// Someone is waiting on the proof, such as a game move
+    Interactive,
+    Normal,
+    /// Backfills and other work nobody waits on
+    Batch,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Interactive, Priority::Normal, Priority::Batch];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Normal => "normal",
+            Priority::Batch => "batch",
+        }
+    }
+
+    pub fn parse(priority: &str) -> Result<Priority, ProvingServerError> {
+        Priority::ALL
+            .iter()
+            .find(|p| p.as_str() == priority)
+            .copied()
+            .ok_or_else(|| ProvingServerError::InvalidPriority {
+                priority: priority.to_string(),
+            })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", default)]
+pub struct SchedulerConfig {
+    /// Proofs running at the same time
+    pub slots: usize,
+    /// Share of the slots each class gets while several are waiting
+    pub interactive_weight: u32,
+    pub normal_weight: u32,
+    pub batch_weight: u32,
+    /// Latest proofs of each prover that estimates are based on
+    pub duration_samples: usize,
+    /// Finished proofs that `GET /v1/proof/<id>` still reports
+    pub finished_kept: usize,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            slots: 2,
+            interactive_weight: 8,
+            normal_weight: 4,
+            batch_weight: 1,
+            duration_samples: 20,
+            finished_kept: 1000,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        let invalid = |message: &str| ProvingServerError::InvalidConfig {
+            message: String::from(message),
+        };
+        if self.slots == 0 {
+            return Err(invalid("scheduler.slots must be at least 1"));
+        }
+        if self.interactive_weight == 0 || self.normal_weight == 0 || self.batch_weight == 0 {
+            return Err(invalid(
+                "scheduler.interactive_weight, scheduler.normal_weight and scheduler.batch_weight must be at least 1",
+            ));
+        }
+        if self.duration_samples == 0 {
+            return Err(invalid("scheduler.duration_samples must be at least 1"));
+        }
+        Ok(())
+    }
+
+    pub fn weight(&self, priority: Priority) -> u32 {
+        match priority {
+            Priority::Interactive => self.interactive_weight,
+            Priority::Normal => self.normal_weight,
+            Priority::Batch => self.batch_weight,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ProofState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    /// The request went away before its proof started
+    Cancelled,
+}
+
+/// What the scheduler knows about a proof request.
+#[derive(Clone, Debug)]
+pub struct ProofInfo {
+    /// The request id, which `enqueue` replaces when it is taken
+    pub id: String,
+    pub namespace: String,
+    /// The prover's `namespace/name@version`
+    pub prover: String,
+    pub priority: Priority,
+}
+
+impl ProofInfo {
+    /// What the scheduler knows it by, ids only need to be unique within a namespace.
+    fn key(&self) -> String {
+        proof_key(&self.namespace, &self.id)
+    }
+}
+
+fn proof_key(namespace: &str, id: &str) -> String {
+    format!("{}/{}", namespace, id)
+}
+
+/// A response along with the id its proof can be looked up by.
+pub struct WithProofId<R>(pub R, pub String);
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for WithProofId<R> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.0.respond_to(req)?;
+        response.set_header(Header::new(PROOF_ID_HEADER, self.1));
+        Ok(response)
+    }
+}
+
+struct Proof {
+    info: ProofInfo,
+    state: ProofState,
+    queued_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    started: Option<Instant>,
+    /// Tells the waiting request that it has a slot
+    start: Option<oneshot::Sender<()>>,
+}
+
+/// Pass values are in units of this divided by a class's weight.
+const STRIDE: u64 = 1 << 20;
+
+/// Proofs of one priority class waiting for a slot.
+#[derive(Clone, Default)]
+struct Class {
+    /// Where the class is in the stride schedule, the class with the lowest goes next
+    pass: u64,
+    /// Namespaces in the order they take turns
+    turns: VecDeque<String>,
+    /// Keys of the waiting proofs by namespace
+    waiting: HashMap<String, VecDeque<String>>,
+}
+
+impl Class {
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+}
+
+/// The waiting proofs in the order they get slots.
+#[derive(Clone)]
+struct Schedule {
+    classes: [Class; 3],
+    /// Pass of the class served last, which classes that had nothing waiting catch up to
+    pass: u64,
+}
+
+impl Schedule {
+    fn push(&mut self, info: &ProofInfo) {
+        let class = &mut self.classes[info.priority as usize];
+        // a class that had nothing waiting doesn't get to make up for the turns it skipped
+        if class.is_empty() {
+            class.pass = class.pass.max(self.pass);
+        }
+        let waiting = class.waiting.entry(info.namespace.clone()).or_default();
+        if waiting.is_empty() {
+            class.turns.push_back(info.namespace.clone());
+        }
+        waiting.push_back(info.key());
+    }
+
+    fn remove(&mut self, info: &ProofInfo) {
+        let class = &mut self.classes[info.priority as usize];
+        if let Some(waiting) = class.waiting.get_mut(&info.namespace) {
+            let key = info.key();
+            waiting.retain(|waiting| waiting != &key);
+            if waiting.is_empty() {
+                class.waiting.remove(&info.namespace);
+                class.turns.retain(|namespace| namespace != &info.namespace);
+            }
+        }
+    }
+
+    fn next(&mut self, config: &SchedulerConfig) -> Option<String> {
+        let priority = Priority::ALL
+            .iter()
+            .filter(|p| !self.classes[**p as usize].is_empty())
+            .min_by_key(|p| (self.classes[**p as usize].pass, **p))
+            .copied()?;
+        let class = &mut self.classes[priority as usize];
+        let namespace = class.turns.pop_front()?;
+        let waiting = class.waiting.get_mut(&namespace)?;
+        let id = waiting.pop_front()?;
+        if waiting.is_empty() {
+            class.waiting.remove(&namespace);
+        } else {
+            class.turns.push_back(namespace);
+        }
+        self.pass = class.pass;
+        class.pass += STRIDE / config.weight(priority) as u64;
+        Some(id)
+    }
+
+    /// Every waiting proof, next one first.
+    fn order(&self, config: &SchedulerConfig) -> Vec<String> {
+        let mut schedule = self.clone();
+        std::iter::from_fn(|| schedule.next(config)).collect()
+    }
+}
+
+struct SchedulerState {
+    config: SchedulerConfig,
+    free: usize,
+    schedule: Schedule,
+    proofs: HashMap<String, Proof>,
+    /// Keys of finished proofs, oldest first, forgotten past `finished_kept`
+    finished: VecDeque<String>,
+    /// Seconds the latest proofs of each prover held their slot
+    durations: HashMap<String, VecDeque<f64>>,
+}
+
+/// Hands out proving slots. Cloning shares it.
+#[derive(Clone)]
+pub struct Scheduler(Arc<Mutex<SchedulerState>>);
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Scheduler(Arc::new(Mutex::new(SchedulerState {
+            free: config.slots,
+            config,
+            schedule: Schedule {
+                classes: Default::default(),
+                pass: 0,
+            },
+            proofs: HashMap::new(),
+            finished: VecDeque::new(),
+            durations: HashMap::new(),
+        })))
+    }
+
+    /// Average seconds a proof of `prover` holds its slot, once any proof has finished.
+    pub fn expected_seconds(&self, prover: &str) -> Option<f64> {
+        self.0.lock().unwrap().estimate(prover)
+    }
+
+    /// Queues a proof for a slot. A proof of the same namespace still queued or running under
+    /// its id, such as a retry, gets it a fresh id, see `Ticket::id`. Ids of finished proofs
+    /// are used again.
+    pub fn enqueue(&self, mut info: ProofInfo) -> Ticket {
+        let mut state = self.0.lock().unwrap();
+        while let Some(proof) = state.proofs.get(&info.key()) {
+            if matches!(proof.state, ProofState::Queued | ProofState::Running) {
+                info.id = new_request_id().0;
+                continue;
+            }
+            let key = info.key();
+            state.finished.retain(|finished| finished != &key);
+            break;
+        }
+        let (start, started) = oneshot::channel();
+        state.schedule.push(&info);
+        let key = info.key();
+        let id = info.id.clone();
+        state.proofs.insert(
+            key.clone(),
+            Proof {
+                info,
+                state: ProofState::Queued,
+                queued_at: unix_now(),
+                started_at: None,
+                finished_at: None,
+                started: None,
+                start: Some(start),
+            },
+        );
+        state.dispatch();
+        Ticket {
+            scheduler: self.clone(),
+            id,
+            key,
+            started: Some(started),
+        }
+    }
+
+    /// Where the proof is, none once it has been forgotten.
+    pub fn status(&self, namespace: &str, id: &str) -> Option<ProofStatus> {
+        let state = self.0.lock().unwrap();
+        let key = proof_key(namespace, id);
+        let proof = state.proofs.get(&key)?;
+        let (position, estimated_wait_secs) = match proof.state {
+            ProofState::Queued => {
+                let order = state.schedule.order(&state.config);
+                let position = order.iter().position(|queued| queued == &key);
+                let wait = position.and_then(|position| state.estimate_wait(&order[..position]));
+                (position, wait)
+            }
+            _ => (None, None),
+        };
+        Some(ProofStatus {
+            id: proof.info.id.clone(),
+            namespace: proof.info.namespace.clone(),
+            prover: proof.info.prover.clone(),
+            priority: proof.info.priority,
+            state: proof.state,
+            position,
+            queued_at: proof.queued_at,
+            started_at: proof.started_at,
+            finished_at: proof.finished_at,
+            estimated_start_at: estimated_wait_secs.map(|wait| unix_now() + wait.ceil() as i64),
+            estimated_wait_secs,
+        })
+    }
+
+    fn finish(&self, key: &str, outcome: ProofState) {
+        let mut state = self.0.lock().unwrap();
+        let proof = match state.proofs.get_mut(key) {
+            Some(proof) if matches!(proof.state, ProofState::Queued | ProofState::Running) => proof,
+            _ => return,
+        };
+        let previous = proof.state;
+        proof.state = outcome;
+        proof.finished_at = Some(unix_now());
+        let took = proof.started.map(|started| started.elapsed().as_secs_f64());
+        let info = proof.info.clone();
+        if previous == ProofState::Queued {
+            state.schedule.remove(&info);
+        } else {
+            state.free += 1;
+            if let (ProofState::Done, Some(took)) = (outcome, took) {
+                state.record(&info.prover, took);
+            }
+        }
+        state.finished.push_back(info.key());
+        while state.finished.len() > state.config.finished_kept {
+            if let Some(forgotten) = state.finished.pop_front() {
+                state.proofs.remove(&forgotten);
+            }
+        }
+        state.dispatch();
+    }
+}
+
+impl SchedulerState {
+    /// Starts waiting proofs while there are free slots.
+    fn dispatch(&mut self) {
+        while self.free > 0 {
+            let key = match self.schedule.next(&self.config) {
+                Some(key) => key,
+                None => return,
+            };
+            let proof = match self.proofs.get_mut(&key) {
+                Some(proof) => proof,
+                None => continue,
+            };
+            proof.state = ProofState::Running;
+            proof.started_at = Some(unix_now());
+            proof.started = Some(Instant::now());
+            if let Some(start) = proof.start.take() {
+                // a request that went away gives its slot back when its ticket is dropped
+                start.send(()).ok();
+            }
+            self.free -= 1;
+        }
+    }
+
+    fn record(&mut self, prover: &str, seconds: f64) {
+        let samples = self.durations.entry(prover.to_string()).or_default();
+        samples.push_back(seconds);
+        while samples.len() > self.config.duration_samples {
+            samples.pop_front();
+        }
+    }
+
+    /// Average seconds a proof of `prover` holds its slot, from all provers' proofs when it
+    /// has none of its own yet.
+    fn estimate(&self, prover: &str) -> Option<f64> {
+        let mean = |samples: Vec<f64>| match samples.len() {
+            0 => None,
+            n => Some(samples.iter().sum::<f64>() / n as f64),
+        };
+        self.durations
+            .get(prover)
+            .and_then(|samples| mean(samples.iter().copied().collect()))
+            .or_else(|| mean(self.durations.values().flatten().copied().collect()))
+    }
+
+    /// Seconds until a proof waiting behind `ahead` gets a slot, if the scheduler has seen
+    /// enough proofs to tell.
+    fn estimate_wait(&self, ahead: &[String]) -> Option<f64> {
+        // seconds until each slot is free
+        let mut slots = vec![0.0; self.free];
+        for proof in self.proofs.values() {
+            if let (ProofState::Running, Some(started)) = (proof.state, proof.started) {
+                let left = self.estimate(&proof.info.prover)? - started.elapsed().as_secs_f64();
+                slots.push(left.max(0.0));
+            }
+        }
+        for key in ahead {
+            let took = self.estimate(&self.proofs.get(key)?.info.prover)?;
+            let first = slots.iter_mut().min_by(|a, b| a.partial_cmp(b).unwrap())?;
+            *first += took;
+        }
+        slots.into_iter().reduce(f64::min)
+    }
+}
+
+/// A queued proof. Dropping it before it starts takes it out of the queue.
+pub struct Ticket {
+    scheduler: Scheduler,
+    id: String,
+    key: String,
+    started: Option<oneshot::Receiver<()>>,
+}
+
+impl Ticket {
+    /// The id the proof is known by in its namespace.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Waits for a slot.
+    pub async fn start(mut self) -> Slot {
+        if let Some(started) = self.started.take() {
+            // only dropped after sending
+            started.await.ok();
+        }
+        Slot {
+            scheduler: self.scheduler.clone(),
+            key: std::mem::take(&mut self.key),
+            outcome: ProofState::Failed,
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if !self.key.is_empty() {
+            self.scheduler.finish(&self.key, ProofState::Cancelled);
+        }
+    }
+}
+
+/// A running proof, holding its slot until it is dropped. Proofs that don't call `done`,
+/// because they failed or panicked, are recorded as failed.
+pub struct Slot {
+    scheduler: Scheduler,
+    key: String,
+    outcome: ProofState,
+}
+
+impl Slot {
+    /// Gives the slot back, counting the proof towards the estimates.
+    pub fn done(mut self) {
+        self.outcome = ProofState::Done;
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.scheduler.finish(&self.key, self.outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(slots: usize) -> SchedulerConfig {
+        SchedulerConfig {
+            slots,
+            interactive_weight: 2,
+            normal_weight: 1,
+            batch_weight: 1,
+            ..Default::default()
+        }
+    }
+
+    fn proof(id: &str, namespace: &str, priority: Priority) -> ProofInfo {
+        ProofInfo {
+            id: String::from(id),
+            namespace: String::from(namespace),
+            prover: format!("{}/move@0.0.1", namespace),
+            priority,
+        }
+    }
+
+    /// Ids of the waiting proofs, next one first.
+    fn order(scheduler: &Scheduler) -> Vec<String> {
+        let state = scheduler.0.lock().unwrap();
+        let order = state.schedule.order(&state.config);
+        order
+            .iter()
+            .map(|key| state.proofs[key].info.id.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn unit_classes_share_slots_by_weight_and_tenants_take_turns() {
+        let scheduler = Scheduler::new(config(1));
+        let running = scheduler
+            .enqueue(proof("running", "a", Priority::Batch))
+            .start()
+            .await;
+        let mut tickets = vec![];
+        for (id, namespace, priority) in [
+            ("backfill-1", "a", Priority::Batch),
+            ("backfill-2", "a", Priority::Batch),
+            ("move-a1", "a", Priority::Interactive),
+            ("move-a2", "a", Priority::Interactive),
+            ("move-a3", "a", Priority::Interactive),
+            ("move-b1", "b", Priority::Interactive),
+            ("report", "b", Priority::Normal),
+        ] {
+            tickets.push(scheduler.enqueue(proof(id, namespace, priority)));
+        }
+        // ids are only taken within a namespace, and a retry gets a fresh one
+        let other = scheduler.enqueue(proof("report", "a", Priority::Batch));
+        assert_eq!(other.id(), "report");
+        drop(other);
+        let retry = scheduler.enqueue(proof("move-b1", "b", Priority::Batch));
+        assert_ne!(retry.id(), "move-b1");
+        drop(retry);
+
+        // interactive proofs get two turns for every one of the other classes, batch already
+        // had one, and b's move doesn't wait behind every move a queued first
+        assert_eq!(
+            order(&scheduler),
+            vec![
+                "move-a1",
+                "report",
+                "move-b1",
+                "move-a2",
+                "backfill-1",
+                "move-a3",
+                "backfill-2"
+            ]
+        );
+        let status = scheduler.status("b", "report").unwrap();
+        assert_eq!(status.state, ProofState::Queued);
+        assert_eq!(status.position, Some(1));
+        // nothing finished yet to estimate from
+        assert_eq!(status.estimated_wait_secs, None);
+
+        // a request that goes away leaves the queue
+        let report = tickets.pop().unwrap();
+        drop(report);
+        assert_eq!(
+            scheduler.status("b", "report").unwrap().state,
+            ProofState::Cancelled
+        );
+        assert!(!order(&scheduler).contains(&String::from("report")));
+
+        running.done();
+        let next = tickets.remove(2);
+        let slot = tokio::time::timeout(Duration::from_secs(1), next.start())
+            .await
+            .unwrap();
+        assert_eq!(
+            scheduler.status("a", "move-a1").unwrap().state,
+            ProofState::Running
+        );
+        assert_eq!(
+            scheduler.status("a", "running").unwrap().state,
+            ProofState::Done
+        );
+        // a slot dropped without `done` is a failed proof
+        drop(slot);
+        assert_eq!(
+            scheduler.status("a", "move-a1").unwrap().state,
+            ProofState::Failed
+        );
+    }
+
+    #[tokio::test]
+    async fn unit_start_is_estimated_from_recent_durations() {
+        let scheduler = Scheduler::new(SchedulerConfig {
+            duration_samples: 2,
+            ..config(2)
+        });
+        {
+            let mut state = scheduler.0.lock().unwrap();
+            for seconds in [100.0, 10.0, 20.0] {
+                state.record("a/move@0.0.1", seconds);
+            }
+            state.record("b/move@0.0.1", 60.0);
+        }
+        let _first = scheduler
+            .enqueue(proof("first", "a", Priority::Normal))
+            .start()
+            .await;
+        let _second = scheduler
+            .enqueue(proof("second", "b", Priority::Normal))
+            .start()
+            .await;
+        let _third = scheduler.enqueue(proof("third", "a", Priority::Normal));
+        let _fourth = scheduler.enqueue(proof("fourth", "b", Priority::Normal));
+
+        // a's proofs take 15s on average of the latest two, so `third` starts when `first`
+        // is done and `fourth` once `third` is, before `second` frees its slot
+        let third = scheduler.status("a", "third").unwrap();
+        assert_eq!(third.position, Some(0));
+        assert!((third.estimated_wait_secs.unwrap() - 15.0).abs() < 1.0);
+        let fourth = scheduler.status("b", "fourth").unwrap();
+        assert_eq!(fourth.position, Some(1));
+        assert!((fourth.estimated_wait_secs.unwrap() - 30.0).abs() < 1.0);
+        assert!(fourth.estimated_start_at.unwrap() >= unix_now() + 29);
+        assert!(scheduler.status("a", "missing").is_none());
+    }
+}
@@ -0,0 +1,233 @@
+/// Graceful shutdown on SIGTERM or ctrl-c
+///
+/// Once a signal arrives the server drains: new proof requests get a 503, readiness turns false
+/// and the worker stops taking jobs, while proofs and the load job already running get until
+/// `shutdown.deadline_secs` to finish. Load jobs that haven't finished by then, including those
+/// still waiting in the queue, are marked `Interrupted` in the job table, and the next boot
+/// queues them again.
+use crate::errors::ProvingServerError;
+use crate::models::{Job, JobStatus};
+use crate::queue::JobQueue;
+use crate::types::{Config, Db};
+use rocket::fairing;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Build, Orbit, Rocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ShutdownConfig {
+    /// How long in-flight proofs and load jobs get to finish after a signal
+    pub deadline_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { deadline_secs: 30 }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn validate(&self) -> Result<(), ProvingServerError> {
+        if self.deadline_secs == 0 {
+            return Err(ProvingServerError::InvalidConfig {
+                message: String::from("shutdown.deadline_secs must be above 0"),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct DrainState {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+/// Whether the server is draining, and how much work is still in flight. Cloning shares it.
+#[derive(Clone, Default)]
+pub struct Drain(Arc<DrainState>);
+
+/// Counts as in flight until it is dropped.
+pub struct InFlight(Drain);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        (self.0).0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drain {
+    /// Tracks new work, refusing it once the server is draining.
+    pub fn admit(&self) -> Result<InFlight, ProvingServerError> {
+        if self.is_draining() {
+            return Err(ProvingServerError::ShuttingDown);
+        }
+        Ok(self.track())
+    }
+
+    /// Tracks work that was already accepted.
+    pub fn track(&self) -> InFlight {
+        self.0.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.0.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Stops admitting work and waits up to `deadline` for what's in flight. Returns what was
+    /// still running when it gave up.
+    pub async fn drain(&self, deadline: Duration) -> usize {
+        self.0.draining.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        while self.in_flight() > 0 && started.elapsed() < deadline {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        self.in_flight()
+    }
+}
+
+/// Drains, then checkpoints the load jobs that didn't finish.
+pub async fn drain_and_checkpoint(
+    drain: &Drain,
+    db: &Db,
+    deadline: Duration,
+) -> Result<usize, ProvingServerError> {
+    tracing::info!(deadline_secs = deadline.as_secs(), "draining");
+    let abandoned = drain.drain(deadline).await;
+    if abandoned > 0 {
+        tracing::warn!(in_flight = abandoned, "shutdown deadline passed");
+    }
+    let interrupted = Job::interrupt_unfinished(&*db.lock().await)?;
+    tracing::info!(interrupted, "checkpointed load jobs");
+    Ok(interrupted)
+}
+
+#[cfg(unix)]
+async fn signalled() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn signalled() {
+    tokio::signal::ctrl_c().await.ok();
+}
+
+/// Liftoff fairing that drains and shuts the server down when it is signalled. Rocket's own
+/// ctrl-c handling is turned off so it can't cut the drain short.
+pub async fn drain_on_signal(rocket: &Rocket<Orbit>) {
+    let drain = rocket.state::<Drain>().unwrap().clone();
+    let db = rocket.state::<Db>().unwrap().clone();
+    let config = rocket.state::<Config>().unwrap().clone();
+    let shutdown = rocket.shutdown();
+    tokio::spawn(async move {
+        signalled().await;
+        let deadline = Duration::from_secs(config.lock().await.shutdown.deadline_secs);
+        if let Err(error) = drain_and_checkpoint(&drain, &db, deadline).await {
+            tracing::error!(%error, "could not checkpoint load jobs");
+        }
+        shutdown.notify();
+    });
+}
+
+/// Ignite fairing that queues the jobs the last shutdown interrupted, in their old order.
+pub async fn resume_at_boot(rocket: Rocket<Build>) -> fairing::Result {
+    let db = rocket.state::<Db>().unwrap().clone();
+    let queue = rocket.state::<JobQueue>().unwrap().clone();
+    let resumed = match Job::resume_interrupted(&*db.lock().await) {
+        Ok(resumed) => resumed,
+        Err(error) => {
+            tracing::error!(%error, "could not resume interrupted load jobs");
+            return Err(rocket);
+        }
+    };
+    if !resumed.is_empty() {
+        tracing::info!(jobs = ?resumed, "resuming interrupted load jobs");
+        queue.wake();
+    }
+    Ok(rocket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Crud, ProverConfig};
+    use crate::test::fixtures;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn unit_drain_interrupts_unfinished_jobs() {
+        let db: Db = Arc::new(Mutex::new(fixtures::setup_db().await));
+        let conn = db.lock().await;
+        let mut jobs = vec![];
+        for (version, status) in [
+            ("0.0.1", JobStatus::Processing),
+            ("0.0.2", JobStatus::Pending),
+            ("0.0.3", JobStatus::Ready),
+            ("0.0.4", JobStatus::Failed),
+        ] {
+            let mut prover: ProverConfig = fixtures::df_prover_config();
+            prover.version = String::from(version);
+            prover.create(&conn).unwrap();
+            let id = Job::queue_for_prover(&prover, String::from("loading"), &conn).unwrap();
+            let mut job = Job::get(id, &conn).unwrap();
+            job.status = status;
+            job.update(&conn).unwrap();
+            jobs.push(id);
+        }
+        drop(conn);
+
+        let drain = Drain::default();
+        let proof = drain.admit().unwrap();
+        let waiting = {
+            let drain = drain.clone();
+            tokio::spawn(async move { drain.drain(Duration::from_secs(5)).await })
+        };
+        tokio::time::sleep(POLL_INTERVAL).await;
+        assert!(drain.is_draining());
+        assert!(matches!(
+            drain.admit(),
+            Err(ProvingServerError::ShuttingDown)
+        ));
+        drop(proof);
+        assert_eq!(waiting.await.unwrap(), 0);
+
+        let _stuck = drain.track();
+        let interrupted = drain_and_checkpoint(&drain, &db, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(interrupted, 2);
+
+        let conn = db.lock().await;
+        let statuses: Vec<JobStatus> = jobs
+            .iter()
+            .map(|id| Job::get(*id, &conn).unwrap().status)
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                JobStatus::Interrupted,
+                JobStatus::Interrupted,
+                JobStatus::Ready,
+                JobStatus::Failed
+            ]
+        );
+        assert_eq!(Job::resume_interrupted(&conn).unwrap(), jobs[..2]);
+        assert_eq!(Job::get(jobs[0], &conn).unwrap().status, JobStatus::Pending);
+        assert!(Job::resume_interrupted(&conn).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,369 @@
+/// Proof requests signed with an Ethereum key, as an alternative to api keys
+///
+/// The client signs an EIP-191 personal message naming the prover, the keccak256 of its inputs,
+/// a nonce and an expiry, and sends the signature in the `X-Proof-Signature`, `X-Proof-Nonce`
+/// and `X-Proof-Expires` headers. The signer is recovered from the signature and must be on the
+/// prover's `signers` list. Nonces are remembered until they expire so a request can't be
+/// replayed. Nothing here needs chain access.
+use crate::acl::{self, Permission, Principal};
+use crate::auth::{Authorized, Prove};
+use crate::errors::ProvingServerError;
+use crate::models::{ProverConfig, SignatureNonce};
+use crate::types::reqres::ProofRequest;
+use ethers::types::{Address, Signature};
+use ethers::utils::keccak256;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use zeroize::Zeroize;
+
+pub const SIGNATURE_HEADER: &str = "X-Proof-Signature";
+pub const NONCE_HEADER: &str = "X-Proof-Nonce";
+pub const EXPIRES_HEADER: &str = "X-Proof-Expires";
+
+const MAX_NONCE_LEN: usize = 64;
+
+fn unauthorized(message: String) -> ProvingServerError {
+    ProvingServerError::Unauthorized { message }
+}
+
+/// Parses a hex address, returning it in the lowercase form provers store.
+pub fn parse_address(address: &str) -> Result<String, ProvingServerError> {
+    address
+        .parse::<Address>()
+        .map(|address| format!("{:?}", address))
+        .map_err(|_| ProvingServerError::InvalidProverConfig {
+            message: format!("{:?} is not an ethereum address", address),
+        })
+}
+
+/// keccak256 of the inputs as JSON with sorted keys and no whitespace, e.g.
+/// `{"r":8000,"x1":100}`, as a 0x prefixed hex string.
+pub fn inputs_hash(inputs: &ProofRequest) -> String {
+    let sorted: BTreeMap<&String, &u64> = inputs.iter().collect();
+    let mut json = rocket::serde::json::to_string(&sorted).unwrap();
+    let hash = keccak256(json.as_bytes());
+    json.zeroize();
+    format!("0x{}", hex::encode(hash))
+}
+
+/// The text a client signs with `personal_sign`. The prover is named with its namespace and
+/// the version as requested, e.g. `default/move@latest`, so a signature can't be replayed
+/// against another tenant's prover.
+pub fn signing_message(
+    prover: &ProverConfig,
+    version: &str,
+    inputs: &ProofRequest,
+    nonce: &str,
+    expires: i64,
+) -> String {
+    format!(
+        "proving-server proof request\nprover: {}/{}@{}\ninputs: {}\nnonce: {}\nexpires: {}",
+        prover.namespace,
+        prover.name,
+        version,
+        inputs_hash(inputs),
+        nonce,
+        expires
+    )
+}
+
+/// The signature headers of a request, checked against the body by `verify`.
+#[derive(Debug, Clone)]
+pub struct SignedRequest {
+    pub signature: Signature,
+    pub nonce: String,
+    /// Seconds since the unix epoch
+    pub expires: i64,
+}
+
+impl SignedRequest {
+    pub fn from_headers(
+        signature: &str,
+        nonce: Option<&str>,
+        expires: Option<&str>,
+    ) -> Result<SignedRequest, ProvingServerError> {
+        let signature = signature
+            .parse::<Signature>()
+            .map_err(|_| unauthorized(format!("{} is not a valid signature", SIGNATURE_HEADER)))?;
+        let nonce = nonce
+            .ok_or_else(|| unauthorized(format!("{} is required", NONCE_HEADER)))?
+            .to_string();
+        if nonce.is_empty()
+            || nonce.len() > MAX_NONCE_LEN
+            || !nonce
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(unauthorized(format!(
+                "{} must be 1 to {} letters, digits, - or _",
+                NONCE_HEADER, MAX_NONCE_LEN
+            )));
+        }
+        let expires = expires
+            .ok_or_else(|| unauthorized(format!("{} is required", EXPIRES_HEADER)))?
+            .parse::<i64>()
+            .map_err(|_| unauthorized(format!("{} must be a unix timestamp", EXPIRES_HEADER)))?;
+        Ok(SignedRequest {
+            signature,
+            nonce,
+            expires,
+        })
+    }
+}
+
+/// Checks that `signed` covers this prover, requested as `version`, and these inputs, was made
+/// by one of the prover's signers, its owner or an address granted `prove`, and hasn't been
+/// seen before. Returns the signer's address.
+pub fn verify(
+    signed: &SignedRequest,
+    prover: &ProverConfig,
+    version: &str,
+    inputs: &ProofRequest,
+    max_ttl_secs: u64,
+    now: i64,
+    conn: &Connection,
+) -> Result<String, ProvingServerError> {
+    if signed.expires <= now {
+        return Err(unauthorized(String::from("the signature has expired")));
+    }
+    if signed.expires > now + max_ttl_secs as i64 {
+        return Err(unauthorized(format!(
+            "{} may be at most {} seconds in the future",
+            EXPIRES_HEADER, max_ttl_secs
+        )));
+    }
+    let message = signing_message(prover, version, inputs, &signed.nonce, signed.expires);
+    let signer = signed
+        .signature
+        .recover(message)
+        .map_err(|e| unauthorized(e.to_string()))?;
+    let signer = format!("{:?}", signer);
+    // a signature over different inputs recovers some unrelated address, so it ends up here too
+    if !prover.signers.contains(&signer)
+        && !acl::grants(
+            prover,
+            &Principal::Address(signer.clone()),
+            Permission::Prove,
+        )
+    {
+        return Err(ProvingServerError::Forbidden {
+            message: format!("{} may not prove with {}", signer, prover.label()),
+        });
+    }
+    let nonce = SignatureNonce {
+        signer: signer.clone(),
+        nonce: signed.nonce.clone(),
+        expires_at: signed.expires,
+    };
+    if !nonce.claim(now, conn)? {
+        return Err(unauthorized(format!(
+            "nonce {} was already used",
+            signed.nonce
+        )));
+    }
+    Ok(signer)
+}
+
+/// Credentials for a proof request: a signature when `X-Proof-Signature` is sent, otherwise an
+/// api key with the `prove` scope.
+pub enum ProofCredential {
+    ApiKey(Authorized<Prove>),
+    Signed(SignedRequest),
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ProofCredential {
+    type Error = ProvingServerError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let signature = match headers.get_one(SIGNATURE_HEADER) {
+            Some(signature) => signature,
+            None => {
+                return Authorized::<Prove>::from_request(request)
+                    .await
+                    .map(ProofCredential::ApiKey)
+            }
+        };
+        match SignedRequest::from_headers(
+            signature,
+            headers.get_one(NONCE_HEADER),
+            headers.get_one(EXPIRES_HEADER),
+        ) {
+            Ok(signed) => Outcome::Success(ProofCredential::Signed(signed)),
+            Err(e) => Outcome::Failure((Status::Unauthorized, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::fixtures;
+    use ethers::signers::{LocalWallet, Signer};
+
+    // the first two well known hardhat development accounts
+    const ALLOWED_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const ALLOWED_ADDRESS: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+    const OTHER_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    const NOW: i64 = 1_700_000_000;
+
+    async fn sign(
+        key: &str,
+        prover: &ProverConfig,
+        inputs: &ProofRequest,
+        nonce: &str,
+        expires: i64,
+    ) -> SignedRequest {
+        let wallet = key.parse::<LocalWallet>().unwrap();
+        let message = signing_message(prover, &prover.version, inputs, nonce, expires);
+        SignedRequest {
+            signature: wallet.sign_message(message).await.unwrap(),
+            nonce: nonce.to_string(),
+            expires,
+        }
+    }
+
+    fn prover() -> ProverConfig {
+        let mut prover = fixtures::df_prover_config();
+        prover.signers = vec![String::from(ALLOWED_ADDRESS)];
+        prover
+    }
+
+    #[test]
+    fn unit_inputs_hash_ignores_key_order() {
+        let inputs = fixtures::df_proof_request();
+        let mut reordered = std::collections::HashMap::new();
+        let mut keys: Vec<&String> = inputs.keys().collect();
+        keys.reverse();
+        for key in keys {
+            reordered.insert(key.clone(), inputs[key]);
+        }
+        let hash = inputs_hash(&ProofRequest::from(inputs.clone()));
+        assert_eq!(hash, inputs_hash(&ProofRequest::from(reordered.clone())));
+        reordered.insert(String::from("x1"), 101);
+        assert_ne!(hash, inputs_hash(&ProofRequest::from(reordered)));
+    }
+
+    #[test]
+    fn unit_parse_address() {
+        let wallet = ALLOWED_KEY.parse::<LocalWallet>().unwrap();
+        assert_eq!(format!("{:?}", wallet.address()), ALLOWED_ADDRESS);
+        assert_eq!(
+            parse_address("0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266").unwrap(),
+            ALLOWED_ADDRESS
+        );
+        assert!(parse_address("0xf39f").is_err());
+    }
+
+    #[test]
+    fn unit_headers_are_checked() {
+        let signature = format!("0x{}", "11".repeat(65));
+        assert!(SignedRequest::from_headers(&signature, Some("n-1"), Some("1700000000")).is_ok());
+        assert!(SignedRequest::from_headers("0x1234", Some("n-1"), Some("1700000000")).is_err());
+        assert!(SignedRequest::from_headers(&signature, None, Some("1700000000")).is_err());
+        assert!(SignedRequest::from_headers(&signature, Some("a\nb"), Some("1700000000")).is_err());
+        assert!(SignedRequest::from_headers(&signature, Some("n-1"), Some("soon")).is_err());
+    }
+
+    #[tokio::test]
+    async fn unit_verify() {
+        let conn = fixtures::setup_db().await;
+        let prover = prover();
+        let inputs = fixtures::df_proof_inputs();
+
+        let signed = sign(ALLOWED_KEY, &prover, &inputs, "first", NOW + 60).await;
+        assert_eq!(
+            verify(&signed, &prover, &prover.version, &inputs, 300, NOW, &conn).unwrap(),
+            ALLOWED_ADDRESS
+        );
+        // replayed
+        assert!(matches!(
+            verify(&signed, &prover, &prover.version, &inputs, 300, NOW, &conn),
+            Err(ProvingServerError::Unauthorized { .. })
+        ));
+
+        let other = sign(OTHER_KEY, &prover, &inputs, "second", NOW + 60).await;
+        assert!(matches!(
+            verify(&other, &prover, &prover.version, &inputs, 300, NOW, &conn),
+            Err(ProvingServerError::Forbidden { .. })
+        ));
+
+        let mut tampered = fixtures::df_proof_request();
+        tampered.insert(String::from("x1"), 101);
+        let tampered = ProofRequest::from(tampered);
+        let signed = sign(ALLOWED_KEY, &prover, &inputs, "third", NOW + 60).await;
+        assert!(matches!(
+            verify(
+                &signed,
+                &prover,
+                &prover.version,
+                &tampered,
+                300,
+                NOW,
+                &conn
+            ),
+            Err(ProvingServerError::Forbidden { .. })
+        ));
+
+        let expired = sign(ALLOWED_KEY, &prover, &inputs, "fourth", NOW - 1).await;
+        let too_far = sign(ALLOWED_KEY, &prover, &inputs, "fifth", NOW + 3600).await;
+        for signed in [expired, too_far] {
+            assert!(matches!(
+                verify(&signed, &prover, &prover.version, &inputs, 300, NOW, &conn),
+                Err(ProvingServerError::Unauthorized { .. })
+            ));
+        }
+
+        // an owning address doesn't need to be on the signers list
+        let mut owned = prover.clone();
+        owned.signers = vec![];
+        owned.owner = Some(String::from(ALLOWED_ADDRESS));
+        let signed = sign(ALLOWED_KEY, &owned, &inputs, "seventh", NOW + 60).await;
+        assert!(verify(&signed, &owned, &owned.version, &inputs, 300, NOW, &conn).is_ok());
+
+        // a signature over an alias only counts for requests naming that alias
+        let wallet = ALLOWED_KEY.parse::<LocalWallet>().unwrap();
+        let message = signing_message(&prover, "latest", &inputs, "eighth", NOW + 60);
+        let aliased = SignedRequest {
+            signature: wallet.sign_message(message).await.unwrap(),
+            nonce: String::from("eighth"),
+            expires: NOW + 60,
+        };
+        assert!(verify(&aliased, &prover, &prover.version, &inputs, 300, NOW, &conn).is_err());
+        assert!(verify(&aliased, &prover, "latest", &inputs, 300, NOW, &conn).is_ok());
+
+        // the same prover name in another namespace doesn't accept it
+        let mut elsewhere = prover.clone();
+        elsewhere.namespace = String::from("team-a");
+        let signed = sign(ALLOWED_KEY, &prover, &inputs, "sixth", NOW + 60).await;
+        assert!(matches!(
+            verify(
+                &signed,
+                &elsewhere,
+                &elsewhere.version,
+                &inputs,
+                300,
+                NOW,
+                &conn
+            ),
+            Err(ProvingServerError::Forbidden { .. })
+        ));
+
+        // the nonce is forgotten once it has expired, by which time the request is refused anyway
+        let signed = sign(ALLOWED_KEY, &prover, &inputs, "first", NOW + 400).await;
+        assert!(verify(
+            &signed,
+            &prover,
+            &prover.version,
+            &inputs,
+            300,
+            NOW + 200,
+            &conn
+        )
+        .is_ok());
+    }
+}
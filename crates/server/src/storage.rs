@@ -0,0 +1,641 @@
+/// Content addressed artifact storage underneath `ZK_FILE_PATH`
+use crate::errors::ProvingServerError;
+use crate::models::{ProverConfig, DEFAULT_NAMESPACE};
+use crate::types::reqres::{BlobReference, BlobReport, GcReport};
+use crate::types::{Db, EnvConfig};
+use crate::utils::files::create_dir;
+use filetime::FileTime;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+const MAX_IDENTIFIER_LEN: usize = 64;
+const BLOB_DIR: &str = "blobs/sha256";
+const TMP_DIR: &str = "tmp";
+/// Blobs and partial downloads younger than this are left alone by gc, they may belong to a
+/// load job that hasn't recorded its hashes yet.
+pub const GC_GRACE: Duration = Duration::from_secs(10 * 60);
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// An in progress download under `<root>/tmp`, removed when dropped unless it was committed
+/// first. Downloads that fail or are cancelled part way don't leave partial files behind.
+#[derive(Debug)]
+pub struct TempFile(PathBuf);
+
+impl std::ops::Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // gone already once it was committed
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Names and versions end up as path segments, so they are limited to `[A-Za-z0-9._-]`,
+/// must start with an alphanumeric character and may not contain `..`.
+pub fn validate_identifier(field: &str, value: &str) -> Result<(), ProvingServerError> {
+    let invalid = |reason: &str| ProvingServerError::InvalidProverConfig {
+        message: format!("{} {:?} {}", field, value, reason),
+    };
+    if value.is_empty() || value.len() > MAX_IDENTIFIER_LEN {
+        return Err(invalid("must be between 1 and 64 characters"));
+    }
+    if !value.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(invalid("must start with a letter or digit"));
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+    {
+        return Err(invalid(
+            "may only contain letters, digits, '.', '_' and '-'",
+        ));
+    }
+    if value.contains("..") {
+        return Err(invalid("may not contain '..'"));
+    }
+    Ok(())
+}
+
+/// Whether `hash` is a lowercase hex sha256 digest, the only form blobs are addressed by.
+pub fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+#[derive(Clone, Debug)]
+pub struct ArtifactStore {
+    root: PathBuf,
+}
+
+impl ArtifactStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        ArtifactStore { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Joins `relative` onto the root, refusing anything that could step outside of it.
+    pub fn resolve<P: AsRef<Path>>(&self, relative: P) -> Result<PathBuf, ProvingServerError> {
+        let relative = relative.as_ref();
+        let escapes = || ProvingServerError::InvalidArtifactPath {
+            path: relative.to_string_lossy().to_string(),
+        };
+        if relative.as_os_str().is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(escapes());
+        }
+        let path = self.root.join(relative);
+        // a symlinked directory inside the store could still point elsewhere
+        if let (Some(parent), Ok(root)) = (path.parent(), self.root.canonicalize()) {
+            if let Ok(parent) = parent.canonicalize() {
+                if !parent.starts_with(&root) {
+                    return Err(escapes());
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    /// `<root>/blobs/sha256/<first two hex chars>/<hash>`
+    pub fn blob_path(&self, hash: &str) -> Result<PathBuf, ProvingServerError> {
+        if !is_sha256(hash) {
+            return Err(ProvingServerError::InvalidArtifactPath {
+                path: hash.to_string(),
+            });
+        }
+        self.resolve(Path::new(BLOB_DIR).join(&hash[..2]).join(hash))
+    }
+
+    pub fn has_blob(&self, hash: &str) -> bool {
+        self.blob_path(hash).map(|p| p.is_file()).unwrap_or(false)
+    }
+
+    /// Opens a uniquely named file under `<root>/tmp` for an in progress download.
+    pub fn temp_file(&self) -> Result<(TempFile, File), ProvingServerError> {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let name = format!(
+            "{}-{}-{}",
+            std::process::id(),
+            nanos,
+            TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+        );
+        create_dir(&self.root.join(TMP_DIR));
+        let path = self.resolve(Path::new(TMP_DIR).join(name))?;
+        let file = File::create(&path)?;
+        Ok((TempFile(path), file))
+    }
+
+    /// Moves a finished download into place under its hash. If the blob already exists the
+    /// download is discarded, so every distinct artifact is stored exactly once.
+    pub fn commit_blob(&self, temp: &Path, hash: &str) -> Result<PathBuf, ProvingServerError> {
+        let path = self.blob_path(hash)?;
+        if path.is_file() {
+            fs::remove_file(temp)?;
+            // an unreferenced blob could otherwise be collected before the job that just
+            // reused it records its hashes
+            filetime::set_file_mtime(&path, FileTime::now())?;
+            return Ok(path);
+        }
+        if let Some(parent) = path.parent() {
+            create_dir(parent);
+        }
+        fs::rename(temp, &path)?;
+        Ok(path)
+    }
+
+    /// Every blob in the store with its size and last modification time.
+    pub fn list_blobs(&self) -> Result<Vec<BlobInfo>, ProvingServerError> {
+        let mut blobs = vec![];
+        let dir = self.root.join(BLOB_DIR);
+        if !dir.is_dir() {
+            return Ok(blobs);
+        }
+        for prefix in fs::read_dir(dir)? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for blob in fs::read_dir(prefix.path())? {
+                let blob = blob?;
+                let meta = blob.metadata()?;
+                let hash = blob.file_name().to_string_lossy().to_string();
+                if meta.is_file() && self.blob_path(&hash).is_ok() {
+                    blobs.push(BlobInfo {
+                        hash,
+                        size: meta.len(),
+                        modified: meta.modified()?,
+                    });
+                }
+            }
+        }
+        blobs.sort_by(|a, b| a.hash.cmp(&b.hash));
+        Ok(blobs)
+    }
+
+    pub fn stale_temp_files(
+        &self,
+        grace: Duration,
+    ) -> Result<Vec<(PathBuf, u64)>, ProvingServerError> {
+        let mut stale = vec![];
+        let dir = self.root.join(TMP_DIR);
+        if !dir.is_dir() {
+            return Ok(stale);
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() && is_older_than(meta.modified()?, grace) {
+                stale.push((entry.path(), meta.len()));
+            }
+        }
+        Ok(stale)
+    }
+}
+
+fn is_older_than(modified: SystemTime, grace: Duration) -> bool {
+    SystemTime::now()
+        .duration_since(modified)
+        .map(|age| age >= grace)
+        .unwrap_or(false)
+}
+
+/// Hashes everything written through it, used to address blobs while they download.
+pub struct HashingWriter<W, D = Sha256> {
+    inner: W,
+    hasher: D,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter::with_digest(inner)
+    }
+}
+
+impl<W: Write, D: Digest> HashingWriter<W, D> {
+    pub fn with_digest(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: D::new(),
+        }
+    }
+
+    /// Returns the inner writer and the lowercase hex digest of everything written.
+    pub fn finish(self) -> (W, String) {
+        (self.inner, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<W: Write, D: Digest> Write for HashingWriter<W, D> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlobInfo {
+    pub hash: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Where blobs live once they have been downloaded. Every backend keeps a local scratch
+/// store for in progress downloads and for the files handed to `CircuitProver::new_path`.
+#[rocket::async_trait]
+pub trait StorageBackend: Send + Sync {
+    fn scratch(&self) -> &ArtifactStore;
+    /// Stores the finished download at `source` under `hash`, consuming the file.
+    async fn put(&self, hash: &str, source: &Path) -> Result<(), ProvingServerError>;
+    /// A local path the blob can be read from, fetching it into the scratch store if needed.
+    async fn open(&self, hash: &str) -> Result<PathBuf, ProvingServerError>;
+    async fn exists(&self, hash: &str) -> Result<bool, ProvingServerError>;
+    async fn delete(&self, hash: &str) -> Result<(), ProvingServerError>;
+    async fn list(&self) -> Result<Vec<BlobInfo>, ProvingServerError>;
+}
+
+#[rocket::async_trait]
+impl StorageBackend for ArtifactStore {
+    fn scratch(&self) -> &ArtifactStore {
+        self
+    }
+    async fn put(&self, hash: &str, source: &Path) -> Result<(), ProvingServerError> {
+        self.commit_blob(source, hash).map(|_| ())
+    }
+    async fn open(&self, hash: &str) -> Result<PathBuf, ProvingServerError> {
+        let path = self.blob_path(hash)?;
+        if !path.is_file() {
+            return Err(ProvingServerError::Storage {
+                message: format!("blob {} does not exist", hash),
+            });
+        }
+        Ok(path)
+    }
+    async fn exists(&self, hash: &str) -> Result<bool, ProvingServerError> {
+        Ok(self.has_blob(hash))
+    }
+    async fn delete(&self, hash: &str) -> Result<(), ProvingServerError> {
+        fs::remove_file(self.blob_path(hash)?)?;
+        Ok(())
+    }
+    async fn list(&self) -> Result<Vec<BlobInfo>, ProvingServerError> {
+        self.list_blobs()
+    }
+}
+
+/// Every blob hash a prover in `namespace` records, with the provers recording it.
+pub fn prover_references(
+    conn: &Connection,
+    namespace: &str,
+) -> Result<HashMap<String, Vec<BlobReference>>, ProvingServerError> {
+    let mut references: HashMap<String, Vec<BlobReference>> = HashMap::new();
+    for prover in ProverConfig::list_in_namespace(namespace, conn)? {
+        for (artifact, hash) in prover.hashes() {
+            if let Some(hash) = hash {
+                references
+                    .entry(hash.clone())
+                    .or_default()
+                    .push(BlobReference {
+                        namespace: prover.namespace.clone(),
+                        name: prover.name.clone(),
+                        version: prover.version.clone(),
+                        artifact: artifact.to_string(),
+                    });
+            }
+        }
+    }
+    Ok(references)
+}
+
+/// Which provers use each blob in a namespace's store. Blobs nobody references have an empty
+/// list.
+pub async fn reference_report(
+    namespace: &str,
+    backend: &dyn StorageBackend,
+    mut references: HashMap<String, Vec<BlobReference>>,
+) -> Result<Vec<BlobReport>, ProvingServerError> {
+    Ok(backend
+        .list()
+        .await?
+        .into_iter()
+        .map(|blob| BlobReport {
+            namespace: namespace.to_string(),
+            references: references.remove(&blob.hash).unwrap_or_default(),
+            hash: blob.hash,
+            size: blob.size,
+        })
+        .collect())
+}
+
+/// Removes every blob no prover references, along with abandoned partial downloads. Anything
+/// touched within `grace` is kept.
+pub async fn collect_garbage(
+    backend: &dyn StorageBackend,
+    references: HashMap<String, Vec<BlobReference>>,
+    grace: Duration,
+) -> Result<GcReport, ProvingServerError> {
+    let mut report = GcReport {
+        removed: vec![],
+        freed_bytes: 0,
+    };
+    for blob in backend.list().await? {
+        if references.contains_key(&blob.hash) || !is_older_than(blob.modified, grace) {
+            continue;
+        }
+        backend.delete(&blob.hash).await?;
+        report.freed_bytes += blob.size;
+        report.removed.push(blob.hash);
+    }
+    for (path, size) in backend.scratch().stale_temp_files(grace)? {
+        fs::remove_file(path)?;
+        report.freed_bytes += size;
+    }
+    Ok(report)
+}
+
+/// Namespaces that may hold blobs: the default one, those with provers and those with a
+/// directory left behind by provers since deleted.
+pub fn storage_namespaces(
+    config: &EnvConfig,
+    conn: &Connection,
+) -> Result<Vec<String>, ProvingServerError> {
+    let mut namespaces = vec![String::from(DEFAULT_NAMESPACE)];
+    namespaces.extend(ProverConfig::namespaces(conn)?);
+    namespaces.extend(config.namespaces_on_disk());
+    namespaces.sort();
+    namespaces.dedup();
+    Ok(namespaces)
+}
+
+/// The references of every storage namespace, read in one go so the database isn't held
+/// while the backends are listed.
+async fn all_references(
+    config: &EnvConfig,
+    db: &Db,
+) -> Result<Vec<(String, HashMap<String, Vec<BlobReference>>)>, ProvingServerError> {
+    let conn = db.lock().await;
+    storage_namespaces(config, &conn)?
+        .into_iter()
+        .map(|namespace| {
+            let references = prover_references(&conn, &namespace)?;
+            Ok((namespace, references))
+        })
+        .collect()
+}
+
+/// `reference_report` for every namespace.
+pub async fn report_all(
+    config: &EnvConfig,
+    db: &Db,
+) -> Result<Vec<BlobReport>, ProvingServerError> {
+    let mut report = vec![];
+    for (namespace, references) in all_references(config, db).await? {
+        let backend = config.storage_backend(&namespace);
+        report.extend(reference_report(&namespace, &*backend, references).await?);
+    }
+    Ok(report)
+}
+
+/// `collect_garbage` for every namespace.
+pub async fn collect_all_garbage(
+    config: &EnvConfig,
+    db: &Db,
+    grace: Duration,
+) -> Result<GcReport, ProvingServerError> {
+    // hashes recorded after the snapshot belong to blobs a load job just committed, which
+    // `commit_blob` leaves younger than `grace`
+    let mut report = GcReport {
+        removed: vec![],
+        freed_bytes: 0,
+    };
+    for (namespace, references) in all_references(config, db).await? {
+        let backend = config.storage_backend(&namespace);
+        let swept = collect_garbage(&*backend, references, grace).await?;
+        report.removed.extend(swept.removed);
+        report.freed_bytes += swept.freed_bytes;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        collect_garbage, prover_references, reference_report, validate_identifier, ArtifactStore,
+        HashingWriter, GC_GRACE,
+    };
+    use crate::errors::ProvingServerError;
+    use crate::models::{Crud, DEFAULT_NAMESPACE};
+    use crate::test::fixtures;
+    use filetime::FileTime;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn temp_store(label: &str) -> ArtifactStore {
+        let root = std::env::temp_dir().join(format!("proving-server-{}", label));
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::create_dir_all(&root).unwrap();
+        ArtifactStore::new(root)
+    }
+
+    #[test]
+    fn unit_identifier_grammar() {
+        for ok in ["move", "0.0.1", "6.6.6", "biomebase_v2", "reveal-1"] {
+            assert!(validate_identifier("name", ok).is_ok(), "{}", ok);
+        }
+        for bad in [
+            "",
+            "..",
+            "../../etc/x",
+            "a/b",
+            "a\\b",
+            ".hidden",
+            "-flag",
+            "v1..2",
+            "move\0",
+            "/etc/passwd",
+        ] {
+            assert!(validate_identifier("name", bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn unit_traversal_through_name_is_rejected() {
+        let mut prover = fixtures::df_prover_config();
+        prover.name = String::from("../../etc/x");
+        assert!(matches!(
+            prover.validate(),
+            Err(ProvingServerError::InvalidProverConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn unit_traversal_through_version_is_rejected() {
+        let mut prover = fixtures::df_prover_config();
+        prover.version = String::from("../..");
+        assert!(matches!(
+            prover.validate(),
+            Err(ProvingServerError::InvalidProverConfig { .. })
+        ));
+        prover.version = String::from("/tmp");
+        assert!(prover.validate().is_err());
+    }
+
+    #[test]
+    fn unit_resolve_stays_inside_root() {
+        let store = temp_store("resolve");
+        assert!(store.resolve("blobs/sha256/ab/cd").is_ok());
+        for bad in ["../x", "a/../../x", "/etc/passwd", "./x", ""] {
+            assert!(matches!(
+                store.resolve(Path::new(bad)),
+                Err(ProvingServerError::InvalidArtifactPath { .. })
+            ));
+        }
+        for bad in [
+            "../../../../etc/passwd",
+            "ABCD",
+            &"g".repeat(64),
+            &"A".repeat(64),
+        ] {
+            assert!(store.blob_path(bad).is_err());
+        }
+        let path = store.blob_path(&"ab".repeat(32)).unwrap();
+        assert!(path.starts_with(store.root()));
+    }
+
+    fn write_blob(store: &ArtifactStore, contents: &[u8]) -> String {
+        let (temp, file) = store.temp_file().unwrap();
+        let mut writer = HashingWriter::new(file);
+        writer.write_all(contents).unwrap();
+        let (_, hash) = writer.finish();
+        store.commit_blob(&temp, &hash).unwrap();
+        hash
+    }
+
+    #[test]
+    fn unit_abandoned_downloads_are_removed() {
+        let store = temp_store("abandoned");
+        let (temp, mut file) = store.temp_file().unwrap();
+        file.write_all(b"move.zk").unwrap();
+        let path = temp.to_path_buf();
+        assert!(path.is_file());
+        drop(temp);
+        assert!(!path.exists());
+        assert!(store
+            .stale_temp_files(Duration::from_secs(0))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn unit_identical_artifacts_are_stored_once() {
+        let store = temp_store("dedup");
+        let first = write_blob(&store, b"move.wasm");
+        let second = write_blob(&store, b"move.wasm");
+        assert_eq!(first, second);
+        assert_eq!(
+            first,
+            "f57a84b124351d7db13d768de3417076eb5077426f9eeb77f49273d578a276be"
+        );
+        let copies = store
+            .list_blobs()
+            .unwrap()
+            .into_iter()
+            .filter(|blob| blob.hash == first)
+            .count();
+        assert_eq!(copies, 1);
+    }
+
+    #[tokio::test]
+    async fn unit_reused_blobs_are_kept_through_the_grace_period() {
+        let store = temp_store("reuse");
+        let hash = write_blob(&store, b"move.zkey");
+        let old = FileTime::from_unix_time(0, 0);
+        filetime::set_file_mtime(store.blob_path(&hash).unwrap(), old).unwrap();
+        // another job downloads the same artifact before recording its hashes
+        write_blob(&store, b"move.zkey");
+        let gc = collect_garbage(&store, HashMap::new(), GC_GRACE)
+            .await
+            .unwrap();
+        assert!(gc.removed.is_empty());
+        assert!(store.has_blob(&hash));
+    }
+
+    #[tokio::test]
+    async fn unit_gc_removes_unreferenced_blobs() {
+        let conn = fixtures::setup_db().await;
+        let store = temp_store("gc");
+        let shared = write_blob(&store, b"shared wasm");
+        let zkey = write_blob(&store, b"zkey");
+        let orphan = write_blob(&store, b"orphan");
+
+        let mut prover = fixtures::df_prover_config();
+        prover.create(&conn).unwrap();
+        prover
+            .set_hashes(shared.clone(), zkey.clone(), shared.clone(), &conn)
+            .unwrap();
+
+        let refs = || prover_references(&conn, DEFAULT_NAMESPACE).unwrap();
+        let report = reference_report(DEFAULT_NAMESPACE, &store, refs())
+            .await
+            .unwrap();
+        let shared_refs = &report.iter().find(|b| b.hash == shared).unwrap().references;
+        assert_eq!(shared_refs.len(), 2);
+        assert!(report
+            .iter()
+            .find(|b| b.hash == orphan)
+            .unwrap()
+            .references
+            .is_empty());
+
+        // within the grace period nothing is collected
+        let gc = collect_garbage(&store, refs(), GC_GRACE).await.unwrap();
+        assert!(gc.removed.is_empty());
+
+        let gc = collect_garbage(&store, refs(), Duration::from_secs(0))
+            .await
+            .unwrap();
+        assert_eq!(gc.removed, vec![orphan.clone()]);
+        assert!(!store.has_blob(&orphan));
+        assert!(store.has_blob(&shared) && store.has_blob(&zkey));
+
+        prover.delete(&conn).unwrap();
+        let gc = collect_garbage(&store, refs(), Duration::from_secs(0))
+            .await
+            .unwrap();
+        assert_eq!(gc.removed.len(), 2);
+    }
+}
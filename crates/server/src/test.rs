@@ -1,24 +1,79 @@
 #[cfg(test)]
 pub mod fixtures {
+    use crate::fetch::FetchPolicy;
     use crate::models::ProverConfig;
     use crate::types::reqres::{ProofRequest, ProverConfigRequest};
+    use crate::types::{DatabaseMode, EnvConfig, StorageMode};
     use rusqlite::Connection;
+    use std::collections::HashMap;
     fn max_distance(x1: i64, y1: i64, x2: i64, y2: i64) -> u64 {
         ((x1 - x2).pow(2) as f64 + (y1 - y2).pow(2) as f64).sqrt() as u64 + 1
     }
     pub async fn setup_db() -> Connection {
         use crate::db::init_async_connection;
-        use crate::utils::init_async_config;
         use crate::utils::load_environment_variables;
+        use crate::utils::{init_async_config, init_config};
 
         load_environment_variables();
-        let config = init_async_config();
+        let config = init_async_config(init_config().unwrap());
         let conn = init_async_connection(config).await.unwrap();
         return conn;
     }
+    /// Config rooted in a fresh temp dir that may download from localhost test servers.
+    pub fn local_config(label: &str) -> EnvConfig {
+        let root = std::env::temp_dir().join(format!("proving-server-{}", label));
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::create_dir_all(&root).unwrap();
+        EnvConfig {
+            zk_file_path: root.to_string_lossy().to_string(),
+            db_config: DatabaseMode::Memory,
+            port: 8000,
+            queue_size: 64,
+            fetch_policy: FetchPolicy {
+                allowed_cidrs: vec!["127.0.0.1/32".parse().unwrap()],
+                ..FetchPolicy::default()
+            },
+            storage: StorageMode::Local,
+            ipfs_gateway: String::from("http://127.0.0.1:8080"),
+            npm_registry: String::from("http://127.0.0.1:4873"),
+            manifest_path: None,
+            manifest_prune: false,
+            cors: Default::default(),
+            auth: Default::default(),
+            limits: Default::default(),
+            logging: Default::default(),
+            shutdown: Default::default(),
+            queue: Default::default(),
+            scheduler: Default::default(),
+        }
+    }
+    /// Mints a server wide key holding every scope and returns it as a request header.
+    pub fn api_key_header(conn: &Connection, name: &str) -> rocket::http::Header<'static> {
+        key_header(conn, name, None)
+    }
+    /// Like `api_key_header`, for a key owned by `namespace`.
+    pub fn tenant_key_header(
+        conn: &Connection,
+        name: &str,
+        namespace: &str,
+    ) -> rocket::http::Header<'static> {
+        key_header(conn, name, Some(namespace))
+    }
+    fn key_header(
+        conn: &Connection,
+        name: &str,
+        namespace: Option<&str>,
+    ) -> rocket::http::Header<'static> {
+        use crate::auth::Scope;
+        use crate::models::ApiKey;
+        let scopes = [Scope::ProverAdmin, Scope::Prove, Scope::Read];
+        let (_, secret) = ApiKey::mint(name, namespace, &scopes, conn).unwrap();
+        rocket::http::Header::new("X-Api-Key", secret)
+    }
     pub fn df_prover_config() -> ProverConfig {
         return ProverConfig {
             id: None,
+            namespace: String::from(crate::models::DEFAULT_NAMESPACE),
             name: String::from("test"),
             version: String::from("0.0.1"),
             path_to_r1cs: String::from("https://unpkg.com/@darkforest_eth/snarks@6.6.6/move.r1cs"),
@@ -37,10 +92,23 @@ pub mod fixtures {
                 String::from("xMirror"),
                 String::from("yMirror"),
             ],
+            wasm_hash: None,
+            zkey_hash: None,
+            r1cs_hash: None,
+            pinned_wasm_hash: None,
+            pinned_zkey_hash: None,
+            pinned_r1cs_hash: None,
+            npm: None,
+            signers: vec![],
+            limits: Default::default(),
+            owner: None,
+            visibility: Default::default(),
+            acl: vec![],
         };
     }
     pub fn df_prover_config_request() -> ProverConfigRequest {
         return ProverConfigRequest {
+            namespace: None,
             name: String::from("move"),
             version: String::from("0.0.1"),
             path_to_r1cs: String::from("https://unpkg.com/@darkforest_eth/snarks@6.6.6/move.r1cs"),
@@ -59,10 +127,19 @@ pub mod fixtures {
                 String::from("xMirror"),
                 String::from("yMirror"),
             ],
+            npm: None,
+            circuit: None,
+            checksums: Default::default(),
+            signers: vec![],
+            limits: Default::default(),
+            owner: None,
+            visibility: Default::default(),
+            acl: vec![],
         };
     }
-    pub fn df_proof_request() -> ProofRequest {
-        let mut proof_request: ProofRequest = std::collections::HashMap::new();
+    /// The body of a proof request for the move circuit.
+    pub fn df_proof_request() -> HashMap<String, u64> {
+        let mut proof_request = HashMap::new();
         proof_request.insert(String::from("x1"), 100);
         proof_request.insert(String::from("y1"), 100);
         proof_request.insert(String::from("x2"), 120);
@@ -76,4 +153,211 @@ pub mod fixtures {
         proof_request.insert(String::from("yMirror"), false as u64);
         return proof_request;
     }
+    /// `df_proof_request` as the server sees it.
+    pub fn df_proof_inputs() -> ProofRequest {
+        ProofRequest::from(df_proof_request())
+    }
+
+    pub mod logs {
+        //! Captures what gets logged, for tests that check what must never be.
+        use std::future::Future;
+        use std::io;
+        use std::panic;
+        use std::sync::{Arc, Mutex};
+        use tracing::instrument::WithSubscriber;
+
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl Buffer {
+            fn contents(&self) -> String {
+                String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+            }
+        }
+
+        impl io::Write for Buffer {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(bytes);
+                Ok(bytes.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        fn subscriber(buffer: &Buffer) -> impl tracing::Subscriber + Send + Sync {
+            let writer = buffer.clone();
+            tracing_subscriber::fmt()
+                .json()
+                .with_max_level(tracing::Level::TRACE)
+                .with_span_list(true)
+                .with_writer(move || writer.clone())
+                .finish()
+        }
+
+        /// Runs `f` with every event and span on this thread logged as JSON, and returns the
+        /// logs.
+        pub fn capture<F: FnOnce()>(f: F) -> String {
+            let buffer = Buffer::default();
+            tracing::subscriber::with_default(subscriber(&buffer), f);
+            buffer.contents()
+        }
+
+        /// `capture` for a future. Work it hands to other threads is only logged here when
+        /// it takes the current dispatcher along.
+        pub async fn capture_async<F: Future>(f: F) -> (F::Output, String) {
+            let buffer = Buffer::default();
+            let output = f.with_subscriber(subscriber(&buffer)).await;
+            (output, buffer.contents())
+        }
+
+        /// Runs `f` with panic messages kept instead of printed, and returns them.
+        pub async fn capture_panics<F: Future>(f: F) -> (F::Output, String) {
+            let buffer = Buffer::default();
+            let writer = buffer.clone();
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                writer
+                    .0
+                    .lock()
+                    .unwrap()
+                    .extend_from_slice(info.to_string().as_bytes());
+            }));
+            let output = f.await;
+            panic::set_hook(previous);
+            (output, buffer.contents())
+        }
+    }
+
+    pub mod http {
+        //! Minimal http/1.1 server for tests that need a remote to download from.
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        #[derive(Debug, Clone)]
+        pub struct TestRequest {
+            pub method: String,
+            pub path: String,
+            pub headers: Vec<(String, String)>,
+            pub body: Vec<u8>,
+        }
+        impl TestRequest {
+            pub fn header(&self, name: &str) -> Option<&str> {
+                self.headers
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.as_str())
+            }
+        }
+
+        #[derive(Debug, Clone)]
+        pub struct TestResponse {
+            pub status: u16,
+            pub headers: Vec<(String, String)>,
+            pub body: Vec<u8>,
+        }
+        impl TestResponse {
+            pub fn ok<B: Into<Vec<u8>>>(body: B) -> Self {
+                TestResponse {
+                    status: 200,
+                    headers: vec![],
+                    body: body.into(),
+                }
+            }
+            pub fn status(status: u16) -> Self {
+                TestResponse {
+                    status,
+                    headers: vec![],
+                    body: vec![],
+                }
+            }
+            pub fn redirect(location: &str) -> Self {
+                TestResponse {
+                    status: 302,
+                    headers: vec![(String::from("Location"), String::from(location))],
+                    body: vec![],
+                }
+            }
+            fn to_bytes(&self) -> Vec<u8> {
+                let mut head = format!("HTTP/1.1 {} Test\r\n", self.status);
+                for (k, v) in &self.headers {
+                    head.push_str(&format!("{}: {}\r\n", k, v));
+                }
+                head.push_str(&format!(
+                    "Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    self.body.len()
+                ));
+                let mut bytes = head.into_bytes();
+                bytes.extend_from_slice(&self.body);
+                bytes
+            }
+        }
+
+        async fn read_request(socket: &mut TcpStream) -> Option<TestRequest> {
+            let mut buf = vec![];
+            let mut chunk = [0u8; 4096];
+            let head_end = loop {
+                let n = socket.read(&mut chunk).await.ok()?;
+                if n == 0 {
+                    return None;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break pos + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+            let mut lines = head.split("\r\n");
+            let mut request_line = lines.next()?.split(' ');
+            let method = request_line.next()?.to_string();
+            let path = request_line.next()?.to_string();
+            let headers: Vec<(String, String)> = lines
+                .filter_map(|l| l.split_once(':'))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .collect();
+            let length = headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, v)| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            let mut body = buf[head_end..].to_vec();
+            while body.len() < length {
+                let n = socket.read(&mut chunk).await.ok()?;
+                if n == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..n]);
+            }
+            Some(TestRequest {
+                method,
+                path,
+                headers,
+                body,
+            })
+        }
+
+        /// Serves `handler` on an ephemeral localhost port and returns the base url.
+        pub async fn serve<F>(handler: F) -> String
+        where
+            F: Fn(TestRequest) -> TestResponse + Send + Sync + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let handler = Arc::new(handler);
+            tokio::spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    let handler = handler.clone();
+                    tokio::spawn(async move {
+                        if let Some(req) = read_request(&mut socket).await {
+                            let resp = handler(req);
+                            let _ = socket.write_all(&resp.to_bytes()).await;
+                            let _ = socket.shutdown().await;
+                        }
+                    });
+                }
+            });
+            format!("http://{}", addr)
+        }
+    }
 }
@@ -1,6 +1,6 @@
+use rocket::serde::{Deserialize, Serialize};
 use rusqlite::Connection;
 
-use std::sync::mpsc;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 pub mod proof {
@@ -10,8 +10,10 @@ pub mod proof {
     use tokio::sync::Mutex;
 
     use ark_bn254::Bn254;
-    use ark_circom::ethereum::Proof;
+    use ark_circom::ethereum::{Proof, VerifyingKey};
+    use ark_groth16::VerifyingKey as ArkVerifyingKey;
     use ethers::types::U256;
+    use rocket::serde::{Deserialize, Serialize};
     use std::collections::HashMap;
     use std::{fs::File, path::PathBuf};
 
@@ -27,7 +29,27 @@ pub mod proof {
         (a, b, c)
     }
 
-    pub type ProofInputs = HashMap<String, u64>;
+    /// A verifying key laid out the way solidity verifiers take it, G2 points as in `Abc`
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(crate = "rocket::serde")]
+    pub struct EthVerifyingKey {
+        pub alpha1: [U256; 2],
+        pub beta2: [[U256; 2]; 2],
+        pub gamma2: [[U256; 2]; 2],
+        pub delta2: [[U256; 2]; 2],
+        pub ic: Vec<[U256; 2]>,
+    }
+    pub fn vk_to_eth_type(vk: &ArkVerifyingKey<Bn254>) -> EthVerifyingKey {
+        let (alpha1, beta2, gamma2, delta2, ic) = VerifyingKey::from(vk.clone()).as_tuple();
+        let g2 = |(x, y): ([U256; 2], [U256; 2])| [x, y];
+        EthVerifyingKey {
+            alpha1: [alpha1.0, alpha1.1],
+            beta2: g2(beta2),
+            gamma2: g2(gamma2),
+            delta2: g2(delta2),
+            ic: ic.into_iter().map(|point| [point.0, point.1]).collect(),
+        }
+    }
 
     pub type ProofWithInputs = (GrothProof<Bn254>, Vec<ark_bn254::Fr>);
 
@@ -35,52 +57,243 @@ pub mod proof {
     pub struct CircuitProver {
         pub builder: CircomBuilder<Bn254>,
         pub params: ProvingKey<Bn254>,
+        /// Size of the artifacts it was loaded from
+        pub size_bytes: u64,
     }
 
     impl CircuitProver {
         pub fn new_path<P: Into<PathBuf>>(zkey: P, wasm: P, r1cs: P) -> Result<Self, ()> {
-            let cfg = CircomConfig::<Bn254>::new(wasm.into(), r1cs.into()).unwrap();
+            let (zkey, wasm, r1cs) = (zkey.into(), wasm.into(), r1cs.into());
+            let size_bytes = [&zkey, &wasm, &r1cs]
+                .iter()
+                .filter_map(|path| std::fs::metadata(path).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            let cfg = CircomConfig::<Bn254>::new(wasm, r1cs).unwrap();
             let builder = CircomBuilder::new(cfg);
 
-            let mut reader = File::open(zkey.into()).unwrap();
+            let mut reader = File::open(zkey).unwrap();
             let (params, _) = ark_circom::read_zkey(&mut reader).unwrap();
 
-            Ok(CircuitProver::new(builder, params))
+            Ok(CircuitProver {
+                size_bytes,
+                ..CircuitProver::new(builder, params)
+            })
         }
 
         pub fn new(builder: CircomBuilder<Bn254>, params: ProvingKey<Bn254>) -> Self {
-            Self { builder, params }
+            Self {
+                builder,
+                params,
+                size_bytes: 0,
+            }
         }
     }
     pub type Provers = Arc<Mutex<HashMap<String, CircuitProver>>>;
 }
 
 pub mod reqres {
-    use std::collections::HashMap;
-
     use rocket::serde::{Deserialize, Serialize};
 
     #[derive(Clone, Debug, Default, Deserialize, Serialize)]
     #[serde(crate = "rocket::serde")]
     pub struct ProverConfigRequest {
+        /// Set from the route, or in manifests. Provers without one live in `default`.
+        #[serde(default)]
+        pub namespace: Option<String>,
         pub name: String,
         pub version: String,
+        #[serde(default)]
         pub path_to_wasm: String,
+        #[serde(default)]
         pub path_to_zkey: String,
+        #[serde(default)]
         pub path_to_r1cs: String,
         pub builder_params: Vec<String>,
+        /// npm package spec to take the artifacts from instead of the three paths,
+        /// e.g. `@darkforest_eth/snarks@6.6.6`
+        #[serde(default)]
+        pub npm: Option<String>,
+        /// Circuit inside the `npm` package, `move` selects `move.wasm`, `move.zkey` and `move.r1cs`
+        #[serde(default)]
+        pub circuit: Option<String>,
+        #[serde(default)]
+        pub checksums: ArtifactChecksums,
+        /// Ethereum addresses that may send signed proof requests for this prover
+        #[serde(default)]
+        pub signers: Vec<String>,
+        /// Overrides of the server wide `[limits]`
+        #[serde(default)]
+        pub limits: crate::limits::ProverLimits,
+        /// `key:<name>` or an Ethereum address, the registering key when left out
+        #[serde(default)]
+        pub owner: Option<String>,
+        #[serde(default)]
+        pub visibility: crate::acl::Visibility,
+        #[serde(default)]
+        pub acl: Vec<crate::acl::AclEntry>,
+    }
+    /// Expected sha256 of each artifact. A load job fails if a download doesn't match.
+    #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+    #[serde(crate = "rocket::serde")]
+    pub struct ArtifactChecksums {
+        #[serde(default)]
+        pub wasm: Option<String>,
+        #[serde(default)]
+        pub zkey: Option<String>,
+        #[serde(default)]
+        pub r1cs: Option<String>,
+    }
+    pub type ProofRequest = crate::inputs::PrivateInputs;
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(crate = "rocket::serde")]
+    pub struct AliasRequest {
+        pub version: String,
+    }
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(crate = "rocket::serde")]
+    pub struct VerifyingKeyResponse {
+        pub namespace: String,
+        pub name: String,
+        /// The concrete version, also when the request named an alias
+        pub version: String,
+        #[serde(flatten)]
+        pub key: super::proof::EthVerifyingKey,
     }
-    pub type ProofRequest = HashMap<String, u64>;
     #[derive(Clone, Debug, Deserialize, Serialize)]
     #[serde(crate = "rocket::serde")]
     pub struct JobResponse {
+        /// For `DELETE /v1/job/<id>`
+        #[serde(default)]
+        pub id: Option<i64>,
         pub status: crate::models::JobStatus,
         pub message: String,
         pub prover: i64,
     }
+    /// A job as `GET /v1/jobs` lists it. Timestamps are seconds since the unix epoch.
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+    #[serde(crate = "rocket::serde")]
+    pub struct JobRecord {
+        pub id: i64,
+        pub kind: crate::models::JobKind,
+        pub namespace: String,
+        pub prover: String,
+        pub version: String,
+        pub status: crate::models::JobStatus,
+        /// The last progress message, or why the job failed
+        pub message: String,
+        pub attempts: i64,
+        pub created_at: i64,
+        pub started_at: Option<i64>,
+        pub finished_at: Option<i64>,
+        /// Time spent in the queue before the last start
+        pub wait_secs: Option<i64>,
+        /// Time the last attempt ran for
+        pub run_secs: Option<i64>,
+    }
+    /// Query string of `GET /v1/jobs`, every field optional.
+    #[derive(Clone, Debug, Default, FromForm)]
+    pub struct JobFilter {
+        /// Prover name
+        pub prover: Option<String>,
+        pub version: Option<String>,
+        /// A status such as `failed`, any case
+        pub status: Option<String>,
+        pub kind: Option<String>,
+        /// Jobs created at or after this unix time
+        pub since: Option<i64>,
+        /// Jobs created before this unix time
+        pub until: Option<i64>,
+        pub limit: Option<usize>,
+    }
+    /// A proof request as `GET /v1/proof/<id>` reports it, see `scheduler`. Timestamps are
+    /// seconds since the unix epoch.
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+    #[serde(crate = "rocket::serde")]
+    pub struct ProofStatus {
+        /// The id sent back in `X-Proof-Id`, unique within the namespace
+        pub id: String,
+        pub namespace: String,
+        /// The prover's `namespace/name@version`
+        pub prover: String,
+        pub priority: crate::scheduler::Priority,
+        pub state: crate::scheduler::ProofState,
+        /// Proofs that get a slot before this one, while it is queued
+        pub position: Option<usize>,
+        pub queued_at: i64,
+        pub started_at: Option<i64>,
+        pub finished_at: Option<i64>,
+        /// Estimated from recent proofs, none until there have been some
+        pub estimated_start_at: Option<i64>,
+        pub estimated_wait_secs: Option<f64>,
+    }
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(crate = "rocket::serde")]
+    pub struct ProverReadiness {
+        pub namespace: String,
+        pub name: String,
+        pub version: String,
+        /// Status of the load job, none when the prover was never registered
+        pub status: Option<crate::models::JobStatus>,
+        pub message: Option<String>,
+        pub loaded: bool,
+    }
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(crate = "rocket::serde")]
+    pub struct ReadinessResponse {
+        pub ready: bool,
+        pub database: bool,
+        pub worker: bool,
+        /// Shutting down, see `shutdown`
+        pub draining: bool,
+        /// Every prover the manifest lists
+        pub provers: Vec<ProverReadiness>,
+    }
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+    #[serde(crate = "rocket::serde")]
+    pub struct BlobReference {
+        pub namespace: String,
+        pub name: String,
+        pub version: String,
+        pub artifact: String,
+    }
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+    #[serde(crate = "rocket::serde")]
+    pub struct BlobReport {
+        pub namespace: String,
+        pub hash: String,
+        pub size: u64,
+        pub references: Vec<BlobReference>,
+    }
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+    #[serde(crate = "rocket::serde")]
+    pub struct GcReport {
+        pub removed: Vec<String>,
+        pub freed_bytes: u64,
+    }
+    impl JobRecord {
+        pub fn new(job: crate::models::Job, prover: &crate::models::ProverConfig) -> JobRecord {
+            JobRecord {
+                id: job.id.unwrap_or_default(),
+                kind: job.kind,
+                namespace: prover.namespace.clone(),
+                prover: prover.name.clone(),
+                version: prover.version.clone(),
+                status: job.status,
+                wait_secs: job.wait_secs(),
+                run_secs: job.run_secs(),
+                message: job.message,
+                attempts: job.attempts,
+                created_at: job.created_at,
+                started_at: job.started_at,
+                finished_at: job.finished_at,
+            }
+        }
+    }
     impl From<crate::models::Job> for JobResponse {
         fn from(job: crate::models::Job) -> JobResponse {
             JobResponse {
+                id: job.id,
                 status: job.status,
                 message: job.message,
                 prover: job.prover,
@@ -88,22 +301,137 @@ pub mod reqres {
         }
     }
 }
-#[derive(Clone, Debug)]
-pub struct JobSender(pub mpsc::SyncSender<i64>);
-
-#[derive(Clone, Debug)]
+/// Effective server configuration, see `config` for where each value can come from.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde")]
 pub struct EnvConfig {
     pub zk_file_path: String,
+    #[serde(rename = "database", default)]
     pub db_config: DatabaseMode,
+    #[serde(default = "default_port")]
     pub port: i32,
+    /// Load jobs that can wait for a worker before registrations are refused
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    #[serde(rename = "fetch", default)]
+    pub fetch_policy: crate::fetch::FetchPolicy,
+    #[serde(default)]
+    pub storage: StorageMode,
+    /// Gateway used to resolve `ipfs://` artifacts, e.g. `https://ipfs.io`
+    #[serde(default = "default_ipfs_gateway")]
+    pub ipfs_gateway: String,
+    /// Registry used to resolve npm package specs, e.g. `https://registry.npmjs.org`
+    #[serde(default = "default_npm_registry")]
+    pub npm_registry: String,
+    /// Manifest of provers to reconcile the database against at boot
+    #[serde(default)]
+    pub manifest_path: Option<String>,
+    /// Delete provers that are no longer in the manifest
+    #[serde(default)]
+    pub manifest_prune: bool,
+    #[serde(default)]
+    pub cors: crate::cors::CorsConfig,
+    #[serde(default)]
+    pub auth: crate::auth::AuthConfig,
+    #[serde(default)]
+    pub limits: crate::limits::LimitsConfig,
+    #[serde(default)]
+    pub logging: crate::logging::LoggingConfig,
+    #[serde(default)]
+    pub shutdown: crate::shutdown::ShutdownConfig,
+    #[serde(default)]
+    pub queue: crate::queue::QueueConfig,
+    #[serde(default)]
+    pub scheduler: crate::scheduler::SchedulerConfig,
+}
+
+const NAMESPACE_DIR: &str = "namespaces";
+
+fn default_port() -> i32 {
+    8000
+}
+fn default_queue_size() -> usize {
+    64
+}
+fn default_ipfs_gateway() -> String {
+    String::from("https://ipfs.io")
+}
+fn default_npm_registry() -> String {
+    String::from("https://registry.npmjs.org")
+}
+
+impl EnvConfig {
+    /// The local store under `zk_file_path`. With a remote backend this only holds scratch
+    /// downloads and cached copies of blobs.
+    pub fn artifact_store(&self) -> crate::storage::ArtifactStore {
+        self.namespace_store(crate::models::DEFAULT_NAMESPACE)
+    }
+    /// Each namespace keeps its artifacts under `namespaces/<ns>`, except the default one which
+    /// stays at the root where it was before namespaces existed.
+    pub fn namespace_store(&self, namespace: &str) -> crate::storage::ArtifactStore {
+        let root = std::path::Path::new(&self.zk_file_path);
+        if namespace == crate::models::DEFAULT_NAMESPACE {
+            crate::storage::ArtifactStore::new(root)
+        } else {
+            crate::storage::ArtifactStore::new(root.join(NAMESPACE_DIR).join(namespace))
+        }
+    }
+    /// Namespaces with a directory of their own under `zk_file_path`.
+    pub fn namespaces_on_disk(&self) -> Vec<String> {
+        let dir = std::path::Path::new(&self.zk_file_path).join(NAMESPACE_DIR);
+        std::fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    pub fn storage_backend(&self, namespace: &str) -> Arc<dyn crate::storage::StorageBackend> {
+        let store = self.namespace_store(namespace);
+        match &self.storage {
+            StorageMode::Local => Arc::new(store),
+            StorageMode::S3(s3) => {
+                let mut s3 = s3.clone();
+                if namespace != crate::models::DEFAULT_NAMESPACE {
+                    s3.prefix = format!("{}{}/{}/", s3.prefix, NAMESPACE_DIR, namespace);
+                }
+                Arc::new(crate::s3::S3Backend::new(s3, store))
+            }
+        }
+    }
 }
 
 pub type Db = Arc<Mutex<Connection>>;
 
 pub type Config = Arc<Mutex<EnvConfig>>;
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", tag = "backend", rename_all = "lowercase")]
+pub enum StorageMode {
+    Local,
+    S3(crate::s3::S3Config),
+}
+
+impl Default for StorageMode {
+    fn default() -> Self {
+        StorageMode::Local
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(crate = "rocket::serde", tag = "mode", rename_all = "lowercase")]
 pub enum DatabaseMode {
     Memory,
-    File { path_to_file: String },
+    File {
+        #[serde(default)]
+        path_to_file: String,
+    },
     // Hosted { database_connection_string: String },
 }
+
+impl Default for DatabaseMode {
+    fn default() -> Self {
+        DatabaseMode::Memory
+    }
+}
@@ -1,71 +1,120 @@
-use crate::types::{Config, DatabaseMode, EnvConfig};
+use crate::types::{Config, EnvConfig};
 use dotenv::from_filename;
 use std::collections::HashMap;
-use std::env;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 pub mod files {
+    use crate::errors::ProvingServerError;
+    use crate::fetch::{fetch_to_writer, parse_url, FetchPolicy};
+    use crate::ipfs::{fetch_cid, parse_ipfs_url};
+    use crate::logging::millis;
+    use crate::metrics::{DownloadSource, Metrics};
     use crate::models::ProverConfig;
+    use crate::npm;
+    use crate::storage::{validate_identifier, HashingWriter, StorageBackend};
+    use crate::types::reqres::ProverConfigRequest;
     use crate::types::EnvConfig;
-    use rocket::http::Status;
     use std::fs::create_dir_all as createDir;
-    use std::fs::File;
-    use std::io::copy;
     use std::io::ErrorKind;
-    use std::path::{Path, PathBuf};
+    use std::path::Path;
+    use std::time::Instant;
     pub fn create_dir(archive_dir: &Path) {
         match createDir(&archive_dir) {
             Err(why) => match why.kind() {
                 ErrorKind::AlreadyExists => {}
                 other_error => {
-                    println! {"Current dir {:?}", std::env::current_dir().unwrap()}
-                    println!("Looking for {:?}", archive_dir);
+                    tracing::error!(
+                        current_dir = ?std::env::current_dir().unwrap(),
+                        ?archive_dir,
+                        "could not create archive dir"
+                    );
                     panic!("! {:?}", other_error)
                 }
             },
             Ok(_) => {
-                println!("creating archive dir at {:?}", archive_dir)
+                tracing::info!(?archive_dir, "created archive dir")
             }
         }
     }
-    pub fn get_zkey_path(prover: &ProverConfig, config: EnvConfig) -> PathBuf {
-        let mut path = get_path_from_prover(prover, config).unwrap();
-        path.set_extension("zkey");
-        path
-    }
-    pub fn get_wasm_path(prover: &ProverConfig, config: EnvConfig) -> PathBuf {
-        let mut path = get_path_from_prover(prover, config).unwrap();
-        path.set_extension("wasm");
-        path
-    }
-    pub fn get_r1cs_path(prover: &ProverConfig, config: EnvConfig) -> PathBuf {
-        let mut path = get_path_from_prover(prover, config).unwrap();
-        path.set_extension("r1cs");
-        path
+    /// Checks an artifact source at registration, before anything is downloaded.
+    pub fn check_source(policy: &FetchPolicy, url: &str) -> Result<(), ProvingServerError> {
+        match parse_ipfs_url(url) {
+            Some(cid) => cid.map(|_| ()),
+            None => policy.check_url(&parse_url(url)?),
+        }
     }
 
-    pub fn get_path_from_prover(
-        prover: &ProverConfig,
-        config: EnvConfig,
-    ) -> Result<PathBuf, std::io::Error> {
-        let mut path = PathBuf::from(config.zk_file_path);
-        path = path.join(prover.version.clone());
-        create_dir(&path);
-        path = path.join(prover.name.clone());
-        Ok(path)
+    /// Validates a registration and works out where its artifacts come from, either the three
+    /// paths or an npm package resolved against the registry.
+    pub async fn prover_from_request(
+        config: &EnvConfig,
+        request: ProverConfigRequest,
+    ) -> Result<ProverConfig, ProvingServerError> {
+        let mut prover = ProverConfig::from(request.clone());
+        prover.validate()?;
+        let paths = [
+            &request.path_to_wasm,
+            &request.path_to_zkey,
+            &request.path_to_r1cs,
+        ];
+        match (&request.npm, &request.circuit) {
+            (Some(spec), Some(circuit)) if paths.iter().all(|path| path.is_empty()) => {
+                validate_identifier("circuit", circuit)?;
+                prover.set_npm_source(npm::resolve(config, spec, circuit).await?);
+            }
+            (None, None) => {
+                for url in paths {
+                    check_source(&config.fetch_policy, url)?;
+                }
+            }
+            _ => {
+                return Err(ProvingServerError::InvalidProverConfig {
+                    message: String::from(
+                        "give either the three artifact paths or an npm spec and a circuit",
+                    ),
+                })
+            }
+        }
+        Ok(prover)
     }
 
-    pub async fn fetch_file(path: PathBuf, url: String) -> Status {
-        let resp = match reqwest::get(url.clone()).await {
-            Ok(res) => res,
-            Err(_) => return Status::BadRequest,
+    /// Downloads `url` into the blob store and returns its sha256. `ipfs://` sources are
+    /// verified against their CID before they are stored.
+    pub async fn fetch_blob(
+        config: &EnvConfig,
+        backend: &dyn StorageBackend,
+        url: String,
+        metrics: &Metrics,
+    ) -> Result<String, ProvingServerError> {
+        let (temp, file) = backend.scratch().temp_file()?;
+        tracing::debug!(%url, temp = ?temp.as_os_str(), "downloading artifact");
+        let mut dest = HashingWriter::new(file);
+        let started = Instant::now();
+        let (source, fetched) = match parse_ipfs_url(&url) {
+            Some(cid) => (
+                DownloadSource::Ipfs,
+                fetch_cid(&config.fetch_policy, &config.ipfs_gateway, &cid?, &mut dest).await,
+            ),
+            None => (
+                DownloadSource::Http,
+                fetch_to_writer(&config.fetch_policy, &url, &mut dest).await,
+            ),
         };
-        println!("{:?} {:?}", resp.status(), url.clone());
-        let mut dest = File::create(path.clone()).unwrap();
-        let content = resp.bytes().await.unwrap();
-        println!("copying {:?} to {:?}", url, path.clone().as_os_str());
-        copy(&mut content.as_ref(), &mut dest).unwrap();
-        Status::Accepted
+        // a truncated artifact goes away with `temp`
+        let bytes = fetched?;
+        let elapsed = started.elapsed();
+        metrics.observe_download(source, bytes, elapsed);
+        tracing::info!(
+            %url,
+            source = source.as_str(),
+            bytes,
+            elapsed_ms = millis(elapsed),
+            "downloaded artifact"
+        );
+        let (file, hash) = dest.finish();
+        drop(file);
+        backend.put(&hash, &temp).await?;
+        Ok(hash)
     }
 }
 pub fn load_environment_variables() {
@@ -80,26 +129,11 @@ pub fn load_environment_variables() {
 pub fn init_provers() -> crate::types::proof::Provers {
     Arc::new(Mutex::new(HashMap::new()))
 }
-pub fn init_config() -> EnvConfig {
-    let zk_file_path = env::var("ZK_FILE_PATH").unwrap();
-    let db_config = match env::var("DB_FILE_PATH") {
-        Ok(string) => DatabaseMode::File {
-            path_to_file: string,
-        },
-        Err(_) => DatabaseMode::Memory,
-    };
-    let port = match env::var("PORT") {
-        Ok(num) => num.parse::<i32>().unwrap(),
-        Err(_) => 8000,
-    };
-
-    EnvConfig {
-        zk_file_path,
-        db_config,
-        port,
-    }
+/// Loads the layered configuration, see `config::load`.
+pub fn init_config() -> Result<EnvConfig, crate::errors::ProvingServerError> {
+    crate::config::load()
 }
-pub fn init_async_config() -> Config {
-    let conf = init_config();
-    Arc::new(Mutex::new(conf))
+/// Shares the configuration `init_config` loaded with the routes.
+pub fn init_async_config(config: EnvConfig) -> Config {
+    Arc::new(Mutex::new(config))
 }
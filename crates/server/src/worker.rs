@@ -1,68 +1,514 @@
-use crate::models::{Crud, Job, JobStatus, ProverConfig};
+use crate::errors::ProvingServerError;
+use crate::health::WorkerHealth;
+use crate::logging::millis;
+use crate::memory::MemoryBudget;
+use crate::metrics::Metrics;
+use crate::models::{Crud, Job, JobStatus, NpmSource, ProverConfig};
+use crate::npm;
+use crate::queue::JobQueue;
+use crate::shutdown::Drain;
+use crate::storage::StorageBackend;
 use crate::types::proof::{CircuitProver, Provers};
 use crate::types::{Db, EnvConfig};
-use crate::utils::files::{fetch_file, get_r1cs_path, get_wasm_path, get_zkey_path};
-use std::sync::mpsc;
-
-pub async fn worker(
-    db: Db,
-    config: EnvConfig,
-    prover_storage: Provers,
-    trigger: mpsc::Receiver<i64>,
-) {
+use crate::utils::files::fetch_blob;
+use std::path::PathBuf;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::Instrument;
+
+/// Everything the load workers share. Cloning shares it.
+#[derive(Clone)]
+pub struct Pool {
+    pub db: Db,
+    pub config: EnvConfig,
+    pub provers: Provers,
+    pub metrics: Metrics,
+    pub health: WorkerHealth,
+    pub drain: Drain,
+    pub queue: JobQueue,
+    pub budget: MemoryBudget,
+}
+
+impl Pool {
+    /// Starts `queue.workers` workers, which take load jobs concurrently.
+    pub fn spawn(self) {
+        for index in 0..self.config.queue.workers {
+            tokio::spawn(worker(index, self.clone()));
+        }
+    }
+}
+
+/// Takes load jobs from the queue in the job table until the server drains.
+async fn worker(index: usize, pool: Pool) {
+    let Pool {
+        db,
+        config,
+        drain,
+        queue,
+        health,
+        ..
+    } = pool.clone();
+    let _running = health.running();
+    let owner = format!("worker-{}-{}", std::process::id(), index);
+    let poll = config.queue.poll_interval();
     loop {
-        let id = trigger.recv().unwrap();
-        println!("starting job for {:?}", id);
-        process_job(id, &db, config.clone(), &prover_storage).await;
+        // jobs left in the queue by a drain are resumed by the next boot
+        let in_flight = match drain.admit() {
+            Ok(in_flight) => in_flight,
+            Err(_) => {
+                tokio::time::sleep(poll).await;
+                continue;
+            }
+        };
+        let claimed = Job::claim(
+            &owner,
+            config.queue.lease_secs,
+            config.queue.max_attempts,
+            &*db.lock().await,
+        );
+        let job = match claimed {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                drop(in_flight);
+                queue.wait(poll).await;
+                continue;
+            }
+            Err(error) => {
+                drop(in_flight);
+                tracing::error!(%error, "could not claim a load job");
+                queue.wait(poll).await;
+                continue;
+            }
+        };
+        // there may be more waiting, which an idle worker can take
+        queue.wake();
+        let id = job.id.unwrap();
+        let span = tracing::info_span!(
+            "job",
+            job_id = id,
+            worker = index,
+            attempt = job.attempts,
+            namespace = Empty,
+            prover = Empty,
+            version = Empty
+        );
+        let running = queue.running(id);
+        let started = Instant::now();
+        async {
+            // losing the lease means another worker took the job over or it was cancelled, so
+            // this one stops. Dropping the job's future aborts its downloads.
+            tokio::select! {
+                _ = process_job(job, &pool) => {}
+                _ = hold_lease(id, &owner, &db, &config) => {}
+                _ = running.cancelled() => {
+                    tracing::info!("load job cancelled");
+                    pool.metrics.observe_load("cancelled", started.elapsed());
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+        drop(in_flight);
     }
 }
 
-async fn process_job(id: i64, db: &Db, config: EnvConfig, prover_storage: &Provers) {
-    let guard = db.lock().await;
-    let mut job = Job::get(id, &guard).unwrap();
-    job.status = JobStatus::Processing;
-    job.update(&guard).unwrap();
-    drop(guard);
-    let guard = db.lock().await;
-    let prover = ProverConfig::get(job.prover, &guard).unwrap();
-    drop(guard);
-    let wasm_path = get_wasm_path(&prover, config.clone());
-    let zkey_path = get_zkey_path(&prover, config.clone());
-    let r1cs_path = get_r1cs_path(&prover, config.clone());
-
-    println!(
-        "writing {:?} file to {:?}",
-        prover.path_to_wasm.clone(),
-        wasm_path.clone()
+/// Renews the job's lease for as long as it is being worked on, returning once it is lost.
+async fn hold_lease(id: i64, owner: &str, db: &Db, config: &EnvConfig) {
+    let mut ticks = tokio::time::interval(config.queue.renew_interval());
+    // the claim just took the lease, so the first immediate tick has nothing to renew
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        match Job::renew_lease(id, owner, config.queue.lease_secs, &*db.lock().await) {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!("lost the lease on the load job");
+                return;
+            }
+            Err(error) => tracing::warn!(%error, "could not renew the lease on the load job"),
+        }
+    }
+}
+
+async fn process_job(mut job: Job, pool: &Pool) {
+    let Pool {
+        db,
+        config,
+        metrics,
+        ..
+    } = pool;
+    let started = Instant::now();
+    let found = ProverConfig::get(job.prover, &*db.lock().await);
+    let mut prover = match found {
+        Ok(prover) => prover,
+        // the prover may have been deleted while its job waited in the queue
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            tracing::info!("prover was deleted, cancelling the load job");
+            metrics.observe_load("cancelled", started.elapsed());
+            job.message = String::from("the prover was deleted");
+            return finish_job(&mut job, JobStatus::Cancelled, db).await;
+        }
+        Err(e) => {
+            metrics.observe_load("failed", started.elapsed());
+            return fail_job(&mut job, e.into(), db).await;
+        }
+    };
+    let span = tracing::Span::current();
+    span.record("namespace", &prover.namespace.as_str());
+    span.record("prover", &prover.name.as_str());
+    span.record("version", &prover.version.as_str());
+    tracing::info!("starting job");
+    let backend = config.storage_backend(&prover.namespace);
+    let fetching = Instant::now();
+    let fetched = match &prover.npm {
+        Some(source) => fetch_npm_artifacts(&prover, source, &*backend, db, config, metrics).await,
+        None => fetch_artifacts(&prover, &*backend, db, config, metrics).await,
+    };
+    let hashes = match fetched.and_then(|hashes| check_pinned(&prover, hashes)) {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            metrics.observe_load("failed", started.elapsed());
+            return fail_job(&mut job, e, db).await;
+        }
+    };
+    tracing::info!(
+        phase = "fetch",
+        elapsed_ms = millis(fetching.elapsed()),
+        "fetched artifacts"
     );
-    fetch_file(wasm_path.clone(), prover.path_to_wasm.clone()).await;
-    println!(
-        "writing {:?} file to {:?}",
-        prover.path_to_zkey.clone(),
-        zkey_path.clone()
+    let recorded = prover.set_hashes(
+        hashes[0].clone(),
+        hashes[1].clone(),
+        hashes[2].clone(),
+        &*db.lock().await,
     );
-    fetch_file(zkey_path.clone(), prover.path_to_zkey.clone()).await;
-    println!(
-        "writing {:?} file to {:?}",
-        prover.path_to_r1cs.clone(),
-        r1cs_path.clone()
+    if let Err(e) = recorded {
+        metrics.observe_load("failed", started.elapsed());
+        return fail_job(&mut job, e.into(), db).await;
+    }
+    let mut paths: Vec<PathBuf> = vec![];
+    for hash in &hashes {
+        match backend.open(hash).await {
+            Ok(path) => paths.push(path),
+            Err(e) => {
+                metrics.observe_load("failed", started.elapsed());
+                return fail_job(&mut job, e, db).await;
+            }
+        }
+    }
+    // the artifact sizes, as `CircuitProver` estimates its own
+    let estimate: u64 = paths
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let reservation = match pool
+        .budget
+        .reserve(estimate, &prover.key(), &pool.provers)
+        .await
+    {
+        Ok(reservation) => reservation,
+        Err(e) => {
+            metrics.observe_load("failed", started.elapsed());
+            return fail_job(&mut job, e, db).await;
+        }
+    };
+    let (wasm_path, zkey_path, r1cs_path) = (paths[0].clone(), paths[1].clone(), paths[2].clone());
+    let loading = Instant::now();
+    // parsing the zkey takes a while, and panics on artifacts it can't read. The reservation
+    // goes along, so a cancelled job keeps holding it until the parse actually stops.
+    let instantiated = tokio::task::spawn_blocking(move || {
+        let p = CircuitProver::new_path(zkey_path, wasm_path, r1cs_path);
+        (p, reservation)
+    })
+    .await;
+    let (p, _reservation) = match instantiated {
+        Ok((Ok(p), reservation)) => (p, reservation),
+        _ => {
+            metrics.observe_load("failed", started.elapsed());
+            let e = ProvingServerError::InvalidProverConfig {
+                message: String::from("could not build a circuit from the artifacts"),
+            };
+            return fail_job(&mut job, e, db).await;
+        }
+    };
+    tracing::info!(
+        phase = "load",
+        elapsed_ms = millis(loading.elapsed()),
+        bytes = p.size_bytes,
+        "loaded prover"
     );
-    fetch_file(r1cs_path.clone(), prover.path_to_r1cs.clone()).await;
-    println!("Initializing Prover");
-    let p = CircuitProver::new_path(zkey_path, wasm_path, r1cs_path).unwrap();
-    let mut prover_storage = prover_storage.lock().await;
-    prover_storage.insert(prover.name.clone(), p);
+    match job.finish(JobStatus::Ready, &*db.lock().await) {
+        Ok(true) => {}
+        Ok(false) => {
+            tracing::info!("load job was cancelled, dropping the prover");
+            metrics.observe_load("cancelled", started.elapsed());
+            return;
+        }
+        // the lease runs out and another worker loads it again
+        Err(error) => {
+            tracing::error!(%error, "could not record the loaded prover, dropping it");
+            metrics.observe_load("failed", started.elapsed());
+            return;
+        }
+    }
+    pool.provers.lock().await.insert(prover.key(), p);
+    metrics.observe_load("ready", started.elapsed());
+}
+
+/// Provers registered with checksums refuse downloads that don't match them, instead of
+/// recording their hashes.
+fn check_pinned(
+    prover: &ProverConfig,
+    hashes: Vec<String>,
+) -> Result<Vec<String>, ProvingServerError> {
+    for ((_, expected), actual) in prover.pinned().iter().zip(&hashes) {
+        match expected {
+            Some(expected) if *expected != actual => {
+                return Err(ProvingServerError::ChecksumMismatch {
+                    expected: expected.to_string(),
+                    actual: actual.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(hashes)
+}
+
+fn artifact_paths(prover: &ProverConfig) -> [&String; 3] {
+    [
+        &prover.path_to_wasm,
+        &prover.path_to_zkey,
+        &prover.path_to_r1cs,
+    ]
+}
 
+async fn fetch_artifacts(
+    prover: &ProverConfig,
+    backend: &dyn StorageBackend,
+    db: &Db,
+    config: &EnvConfig,
+    metrics: &Metrics,
+) -> Result<Vec<String>, ProvingServerError> {
+    let namespace = &prover.namespace;
+    let [wasm, zkey, r1cs] = artifact_paths(prover);
+    // downloaded side by side, the zkey usually takes longest
+    let (wasm, zkey, r1cs) = tokio::try_join!(
+        fetch_artifact(namespace, wasm, backend, db, config, metrics),
+        fetch_artifact(namespace, zkey, backend, db, config, metrics),
+        fetch_artifact(namespace, r1cs, backend, db, config, metrics),
+    )?;
+    Ok(vec![wasm, zkey, r1cs])
+}
+
+/// Only downloads the package when some of its artifacts aren't already stored.
+async fn fetch_npm_artifacts(
+    prover: &ProverConfig,
+    source: &NpmSource,
+    backend: &dyn StorageBackend,
+    db: &Db,
+    config: &EnvConfig,
+    metrics: &Metrics,
+) -> Result<Vec<String>, ProvingServerError> {
+    let mut hashes = vec![];
+    for url in artifact_paths(prover) {
+        match known_hash(&prover.namespace, url, backend, db).await? {
+            Some(hash) => hashes.push(hash),
+            None => return npm::fetch_artifacts(config, backend, source, metrics).await,
+        }
+    }
+    Ok(hashes)
+}
+
+/// Returns the hash of the blob for `url`, only downloading it if no other prover in the
+/// namespace already has.
+async fn fetch_artifact(
+    namespace: &str,
+    url: &str,
+    backend: &dyn StorageBackend,
+    db: &Db,
+    config: &EnvConfig,
+    metrics: &Metrics,
+) -> Result<String, ProvingServerError> {
+    match known_hash(namespace, url, backend, db).await? {
+        Some(hash) => Ok(hash),
+        None => fetch_blob(config, backend, url.to_string(), metrics).await,
+    }
+}
+
+async fn known_hash(
+    namespace: &str,
+    url: &str,
+    backend: &dyn StorageBackend,
+    db: &Db,
+) -> Result<Option<String>, ProvingServerError> {
     let guard = db.lock().await;
-    job.status = JobStatus::Ready;
-    job.update(&guard).unwrap();
+    let known = ProverConfig::find_hash_for_url(namespace, url, &guard)?;
     drop(guard);
+    if let Some(hash) = known {
+        if backend.exists(&hash).await? {
+            tracing::debug!(%hash, url, "reusing blob");
+            return Ok(Some(hash));
+        }
+    }
+    Ok(None)
+}
+
+async fn fail_job(job: &mut Job, error: ProvingServerError, db: &Db) {
+    tracing::warn!(%error, "load job failed");
+    job.message = error.to_string();
+    finish_job(job, JobStatus::Failed, db).await;
+}
+
+async fn finish_job(job: &mut Job, status: JobStatus, db: &Db) {
+    if let Err(error) = job.finish(status, &*db.lock().await) {
+        tracing::error!(%error, status = ?status, "could not record how the load job ended");
+    }
 }
 
 #[tokio::test]
 async fn read_job_from_db() {
-    use crate::utils::init_async_config;
     use crate::utils::load_environment_variables;
+    use crate::utils::{init_async_config, init_config};
     load_environment_variables();
-    let _config = init_async_config();
+    let _config = init_async_config(init_config().unwrap());
+}
+
+#[tokio::test]
+async fn unit_shared_artifacts_are_downloaded_once() {
+    use crate::models::DEFAULT_NAMESPACE;
+    use crate::test::fixtures;
+    use crate::test::fixtures::http::{serve, TestResponse};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let base = serve(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        TestResponse::ok("move.wasm")
+    })
+    .await;
+    let db: Db = Arc::new(Mutex::new(fixtures::setup_db().await));
+    let config = fixtures::local_config("shared-artifacts");
+    let store = config.artifact_store();
+    let metrics = Metrics::default();
+    let url = format!("{}/move.wasm", base);
+
+    let mut first = fixtures::df_prover_config();
+    first.path_to_wasm = url.clone();
+    first.create(&*db.lock().await).unwrap();
+    let hash = fetch_artifact(DEFAULT_NAMESPACE, &url, &store, &db, &config, &metrics)
+        .await
+        .unwrap();
+    first
+        .set_hashes(hash.clone(), hash.clone(), hash.clone(), &*db.lock().await)
+        .unwrap();
+
+    let again = fetch_artifact(DEFAULT_NAMESPACE, &url, &store, &db, &config, &metrics)
+        .await
+        .unwrap();
+    assert_eq!(hash, again);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    assert!(store.has_blob(&hash));
+
+    // another namespace has a store of its own, so it downloads its own copy
+    let team = config.namespace_store("team-a");
+    let theirs = fetch_artifact("team-a", &url, &team, &db, &config, &metrics)
+        .await
+        .unwrap();
+    assert_eq!(theirs, hash);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    assert!(team.has_blob(&hash));
+    assert_ne!(team.root(), store.root());
+    assert_eq!(
+        metrics.download_bytes.with_label_values(&["http"]).get(),
+        2 * "move.wasm".len() as u64
+    );
+}
+
+#[tokio::test]
+async fn unit_pinned_checksums_are_enforced() {
+    use crate::test::fixtures;
+    use crate::test::fixtures::http::{serve, TestResponse};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    let base = serve(|req| TestResponse::ok(req.path.trim_start_matches('/').to_string())).await;
+    let db: Db = Arc::new(Mutex::new(fixtures::setup_db().await));
+    let config = fixtures::local_config("pinned-checksums");
+    let store = config.artifact_store();
+
+    let mut prover = fixtures::df_prover_config();
+    prover.path_to_wasm = format!("{}/move.wasm", base);
+    prover.path_to_zkey = format!("{}/move.zkey", base);
+    prover.path_to_r1cs = format!("{}/move.r1cs", base);
+    // pinned to the zkey's hash, which the wasm download can't match
+    prover.pinned_wasm_hash = Some(String::from(
+        "ae3972cd3a7ab4e3e7dc195e1d1fc1d1acd1d501ac59b400df5c6bfd9b0b2509",
+    ));
+    prover.create(&*db.lock().await).unwrap();
+    // a pin isn't a hash anything was verified against, so no download reuses it
+    let known =
+        ProverConfig::find_hash_for_url(&prover.namespace, &prover.path_to_wasm, &*db.lock().await);
+    assert_eq!(known.unwrap(), None);
+
+    let hashes = fetch_artifacts(&prover, &store, &db, &config, &Metrics::default())
+        .await
+        .unwrap();
+    match check_pinned(&prover, hashes.clone()) {
+        Err(ProvingServerError::ChecksumMismatch { actual, .. }) => assert_eq!(actual, hashes[0]),
+        other => panic!("expected a checksum mismatch, got {:?}", other),
+    }
+
+    prover.pinned_wasm_hash = Some(hashes[0].clone());
+    assert_eq!(check_pinned(&prover, hashes.clone()).unwrap(), hashes);
+}
+
+#[tokio::test]
+async fn unit_jobs_of_deleted_provers_are_cancelled() {
+    use crate::test::fixtures;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    let db: Db = Arc::new(Mutex::new(fixtures::setup_db().await));
+    let config = fixtures::local_config("deleted-prover");
+    let pool = Pool {
+        db: db.clone(),
+        provers: crate::utils::init_provers(),
+        metrics: Metrics::default(),
+        health: Default::default(),
+        drain: Default::default(),
+        queue: Default::default(),
+        budget: MemoryBudget::new(None),
+        config,
+    };
+    let mut prover = fixtures::df_prover_config();
+    prover.create(&*db.lock().await).unwrap();
+    let id = Job::queue_for_prover(&prover, String::from("queued"), &*db.lock().await).unwrap();
+    let job = Job::claim("worker-a", 60, 3, &*db.lock().await)
+        .unwrap()
+        .unwrap();
+    // gone between the claim and the worker reading it
+    db.lock()
+        .await
+        .execute(
+            "delete from prover where id = ?1",
+            rusqlite::params![prover.id],
+        )
+        .unwrap();
+
+    process_job(job, &pool).await;
+    let job = Job::get(id, &*db.lock().await).unwrap();
+    assert_eq!(job.status, JobStatus::Cancelled);
+    assert_eq!(job.message, "the prover was deleted");
+    assert_eq!(
+        pool.metrics
+            .load_jobs
+            .with_label_values(&["cancelled"])
+            .get(),
+        1
+    );
 }
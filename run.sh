@@ -4,11 +4,17 @@ set -eo pipefail
 # Create mount directory for service.
 mkdir -p $ZK_FILE_PATH
 
-echo "Mounting Cloud Filestore."
-mount -o nolock $FILESTORE_IP_ADDRESS:/$FILE_SHARE_NAME ZK_FILE_PATHR
-echo "Mounting completed."
+# With STORAGE_BACKEND=s3 artifacts live in the bucket and ZK_FILE_PATH is only a local cache.
+if [ -n "$FILESTORE_IP_ADDRESS" ]; then
+  echo "Mounting Cloud Filestore."
+  mount -o nolock $FILESTORE_IP_ADDRESS:/$FILE_SHARE_NAME $ZK_FILE_PATH
+  echo "Mounting completed."
+fi
+
+# Api keys live in the database, so it has to outlive the container.
+export DB_FILE_PATH="${DB_FILE_PATH:-$ZK_FILE_PATH/proving-server.db}"
 
 /usr/local/bin/proving-server
 
 # Exit immediately when one of the background processes terminate.
-wait -n
\ No newline at end of file
+wait -n